@@ -0,0 +1,200 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const SEVEN_ZIP_MAGIC: &[u8] = b"7z\xbc\xaf\x27\x1c";
+
+/// Extracts a zip or 7z archive into `dest_dir` and returns the paths of
+/// the extracted files relative to `dest_dir`.
+pub fn extract(archive_path: &Path, dest_dir: &Path) -> Result<Vec<PathBuf>> {
+  let mut magic = [0; 6];
+  let mut file = File::open(archive_path).map_err(Error::io(archive_path))?;
+  let n = file.read(&mut magic).map_err(Error::io(archive_path))?;
+  drop(file);
+
+  if magic[..n].starts_with(ZIP_MAGIC) {
+    extract_zip(archive_path, dest_dir)
+  } else if magic[..n].starts_with(SEVEN_ZIP_MAGIC) {
+    extract_7z(archive_path, dest_dir)
+  } else {
+    Err(Error::UnsupportedArchive(archive_path.to_path_buf()))
+  }
+}
+
+/// Turns an archive or filelist name into a relative path, refusing names
+/// that would escape the directory they are extracted into.
+pub fn safe_relative_path(name: &str) -> Result<PathBuf> {
+  let name = name.replace('\\', "/");
+  let mut path = PathBuf::new();
+
+  for component in Path::new(&name).components() {
+    match component {
+      Component::Normal(part) => path.push(part),
+      Component::CurDir => {}
+      _ => return Err(Error::UnsafePath(name)),
+    }
+  }
+
+  if path.as_os_str().is_empty() {
+    return Err(Error::UnsafePath(name));
+  }
+  Ok(path)
+}
+
+fn extract_zip(archive_path: &Path, dest_dir: &Path) -> Result<Vec<PathBuf>> {
+  let archive_error = |e: zip::result::ZipError| Error::Archive {
+    path: archive_path.to_path_buf(),
+    message: e.to_string(),
+  };
+
+  let file = File::open(archive_path).map_err(Error::io(archive_path))?;
+  let mut zip = zip::ZipArchive::new(file).map_err(archive_error)?;
+  let mut extracted = Vec::new();
+
+  for i in 0..zip.len() {
+    let mut entry = zip.by_index(i).map_err(archive_error)?;
+    if entry.is_dir() {
+      continue;
+    }
+
+    let relative = safe_relative_path(entry.name())?;
+    let path = dest_dir.join(&relative);
+    write_entry(&mut entry, &path)?;
+    extracted.push(relative);
+  }
+
+  Ok(extracted)
+}
+
+fn extract_7z(archive_path: &Path, dest_dir: &Path) -> Result<Vec<PathBuf>> {
+  let mut extracted = Vec::new();
+  let mut entry_error = None;
+
+  let result =
+    sevenz_rust::decompress_file_with_extract_fn(archive_path, dest_dir, |entry, reader, _| {
+      if entry.is_directory() {
+        return Ok(true);
+      }
+
+      let written = safe_relative_path(entry.name()).and_then(|relative| {
+        write_entry(reader, &dest_dir.join(&relative))?;
+        Ok(relative)
+      });
+      match written {
+        Ok(relative) => {
+          extracted.push(relative);
+          Ok(true)
+        }
+        Err(e) => {
+          // Stop extracting; the error is reported below.
+          entry_error = Some(e);
+          Ok(false)
+        }
+      }
+    });
+
+  if let Some(e) = entry_error {
+    return Err(e);
+  }
+  result.map_err(|e| Error::Archive {
+    path: archive_path.to_path_buf(),
+    message: e.to_string(),
+  })?;
+
+  Ok(extracted)
+}
+
+fn write_entry(reader: &mut dyn Read, path: &Path) -> Result<()> {
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent).map_err(Error::io(parent))?;
+  }
+  let mut file = File::create(path).map_err(Error::io(path))?;
+  io::copy(reader, &mut file).map_err(Error::io(path))?;
+  Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use std::io::Write;
+  use zip::write::{FileOptions, ZipWriter};
+
+  /// Writes a zip of `(name, contents)` entries, with directories for
+  /// names ending in `/`.
+  pub(crate) fn write_zip(path: &Path, entries: &[(&str, &str)]) {
+    let mut zip = ZipWriter::new(File::create(path).unwrap());
+    for (name, contents) in entries {
+      if name.ends_with('/') {
+        zip.add_directory(*name, FileOptions::default()).unwrap();
+      } else {
+        zip.start_file(*name, FileOptions::default()).unwrap();
+        zip.write_all(contents.as_bytes()).unwrap();
+      }
+    }
+    zip.finish().unwrap();
+  }
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("neb-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn safe_relative_path_refuses_escapes() {
+    assert_eq!(safe_relative_path("a/./b").unwrap(), Path::new("a/b"));
+    assert_eq!(
+      safe_relative_path("data\\mod.vp").unwrap(),
+      Path::new("data/mod.vp")
+    );
+
+    for name in ["../x", "a/../../x", "a/..", "/etc/passwd", "\\x", "", "."] {
+      assert!(
+        matches!(safe_relative_path(name), Err(Error::UnsafePath(_))),
+        "{:?}",
+        name
+      );
+    }
+  }
+
+  #[test]
+  fn extracts_zip_files() {
+    let dir = temp_dir("extract");
+    let archive = dir.join("core.zip");
+    write_zip(
+      &archive,
+      &[("data/", ""), ("data/a.vp", "a"), ("b.txt", "b")],
+    );
+
+    let extracted = extract(&archive, &dir.join("out")).unwrap();
+    assert_eq!(extracted, [Path::new("data/a.vp"), Path::new("b.txt")]);
+    assert_eq!(fs::read_to_string(dir.join("out/data/a.vp")).unwrap(), "a");
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn extract_refuses_unsafe_entries_and_other_files() {
+    let dir = temp_dir("extract-unsafe");
+    let archive = dir.join("evil.zip");
+    write_zip(&archive, &[("../evil.txt", "x")]);
+    assert!(matches!(
+      extract(&archive, &dir.join("out")),
+      Err(Error::UnsafePath(_))
+    ));
+    assert!(!dir.join("evil.txt").exists());
+
+    let text = dir.join("core.zip");
+    fs::write(&text, "not an archive").unwrap();
+    assert!(matches!(
+      extract(&text, &dir.join("out")),
+      Err(Error::UnsupportedArchive(_))
+    ));
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,104 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+use crate::knossos::Checksum;
+
+/// Returns the lowercase hex sha256 digest of the file at `path`.
+pub fn sha256_file(path: &Path) -> Result<String> {
+  let mut file = File::open(path).map_err(Error::io(path))?;
+  let mut hasher = Sha256::new();
+  let mut buffer = vec![0; 1024 * 1024];
+
+  loop {
+    let n = file.read(&mut buffer).map_err(Error::io(path))?;
+    if n == 0 {
+      break;
+    }
+    hasher.update(&buffer[..n]);
+  }
+
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Checks the file at `path` against a Knossos `["sha256", "<digest>"]`
+/// checksum.
+pub fn verify_file(path: &Path, checksum: &Checksum) -> Result<()> {
+  if checksum.algorithm() != "sha256" {
+    return Err(Error::UnsupportedChecksum(checksum.algorithm().to_string()));
+  }
+
+  let actual = sha256_file(path)?;
+  if !actual.eq_ignore_ascii_case(checksum.digest()) {
+    return Err(Error::ChecksumMismatch {
+      path: path.to_path_buf(),
+      expected: checksum.digest().to_string(),
+      actual,
+    });
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::path::PathBuf;
+
+  /// The sha256 digest of `abc`.
+  const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+  fn abc_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("neb-{}-{}", name, std::process::id()));
+    fs::write(&path, "abc").unwrap();
+    path
+  }
+
+  fn checksum(algorithm: &str, digest: &str) -> Checksum {
+    Checksum(algorithm.to_string(), digest.to_string())
+  }
+
+  #[test]
+  fn verifies_sha256_in_any_case() {
+    let path = abc_file("checksum-ok");
+
+    assert_eq!(sha256_file(&path).unwrap(), ABC_SHA256);
+    verify_file(&path, &checksum("sha256", ABC_SHA256)).unwrap();
+    verify_file(&path, &checksum("sha256", &ABC_SHA256.to_uppercase())).unwrap();
+
+    fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn reports_mismatch_and_unsupported_algorithms() {
+    let path = abc_file("checksum-bad");
+    let wrong = "0".repeat(64);
+
+    match verify_file(&path, &checksum("sha256", &wrong)) {
+      Err(Error::ChecksumMismatch {
+        path: reported,
+        expected,
+        actual,
+      }) => {
+        assert_eq!(reported, path);
+        assert_eq!(expected, wrong);
+        assert_eq!(actual, ABC_SHA256);
+      }
+      other => panic!("expected a checksum mismatch, got {:?}", other),
+    }
+    assert!(matches!(
+      verify_file(&path, &checksum("md5", ABC_SHA256)),
+      Err(Error::UnsupportedChecksum(algorithm)) if algorithm == "md5"
+    ));
+
+    fs::remove_file(&path).unwrap();
+    assert!(matches!(
+      verify_file(&path, &checksum("sha256", ABC_SHA256)),
+      Err(Error::Io { .. })
+    ));
+  }
+}
@@ -1,68 +1,611 @@
-use std::path::Path;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
 
-use crate::{downloader, json, repo, web};
+use rusqlite::Connection;
 
-pub fn fetch(json_path: &Path) {
-  downloader::fetch(json_path);
+use crate::config::{self, Config, Location, RepoSource};
+use crate::download_engine::Options;
+use crate::downloader::DownloadSummary;
+use crate::error::{Error, Result};
+use crate::knossos::{KnossosMod, Package};
+use crate::repo::{Mod, SearchFilter, VersionQuery};
+use crate::resolver::{self, Selection};
+use crate::status;
+use crate::verifier::{self, VerifyReport};
+use crate::version::VersionSpec;
+use crate::{downloader, installer, json, library, output, repo, web};
+
+pub fn fetch(
+  json_path: &Path,
+  sources: &[RepoSource],
+  force: bool,
+  options: &Options,
+) -> Result<()> {
+  let mut files = Vec::new();
+  for (source, path) in source_files(json_path, sources) {
+    if let Location::Url(url) = &source.location {
+      status!("==> Fetching {} from {}", source.name, url);
+      files.push((path, url.clone()));
+    }
+  }
+  downloader::fetch(&files, force, options)
+}
+
+pub fn fetch_update(
+  db_path: &Path,
+  json_path: &Path,
+  sources: &[RepoSource],
+  force: bool,
+  options: &Options,
+) -> Result<()> {
+  fetch(json_path, sources, force, options)?;
+  update(db_path, json_path, sources, false)
+}
+
+pub fn update(
+  db_path: &Path,
+  json_path: &Path,
+  sources: &[RepoSource],
+  dry_run: bool,
+) -> Result<()> {
+  // Stdin holds one source, and the releases of the others would be
+  // deleted as missing.
+  if json_path == Path::new("-") && sources.len() > 1 {
+    return Err(Error::StdinWithSources(sources.len()));
+  }
+
+  let conn = match dry_run {
+    true => repo::open_for_dry_run(db_path)?,
+    false => repo::open_read_write(db_path)?,
+  };
+  let mut updater = repo::Updater::new(&conn, dry_run)?;
+  for (source, path) in source_files(json_path, sources) {
+    let result = json::for_each_mod(&path, |mod_json| updater.add(&source.name, &mod_json));
+    if result.is_err() && !dry_run {
+      status!("==> Update failed; the changes above were rolled back");
+    }
+    result?;
+  }
+  updater.finish()
+}
+
+/// Pairs each source with its repo json: a local path as it is, the
+/// first url at `json_path` and any further url next to it with the
+/// source name added, e.g. repo.legacy.json. A `json_path` of `-` is
+/// stdin, read as the first source.
+fn source_files<'a>(json_path: &Path, sources: &'a [RepoSource]) -> Vec<(&'a RepoSource, PathBuf)> {
+  if json_path == Path::new("-") {
+    return sources
+      .iter()
+      .take(1)
+      .map(|source| (source, json_path.to_path_buf()))
+      .collect();
+  }
+
+  let mut first_url = true;
+  sources
+    .iter()
+    .map(|source| {
+      let path = match &source.location {
+        Location::Path(path) => path.clone(),
+        Location::Url(_) if first_url => {
+          first_url = false;
+          json_path.to_path_buf()
+        }
+        Location::Url(_) => {
+          let file_name = json_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+          let file_name = match file_name.split_once('.') {
+            Some((stem, ext)) => format!("{}.{}.{}", stem, source.name, ext),
+            None => format!("{}.{}", file_name, source.name),
+          };
+          json_path.with_file_name(file_name)
+        }
+      };
+      (source, path)
+    })
+    .collect()
+}
+
+pub fn db_info(db_path: &Path) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  repo::db_info(&conn)
+}
+
+pub fn list(db_path: &Path, source: Option<&str>) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  repo::list(&conn, source)
+}
+
+pub fn list_json(db_path: &Path) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  repo::list_json(&conn)
+}
+
+pub fn search(db_path: &Path, query: &str, filters: &[(&str, String)]) -> Result<()> {
+  let mut filter = SearchFilter::default();
+  for (name, value) in filters {
+    filter.set(name, value)?;
+  }
+
+  let conn = repo::open_read_only(db_path)?;
+  repo::search(&conn, query, &filter)
+}
+
+pub fn versions(
+  db_path: &Path,
+  mid: &String,
+  spec: &Option<String>,
+  stable_only: bool,
+  latest: bool,
+) -> Result<()> {
+  let query = VersionQuery {
+    spec: spec.as_deref().map(VersionSpec::parse).transpose()?,
+    stable_only,
+    latest,
+  };
+  let conn = repo::open_read_only(db_path)?;
+  repo::versions(&conn, mid, &query)
 }
 
-pub fn fetch_update(db_path: &Path, json_path: &Path) {
-  fetch(json_path);
-  update(db_path, json_path);
+pub fn json(db_path: &Path, mid: &String, version: &Option<String>) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  repo::json(&conn, mid, version)
 }
 
-pub fn update(db_path: &Path, json_path: &Path) {
-  let conn = repo::open_read_write(db_path);
-  let json = json::read_file(json_path);
-  let mods = json["mods"].as_array().unwrap();
-  repo::update(&conn, mods);
+pub fn cmdline(db_path: &Path, mid: &String, version: &Option<String>) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  repo::cmdline(&conn, mid, version)
 }
 
-pub fn list(db_path: &Path) {
-  let conn = repo::open_read_only(db_path);
-  repo::list(&conn);
+pub fn modline(db_path: &Path, mid: &String, version: &Option<String>) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  repo::modline(&conn, mid, version)
 }
 
-pub fn list_json(db_path: &Path) {
-  let conn = repo::open_read_only(db_path);
-  repo::list_json(&conn);
+pub fn sha256sum(db_path: &Path, mid: &String, version: &Option<String>) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  repo::sha256sum(&conn, mid, version)
 }
 
-pub fn search(db_path: &Path, query: &String) {
-  let conn = repo::open_read_only(db_path);
-  repo::search(&conn, query);
+pub fn dlsize(db_path: &Path, mid: &String, version: &Option<String>) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  repo::dlsize(&conn, mid, version)
 }
 
-pub fn versions(db_path: &Path, mid: &String) {
-  let conn = repo::open_read_only(db_path);
-  repo::versions(&conn, mid);
+pub fn deps(db_path: &Path, mid: &str, version: &Option<String>) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  let resolution = resolver::resolve(&conn, &[selection(mid, version)])?;
+  output::print_records(&resolution.mods, || {
+    resolver::print_tree(&resolution);
+    Ok(())
+  })
 }
 
-pub fn json(db_path: &Path, mid: &String, version: &Option<String>) {
-  let conn = repo::open_read_only(db_path);
-  repo::json(&conn, mid, version);
+pub fn deps_json(db_path: &Path, mid: &str, version: &Option<String>) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  let resolution = resolver::resolve(&conn, &[selection(mid, version)])?;
+  println!("{}", serde_json::to_string_pretty(&resolution)?);
+  Ok(())
 }
 
-pub fn cmdline(db_path: &Path, mid: &String, version: &Option<String>) {
-  let conn = repo::open_read_only(db_path);
-  repo::cmdline(&conn, mid, version);
+pub fn modline_resolved(db_path: &Path, selections: &[String]) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  let selections = selections
+    .iter()
+    .map(|s| s.parse())
+    .collect::<Result<Vec<Selection>>>()?;
+  let resolution = resolver::resolve(&conn, &selections)?;
+  resolver::print_mod_flag(&resolution)
+}
+
+pub fn download(
+  db_path: &Path,
+  mid: &String,
+  version: &Option<String>,
+  packages: &Option<Vec<String>>,
+  dest_dir: &Path,
+  options: &Options,
+) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  let km = get_knossos_mod(&conn, mid, version)?;
+  let selected = select_packages(&km, packages)?;
+  let summary = download_packages(&km, &selected, dest_dir, options)?;
+  output::print_record(&summary, || Ok(()))?;
+  download_result(summary)
+}
+
+pub fn install(
+  db_path: &Path,
+  mid: &String,
+  version: &Option<String>,
+  packages: &Option<Vec<String>>,
+  root: &Path,
+  cache_dir: &Path,
+  options: &Options,
+) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  let m = get_mod(&conn, mid, version)?;
+  let km = m.knossos_mod()?;
+  let selected = select_packages(&km, packages)?;
+
+  download_result(download_packages(&km, &selected, cache_dir, options)?)?;
+  let mod_json = serde_json::from_str(&m.mod_json)?;
+  let record = installer::install(&km, &mod_json, &selected, cache_dir, root)?;
+
+  output::print_record(&record, || {
+    println!(
+      "==> Installed {} {} ({} files) to {}",
+      km.id,
+      km.version,
+      record.files.len(),
+      installer::mod_dir(root, &km.id, &km.version).display()
+    );
+    Ok(())
+  })
+}
+
+pub fn uninstall(root: &Path, mid: &str, version: &str) -> Result<()> {
+  let record = installer::uninstall(root, mid, version)?;
+  output::print_record(&record, || {
+    println!(
+      "==> Uninstalled {} {} ({} files)",
+      record.mid,
+      record.version,
+      record.files.len()
+    );
+    Ok(())
+  })
+}
+
+pub fn outdated(db_path: &Path, root: &Path) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  library::outdated(&conn, root)
+}
+
+pub fn gc(db_path: &Path, root: &Path, yes: bool) -> Result<()> {
+  let conn = repo::open_read_only(db_path)?;
+  library::gc(&conn, root, yes)
+}
+
+pub fn verify(db_path: &Path, mid: &String, version: &Option<String>, dir: &Path) -> Result<()> {
+  let report = verify_report(db_path, mid, version, dir)?;
+  output::print_record(&report, || {
+    verifier::print_report(&report, dir);
+    Ok(())
+  })?;
+  verify_result(&report)
+}
+
+pub fn verify_json(
+  db_path: &Path,
+  mid: &String,
+  version: &Option<String>,
+  dir: &Path,
+) -> Result<()> {
+  let report = verify_report(db_path, mid, version, dir)?;
+  println!("{}", serde_json::to_string_pretty(&report)?);
+  verify_result(&report)
+}
+
+/// Checks the packages recorded in DIR/mod.json, or the default packages
+/// when the folder has none.
+fn verify_report(
+  db_path: &Path,
+  mid: &String,
+  version: &Option<String>,
+  dir: &Path,
+) -> Result<VerifyReport> {
+  if !dir.is_dir() {
+    return Err(Error::FileNotFound(dir.to_path_buf()));
+  }
+
+  let conn = repo::open_read_only(db_path)?;
+  let km = get_knossos_mod(&conn, mid, version)?;
+  let names = verifier::installed_packages(dir)?;
+  let packages = select_packages(&km, &names)?;
+  verifier::verify(&km, &packages, dir)
 }
 
-pub fn modline(db_path: &Path, mid: &String, version: &Option<String>) {
-  let conn = repo::open_read_only(db_path);
-  repo::modline(&conn, mid, version);
+fn verify_result(report: &VerifyReport) -> Result<()> {
+  if report.is_ok() {
+    Ok(())
+  } else {
+    Err(Error::VerifyFailed {
+      missing: report.missing.len(),
+      modified: report.modified.len(),
+    })
+  }
 }
 
-pub fn sha256sum(db_path: &Path, mid: &String, version: &Option<String>) {
-  let conn = repo::open_read_only(db_path);
-  repo::sha256sum(&conn, mid, version);
+fn get_mod(conn: &Connection, mid: &String, version: &Option<String>) -> Result<Mod> {
+  repo::get_mod(conn, mid, version)?.ok_or_else(|| Error::ModNotFound {
+    mid: mid.to_string(),
+    required_by: None,
+  })
 }
 
-pub fn dlsize(db_path: &Path, mid: &String, version: &Option<String>) {
-  let conn = repo::open_read_only(db_path);
-  repo::dlsize(&conn, mid, version);
+fn get_knossos_mod(
+  conn: &Connection,
+  mid: &String,
+  version: &Option<String>,
+) -> Result<KnossosMod> {
+  get_mod(conn, mid, version)?.knossos_mod()
 }
 
-pub fn web(db_path: &Path) {
-  web::start(db_path);
+/// The named packages, or the required and recommended ones by default.
+fn select_packages<'a>(
+  km: &'a KnossosMod,
+  names: &Option<Vec<String>>,
+) -> Result<Vec<&'a Package>> {
+  match names {
+    Some(names) => names
+      .iter()
+      .map(|name| {
+        km.package(name).ok_or_else(|| Error::PackageNotFound {
+          mid: km.id.clone(),
+          package: name.clone(),
+        })
+      })
+      .collect(),
+    None => Ok(km.default_packages().collect()),
+  }
+}
+
+fn download_packages(
+  km: &KnossosMod,
+  packages: &[&Package],
+  dest_dir: &Path,
+  options: &Options,
+) -> Result<DownloadSummary> {
+  let files: Vec<_> = packages.iter().flat_map(|p| p.files.iter()).collect();
+
+  status!(
+    "==> Downloading {} {} ({} files) to {}",
+    km.id,
+    km.version,
+    files.len(),
+    dest_dir.display()
+  );
+  let summary = downloader::download_files(&files, dest_dir, options)?;
+  status!(
+    "==> {} downloaded ({} bytes), {} already present, {} failed",
+    summary.downloaded,
+    summary.bytes,
+    summary.skipped,
+    summary.failed.len()
+  );
+  Ok(summary)
+}
+
+fn download_result(summary: DownloadSummary) -> Result<()> {
+  if summary.failed.is_empty() {
+    Ok(())
+  } else {
+    Err(Error::DownloadFailed(summary.failed))
+  }
+}
+
+fn selection(mid: &str, version: &Option<String>) -> Selection {
+  Selection {
+    mid: mid.to_string(),
+    version: version.clone(),
+    packages: None,
+  }
+}
+
+pub fn web(db_path: &Path, bind: Ipv4Addr, port: u16) -> Result<()> {
+  web::start(db_path, bind, port)
+}
+
+pub fn config_show(config: &Config) -> Result<()> {
+  config::show(config)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn source(name: &str, location: Location) -> RepoSource {
+    RepoSource {
+      name: name.to_string(),
+      location,
+    }
+  }
+
+  fn url(name: &str) -> RepoSource {
+    source(
+      name,
+      Location::Url(format!("https://{}.example.com/repo.json", name)),
+    )
+  }
+
+  fn files(json_path: &str, sources: &[RepoSource]) -> Vec<(String, PathBuf)> {
+    source_files(Path::new(json_path), sources)
+      .into_iter()
+      .map(|(source, path)| (source.name.clone(), path))
+      .collect()
+  }
+
+  #[test]
+  fn source_files_derive_names_for_later_urls() {
+    let sources = [
+      url("fsnebula"),
+      source("local", Location::Path(PathBuf::from("/builds/repo.json"))),
+      url("legacy"),
+      url("mirror"),
+    ];
+    let expected = |paths: [&str; 4]| {
+      sources
+        .iter()
+        .zip(paths)
+        .map(|(s, p)| (s.name.clone(), PathBuf::from(p)))
+        .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+      files("/neb/repo.json.zst", &sources),
+      expected([
+        "/neb/repo.json.zst",
+        "/builds/repo.json",
+        "/neb/repo.legacy.json.zst",
+        "/neb/repo.mirror.json.zst",
+      ])
+    );
+    assert_eq!(
+      files("/neb/repo", &sources),
+      expected([
+        "/neb/repo",
+        "/builds/repo.json",
+        "/neb/repo.legacy",
+        "/neb/repo.mirror",
+      ])
+    );
+  }
+
+  #[test]
+  fn update_refuses_stdin_for_several_sources() {
+    let db = std::env::temp_dir().join(format!("neb-stdin-{}.db", std::process::id()));
+    let sources = [url("fsnebula"), url("legacy")];
+
+    let result = update(&db, Path::new("-"), &sources, false);
+    assert!(matches!(result, Err(Error::StdinWithSources(2))));
+    assert!(!db.exists());
+  }
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("neb-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  /// A repo json of `(mid, version, title)` releases.
+  fn repo_json(releases: &[(&str, &str, &str)]) -> String {
+    let mods: Vec<_> = releases
+      .iter()
+      .map(|(mid, version, title)| {
+        serde_json::json!({
+          "id": mid, "title": title, "version": version,
+          "packages": [{"name": "Core", "status": "required", "files": [], "filelist": []}]
+        })
+      })
+      .collect();
+    serde_json::json!({ "mods": mods }).to_string()
+  }
+
+  fn stored(db: &Path) -> Vec<String> {
+    let conn = repo::open_read_only(db).unwrap();
+    let mut releases: Vec<String> = conn
+      .prepare("SELECT mid, version, title FROM mods")
+      .unwrap()
+      .query_map([], |row| {
+        Ok(format!(
+          "{} {} {}",
+          row.get::<_, String>(0)?,
+          row.get::<_, String>(1)?,
+          row.get::<_, String>(2)?
+        ))
+      })
+      .unwrap()
+      .collect::<rusqlite::Result<_>>()
+      .unwrap();
+    releases.sort();
+    releases
+  }
+
+  #[test]
+  fn failed_update_changes_nothing() {
+    let dir = temp_dir("rollback");
+    let db = dir.join("repo.db");
+    let json_path = dir.join("repo.json");
+    let sources = [source("local", Location::Path(json_path.clone()))];
+
+    std::fs::write(
+      &json_path,
+      repo_json(&[("FSO", "23.0.0", "FSO"), ("MVPS", "4.6.0", "MediaVPs")]),
+    )
+    .unwrap();
+    update(&db, &json_path, &sources, false).unwrap();
+    let before = stored(&db);
+    assert_eq!(before, ["FSO 23.0.0 FSO", "MVPS 4.6.0 MediaVPs"]);
+
+    // A new title, a new release and then a mod without a version.
+    let mut json: serde_json::Value = serde_json::from_str(&repo_json(&[
+      ("MVPS", "4.6.0", "MediaVPs 2024"),
+      ("str", "1.6.0", "Silent Threat"),
+    ]))
+    .unwrap();
+    json["mods"]
+      .as_array_mut()
+      .unwrap()
+      .push(serde_json::json!({"id": "bad", "title": "Bad", "packages": []}));
+    std::fs::write(&json_path, json.to_string()).unwrap();
+
+    assert!(update(&db, &json_path, &sources, false).is_err());
+    assert_eq!(stored(&db), before);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn dry_run_changes_nothing() {
+    let dir = temp_dir("dry-run");
+    let db = dir.join("repo.db");
+    let json_path = dir.join("repo.json");
+    let sources = [source("local", Location::Path(json_path.clone()))];
+    let write = |releases: &[(&str, &str, &str)]| {
+      std::fs::write(&json_path, repo_json(releases)).unwrap();
+    };
+
+    write(&[("FSO", "23.0.0", "FSO"), ("MVPS", "4.6.0", "MediaVPs")]);
+    update(&db, &json_path, &sources, true).unwrap();
+    assert!(!db.exists());
+
+    update(&db, &json_path, &sources, false).unwrap();
+    let before = stored(&db);
+    let info = |db: &Path| {
+      let conn = repo::open_read_only(db).unwrap();
+      let info = repo::get_db_info(&conn).unwrap();
+      (info.releases, info.last_update)
+    };
+    let info_before = info(&db);
+
+    write(&[
+      ("MVPS", "4.6.0", "MediaVPs 2024"),
+      ("str", "1.6.0", "Silent Threat"),
+    ]);
+    update(&db, &json_path, &sources, true).unwrap();
+    assert_eq!(stored(&db), before);
+    assert_eq!(info(&db), info_before);
+
+    update(&db, &json_path, &sources, false).unwrap();
+    assert_eq!(
+      stored(&db),
+      ["MVPS 4.6.0 MediaVPs 2024", "str 1.6.0 Silent Threat"]
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn dry_run_plans_against_older_schema() {
+    let dir = temp_dir("dry-run-old");
+    let db = dir.join("repo.db");
+    let json_path = dir.join("repo.json");
+    let sources = [source("local", Location::Path(json_path.clone()))];
+    drop(crate::migrations::tests::pre_versioning_db_at(&db));
+    let before = std::fs::read(&db).unwrap();
+
+    std::fs::write(&json_path, repo_json(&[("str", "1.6.0", "Silent Threat")])).unwrap();
+    update(&db, &json_path, &sources, true).unwrap();
+    assert_eq!(std::fs::read(&db).unwrap(), before);
+
+    update(&db, &json_path, &sources, false).unwrap();
+    assert_eq!(stored(&db), ["str 1.6.0 Silent Threat"]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
 }
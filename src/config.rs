@@ -0,0 +1,552 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::output;
+
+const DEFAULT_SOURCE_NAME: &str = "fsnebula";
+const DEFAULT_REPO_URL: &str = "https://fsnebula.org/storage/repo.json";
+const DEFAULT_BIND: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
+const DEFAULT_PORT: u16 = 3200;
+
+/// The config file as written. Paths in it may start with `~/` and are
+/// otherwise relative to the directory of the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+  db: Option<PathBuf>,
+  json: Option<PathBuf>,
+  sources: Option<Vec<SourceFile>>,
+  download_dir: Option<PathBuf>,
+  fs2_root: Option<PathBuf>,
+  #[serde(default)]
+  web: WebFile,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SourceFile {
+  name: String,
+  url: Option<String>,
+  path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebFile {
+  bind: Option<String>,
+  port: Option<u16>,
+}
+
+/// Where the effective value of a setting came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+  Default,
+  File(PathBuf),
+  Env(&'static str),
+  Flag(&'static str),
+}
+
+impl fmt::Display for Source {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Source::Default => write!(f, "default"),
+      Source::File(path) => write!(f, "{}", path.display()),
+      Source::Env(name) => write!(f, "env {}", name),
+      Source::Flag(name) => write!(f, "{}", name),
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Setting<T> {
+  pub value: T,
+  pub source: Source,
+}
+
+impl<T> Setting<T> {
+  fn default(value: T) -> Setting<T> {
+    Setting {
+      value,
+      source: Source::Default,
+    }
+  }
+
+  fn set(&mut self, value: Option<T>, source: Source) {
+    if let Some(value) = value {
+      self.value = value;
+      self.source = source;
+    }
+  }
+}
+
+impl<T> Setting<Option<T>> {
+  /// Replaces the value with `value` when there is one, e.g. from a flag.
+  pub fn set_from(&mut self, value: Option<T>, source: Source) {
+    self.set(value.map(Some), source);
+  }
+}
+
+/// A named repo json to merge into the database. Sources earlier in the
+/// list win when the same release is in more than one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepoSource {
+  pub name: String,
+  pub location: Location,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+  /// Downloaded by fetch and kept next to the json file.
+  Url(String),
+  /// Read in place by update.
+  Path(PathBuf),
+}
+
+impl fmt::Display for RepoSource {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self.location {
+      Location::Url(url) => write!(f, "{}={}", self.name, url),
+      Location::Path(path) => write!(f, "{}={}", self.name, path.display()),
+    }
+  }
+}
+
+/// Settings from, in rising priority, the defaults, the config file,
+/// environment variables and command-line flags.
+#[derive(Debug, Clone)]
+pub struct Config {
+  /// The config file that was looked for, and whether it exists.
+  pub path: Option<PathBuf>,
+  pub found: bool,
+  pub db: Setting<Option<PathBuf>>,
+  pub json: Setting<Option<PathBuf>>,
+  pub sources: Setting<Vec<RepoSource>>,
+  pub download_dir: Setting<PathBuf>,
+  pub fs2_root: Setting<Option<PathBuf>>,
+  pub web_bind: Setting<Ipv4Addr>,
+  pub web_port: Setting<u16>,
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      path: None,
+      found: false,
+      db: Setting::default(None),
+      json: Setting::default(None),
+      sources: Setting::default(vec![RepoSource {
+        name: DEFAULT_SOURCE_NAME.to_string(),
+        location: Location::Url(DEFAULT_REPO_URL.to_string()),
+      }]),
+      download_dir: Setting::default(PathBuf::from(".")),
+      fs2_root: Setting::default(None),
+      web_bind: Setting::default(DEFAULT_BIND),
+      web_port: Setting::default(DEFAULT_PORT),
+    }
+  }
+}
+
+impl Config {
+  /// Reads `path`, which must exist, or else the default config file if
+  /// there is one, and applies the NEB_DB, BIND and PORT env variables.
+  pub fn load(path: Option<&Path>) -> Result<Config> {
+    let mut config = Config::default();
+
+    match path {
+      Some(path) => {
+        config.read_file(path)?;
+        if !config.found {
+          return Err(Error::FileNotFound(path.to_path_buf()));
+        }
+      }
+      None => {
+        if let Some(path) = default_path() {
+          config.read_file(&path)?;
+        }
+      }
+    }
+
+    config.read_env()?;
+    Ok(config)
+  }
+
+  fn read_file(&mut self, path: &Path) -> Result<()> {
+    self.path = Some(path.to_path_buf());
+    let text = match fs::read_to_string(path) {
+      Ok(text) => text,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+      Err(e) => return Err(Error::io(path)(e)),
+    };
+    self.found = true;
+
+    let file: ConfigFile = toml::from_str(&text).map_err(|e| Error::Config {
+      path: path.to_path_buf(),
+      message: e.message().to_string(),
+    })?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let source = Source::File(path.to_path_buf());
+    let resolve = |p: Option<PathBuf>| p.map(|p| resolve_path(dir, &p));
+
+    self.db.set_from(resolve(file.db), source.clone());
+    self.json.set_from(resolve(file.json), source.clone());
+    let sources = file
+      .sources
+      .map(|sources| repo_sources(path, dir, sources))
+      .transpose()?;
+    self.sources.set(sources, source.clone());
+    self
+      .download_dir
+      .set(resolve(file.download_dir), source.clone());
+    self
+      .fs2_root
+      .set_from(resolve(file.fs2_root), source.clone());
+    let bind = file
+      .web
+      .bind
+      .map(|bind| {
+        bind.parse().map_err(|_| Error::Config {
+          path: path.to_path_buf(),
+          message: format!("invalid web.bind address '{}'", bind),
+        })
+      })
+      .transpose()?;
+    self.web_bind.set(bind, source.clone());
+    self.web_port.set(file.web.port, source);
+    Ok(())
+  }
+
+  fn read_env(&mut self) -> Result<()> {
+    if let Some(db) = env::var_os("NEB_DB").filter(|db| !db.is_empty()) {
+      self
+        .db
+        .set_from(Some(PathBuf::from(db)), Source::Env("NEB_DB"));
+    }
+    if let Ok(bind) = env::var("BIND") {
+      let bind = bind.parse().map_err(|_| Error::InvalidEnvVar("BIND"))?;
+      self.web_bind.set(Some(bind), Source::Env("BIND"));
+    }
+    if let Ok(port) = env::var("PORT") {
+      let port = port.parse().map_err(|_| Error::InvalidEnvVar("PORT"))?;
+      self.web_port.set(Some(port), Source::Env("PORT"));
+    }
+    Ok(())
+  }
+
+  /// The repo database, which every command but a few needs.
+  pub fn db(&self) -> Result<&Path> {
+    self.db.value.as_deref().ok_or(Error::MissingSetting {
+      name: "db",
+      flag: "--db PATH",
+    })
+  }
+
+  /// The repo json file for fetch and update.
+  pub fn json(&self) -> Result<&Path> {
+    self.json.value.as_deref().ok_or(Error::MissingSetting {
+      name: "json",
+      flag: "JSON",
+    })
+  }
+
+  pub fn fs2_root(&self) -> Result<&Path> {
+    self.fs2_root.value.as_deref().ok_or(Error::MissingSetting {
+      name: "fs2_root",
+      flag: "--root FS2DIR",
+    })
+  }
+
+  fn records(&self) -> Vec<SettingRecord> {
+    let path = |value: &Option<PathBuf>| value.as_ref().map(|p| p.display().to_string());
+    vec![
+      SettingRecord::new("db", path(&self.db.value), &self.db.source),
+      SettingRecord::new("json", path(&self.json.value), &self.json.source),
+      SettingRecord::new(
+        "sources",
+        Some(
+          self
+            .sources
+            .value
+            .iter()
+            .map(RepoSource::to_string)
+            .collect::<Vec<_>>()
+            .join(" "),
+        ),
+        &self.sources.source,
+      ),
+      SettingRecord::new(
+        "download_dir",
+        Some(self.download_dir.value.display().to_string()),
+        &self.download_dir.source,
+      ),
+      SettingRecord::new(
+        "fs2_root",
+        path(&self.fs2_root.value),
+        &self.fs2_root.source,
+      ),
+      SettingRecord::new(
+        "web.bind",
+        Some(self.web_bind.value.to_string()),
+        &self.web_bind.source,
+      ),
+      SettingRecord::new(
+        "web.port",
+        Some(self.web_port.value.to_string()),
+        &self.web_port.source,
+      ),
+    ]
+  }
+}
+
+#[derive(Default, Serialize)]
+struct SettingRecord {
+  name: &'static str,
+  value: Option<String>,
+  source: String,
+}
+
+impl SettingRecord {
+  fn new(name: &'static str, value: Option<String>, source: &Source) -> SettingRecord {
+    SettingRecord {
+      name,
+      value,
+      source: source.to_string(),
+    }
+  }
+}
+
+/// Prints every setting with its effective value and where it came from.
+pub fn show(config: &Config) -> Result<()> {
+  let records = config.records();
+  output::print_records(&records, || {
+    match &config.path {
+      Some(path) if config.found => println!("Config file: {}", path.display()),
+      Some(path) => println!("Config file: {} (not found)", path.display()),
+      None => println!("Config file: none"),
+    }
+    println!();
+    for r in &records {
+      let value = r.value.as_deref().unwrap_or("(not set)");
+      println!("{:<14} {:<40} {}", r.name, value, r.source);
+    }
+    Ok(())
+  })
+}
+
+/// Checks the `[[sources]]` of the config file at `path`: at least one,
+/// each with a unique name usable in a file name and either a url or a
+/// path.
+fn repo_sources(path: &Path, dir: &Path, sources: Vec<SourceFile>) -> Result<Vec<RepoSource>> {
+  let invalid = |message: String| Error::Config {
+    path: path.to_path_buf(),
+    message,
+  };
+
+  if sources.is_empty() {
+    return Err(invalid("sources is empty".to_string()));
+  }
+
+  let mut repo_sources: Vec<RepoSource> = Vec::new();
+  for source in sources {
+    let name = source.name;
+    let valid_name = !name.is_empty()
+      && name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid_name {
+      return Err(invalid(format!(
+        "invalid source name '{}', use letters, digits, - and _",
+        name
+      )));
+    }
+    if repo_sources.iter().any(|s| s.name == name) {
+      return Err(invalid(format!("duplicate source name '{}'", name)));
+    }
+
+    let location = match (source.url, source.path) {
+      (Some(url), None) => Location::Url(url),
+      (None, Some(p)) => Location::Path(resolve_path(dir, &p)),
+      _ => {
+        return Err(invalid(format!(
+          "source '{}' needs either a url or a path",
+          name
+        )))
+      }
+    };
+    repo_sources.push(RepoSource { name, location });
+  }
+
+  Ok(repo_sources)
+}
+
+/// `$XDG_CONFIG_HOME/neb/config.toml`, or `~/.config/neb/config.toml`.
+pub fn default_path() -> Option<PathBuf> {
+  let config_home = env::var_os("XDG_CONFIG_HOME")
+    .filter(|dir| !dir.is_empty())
+    .map(PathBuf::from)
+    .or_else(|| home_dir().map(|home| home.join(".config")))?;
+  Some(config_home.join("neb").join("config.toml"))
+}
+
+fn home_dir() -> Option<PathBuf> {
+  env::var_os("HOME")
+    .filter(|home| !home.is_empty())
+    .map(PathBuf::from)
+}
+
+fn resolve_path(dir: &Path, path: &Path) -> PathBuf {
+  if let Ok(rest) = path.strip_prefix("~") {
+    if let Some(home) = home_dir() {
+      return home.join(rest);
+    }
+  }
+  dir.join(path)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Mutex;
+
+  /// Held by every test that reads the environment, since one of them
+  /// changes it.
+  static ENV: Mutex<()> = Mutex::new(());
+
+  /// Writes `text` to a config file of its own and returns its path.
+  fn config_file(text: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = env::temp_dir().join(format!(
+      "neb-config-{}-{}",
+      std::process::id(),
+      COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    fs::write(&path, text).unwrap();
+    path
+  }
+
+  fn load_error(text: &str) -> String {
+    let _env = ENV.lock().unwrap();
+    match Config::load(Some(&config_file(text))) {
+      Err(Error::Config { message, .. }) => message,
+      other => panic!("expected a config error, got {:?}", other.map(|_| ())),
+    }
+  }
+
+  #[test]
+  fn later_sources_override_earlier_ones() {
+    let _env = ENV.lock().unwrap();
+    let path = config_file("db = '/file.db'\n[web]\nport = 4000\nbind = '0.0.0.0'\n");
+
+    env::set_var("NEB_DB", "/env.db");
+    env::set_var("PORT", "5000");
+    env::remove_var("BIND");
+    let loaded = Config::load(Some(&path));
+    env::remove_var("NEB_DB");
+    env::remove_var("PORT");
+    let mut config = loaded.unwrap();
+
+    assert_eq!(config.db.value.as_deref(), Some(Path::new("/env.db")));
+    assert_eq!(config.db.source, Source::Env("NEB_DB"));
+    assert_eq!(config.web_port.value, 5000);
+    assert_eq!(config.web_port.source, Source::Env("PORT"));
+    assert_eq!(config.web_bind.value, Ipv4Addr::UNSPECIFIED);
+    assert_eq!(config.web_bind.source, Source::File(path.clone()));
+    assert_eq!(config.download_dir.value, PathBuf::from("."));
+    assert_eq!(config.download_dir.source, Source::Default);
+
+    config.db.set_from(None, Source::Flag("--db"));
+    assert_eq!(config.db.source, Source::Env("NEB_DB"));
+    config
+      .db
+      .set_from(Some(PathBuf::from("/flag.db")), Source::Flag("--db"));
+    assert_eq!(config.db.value.as_deref(), Some(Path::new("/flag.db")));
+    assert_eq!(config.db.source, Source::Flag("--db"));
+
+    let config = Config::load(Some(&path)).unwrap();
+    assert_eq!(config.db.value.as_deref(), Some(Path::new("/file.db")));
+    assert_eq!(config.web_port.value, 4000);
+  }
+
+  #[test]
+  fn paths_resolve_against_home_and_config_dir() {
+    let _env = ENV.lock().unwrap();
+    let path = config_file(
+      r#"
+      db = "~/neb/repo.db"
+      json = "repo.json"
+      download_dir = "/srv/downloads"
+      fs2_root = "../fs2"
+
+      [[sources]]
+      name = "local"
+      path = "builds/repo.json"
+
+      [[sources]]
+      name = "remote"
+      url = "https://example.com/repo.json"
+      "#,
+    );
+    let dir = path.parent().unwrap();
+    let config = Config::load(Some(&path)).unwrap();
+
+    let home = home_dir().unwrap_or_else(|| dir.join("~"));
+    assert_eq!(config.db.value, Some(home.join("neb/repo.db")));
+    assert_eq!(config.json.value, Some(dir.join("repo.json")));
+    assert_eq!(config.download_dir.value, PathBuf::from("/srv/downloads"));
+    assert_eq!(config.fs2_root.value, Some(dir.join("../fs2")));
+    assert_eq!(
+      config.sources.value,
+      [
+        RepoSource {
+          name: "local".to_string(),
+          location: Location::Path(dir.join("builds/repo.json")),
+        },
+        RepoSource {
+          name: "remote".to_string(),
+          location: Location::Url("https://example.com/repo.json".to_string()),
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn unknown_fields_are_rejected() {
+    for text in [
+      "database = 'repo.db'",
+      "[web]\nhost = '0.0.0.0'",
+      "[[sources]]\nname = 'a'\nurl = 'u'\nmirror = 'm'",
+    ] {
+      let message = load_error(text);
+      assert!(message.contains("unknown field"), "{}: {}", text, message);
+    }
+  }
+
+  #[test]
+  fn invalid_settings_are_rejected() {
+    assert!(load_error("sources = []").contains("empty"));
+    assert!(load_error("[[sources]]\nname = 'a b'\nurl = 'u'").contains("invalid source name"));
+    assert!(
+      load_error("[[sources]]\nname = 'a'\nurl = 'u'\n[[sources]]\nname = 'a'\nurl = 'v'")
+        .contains("duplicate")
+    );
+    assert!(load_error("[[sources]]\nname = 'a'\nurl = 'u'\npath = 'p'").contains("either"));
+    assert!(load_error("[[sources]]\nname = 'a'").contains("either"));
+    assert!(load_error("[web]\nbind = 'localhost'").contains("web.bind"));
+
+    let _env = ENV.lock().unwrap();
+    let missing = env::temp_dir().join("neb-config-missing.toml");
+    assert!(matches!(
+      Config::load(Some(&missing)),
+      Err(Error::FileNotFound(_))
+    ));
+  }
+}
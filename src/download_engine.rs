@@ -0,0 +1,864 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use curl::easy::{Easy2, Handler, List, WriteError};
+use curl::multi::{Easy2Handle, Multi};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+use crate::checksum;
+use crate::error::{Error, Result};
+use crate::knossos::Checksum;
+use crate::output;
+
+#[derive(Debug, Clone)]
+pub struct Options {
+  /// Number of files downloaded at the same time.
+  pub concurrency: usize,
+  /// Bytes per second across all transfers; `None` is unlimited.
+  pub max_recv_speed: Option<u64>,
+  /// Number of extra rounds through the mirrors of a file before giving up.
+  pub retries: u32,
+  /// Delay before the first retry round, doubled for each further round.
+  pub backoff: Duration,
+}
+
+impl Default for Options {
+  fn default() -> Options {
+    Options {
+      concurrency: 4,
+      max_recv_speed: None,
+      retries: 3,
+      backoff: Duration::from_secs(1),
+    }
+  }
+}
+
+/// One file to download to `path` from the first working mirror in `urls`.
+///
+/// With a checksum, an interrupted download is kept as `<path>.part` and
+/// resumed on the next attempt. Without one there is no way to tell a
+/// stale partial file from a good one, so the download starts over.
+#[derive(Debug, Clone)]
+pub struct Job {
+  pub name: String,
+  pub urls: Vec<String>,
+  pub path: PathBuf,
+  pub filesize: Option<u64>,
+  pub checksum: Option<Checksum>,
+  /// Extra request headers such as `If-None-Match: "v1"`. A 304 answer
+  /// leaves `path` as it is.
+  pub headers: Vec<String>,
+  /// Accept any transfer encoding curl can decode and save the decoded
+  /// data. Off for archives, whose size and checksum are of the raw file.
+  pub decode: bool,
+}
+
+/// What a job got from its last mirror.
+#[derive(Debug, Default)]
+pub struct Downloaded {
+  /// Bytes transferred, not counting a resumed partial file.
+  pub bytes: u64,
+  /// The answer to a conditional request was 304, so nothing was saved.
+  pub not_modified: bool,
+  /// Header lines of the last response after redirects.
+  pub headers: Vec<String>,
+}
+
+impl Downloaded {
+  /// The value of the response header `name`, ignoring case.
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self.headers.iter().find_map(|line| {
+      let (key, value) = line.split_once(':')?;
+      key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+  }
+}
+
+/// Downloads all `jobs` and returns, per job, what was downloaded or the
+/// last error. Failures are reported as they happen.
+pub fn download(jobs: &[Job], options: &Options) -> Result<Vec<Result<Downloaded>>> {
+  let draw_target = output::progress_target();
+  let hidden = draw_target.is_hidden();
+  let multi_progress = Arc::new(MultiProgress::with_draw_target(draw_target));
+  let total_bar = multi_progress.add(ProgressBar::new(
+    jobs.iter().filter_map(|job| job.filesize).sum(),
+  ));
+  total_bar.set_style(
+    ProgressStyle::default_bar()
+      .template("[{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+      .progress_chars("=> "),
+  );
+
+  // The total bar is finished last, which keeps `join` drawing until the
+  // end even though file bars come and go.
+  let drawer = {
+    let multi_progress = multi_progress.clone();
+    thread::spawn(move || multi_progress.join_and_clear())
+  };
+
+  let mut engine = Engine {
+    jobs,
+    options,
+    multi: Multi::new(),
+    multi_progress: &multi_progress,
+    hidden,
+    total_bar: total_bar.clone(),
+    limiter: Rc::new(RefCell::new(RateLimiter::new(options.max_recv_speed))),
+    queue: (0..jobs.len()).map(Attempt::new).collect(),
+    waiting: Vec::new(),
+    active: Vec::new(),
+    outcomes: jobs.iter().map(|_| None).collect(),
+  };
+  let result = engine.run();
+
+  total_bar.finish_and_clear();
+  let _ = drawer.join();
+
+  result?;
+  Ok(
+    engine
+      .outcomes
+      .into_iter()
+      .map(|outcome| outcome.expect("every job has an outcome"))
+      .collect(),
+  )
+}
+
+/// Parses a rate such as `500K`, `2M` or `1048576` into bytes per second.
+pub fn parse_rate(text: &str) -> Option<u64> {
+  parse_size(text).filter(|&n| n > 0)
+}
+
+/// Parses a size such as `500K`, `2M`, `1G` or `1048576` into bytes.
+pub fn parse_size(text: &str) -> Option<u64> {
+  let text = text.trim();
+  let (number, unit) = match text.char_indices().last()? {
+    (i, c) if c.is_ascii_alphabetic() => (&text[..i], c.to_ascii_uppercase()),
+    _ => (text, 'B'),
+  };
+  let multiplier = match unit {
+    'B' => 1,
+    'K' => 1024,
+    'M' => 1024 * 1024,
+    'G' => 1024 * 1024 * 1024,
+    _ => return None,
+  };
+
+  number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+pub fn part_path(path: &Path) -> PathBuf {
+  let mut part = path.as_os_str().to_owned();
+  part.push(".part");
+  PathBuf::from(part)
+}
+
+/// Where a job is in its walk through mirrors and retry rounds.
+struct Attempt {
+  job: usize,
+  mirror: usize,
+  round: u32,
+  /// Ignore the partial file and download from the start.
+  from_scratch: bool,
+  /// Bytes of this job currently credited to the total bar.
+  counted: u64,
+  transferred: u64,
+  /// Response headers of the last transfer.
+  headers: Vec<String>,
+}
+
+impl Attempt {
+  fn new(job: usize) -> Attempt {
+    Attempt {
+      job,
+      mirror: 0,
+      round: 0,
+      from_scratch: false,
+      counted: 0,
+      transferred: 0,
+      headers: Vec::new(),
+    }
+  }
+}
+
+struct Active {
+  handle: Easy2Handle<Transfer>,
+  attempt: Attempt,
+  offset: u64,
+}
+
+struct Engine<'a> {
+  jobs: &'a [Job],
+  options: &'a Options,
+  multi: Multi,
+  multi_progress: &'a MultiProgress,
+  hidden: bool,
+  total_bar: ProgressBar,
+  limiter: Rc<RefCell<RateLimiter>>,
+  queue: VecDeque<Attempt>,
+  waiting: Vec<(Instant, Attempt)>,
+  active: Vec<Active>,
+  outcomes: Vec<Option<Result<Downloaded>>>,
+}
+
+impl<'a> Engine<'a> {
+  fn run(&mut self) -> Result<()> {
+    loop {
+      self.wake_retries();
+      while self.active.len() < self.options.concurrency.max(1) {
+        match self.queue.pop_front() {
+          Some(attempt) => self.start(attempt)?,
+          None => break,
+        }
+      }
+
+      if self.active.is_empty() {
+        match self.waiting.iter().map(|(at, _)| *at).min() {
+          Some(at) => thread::sleep(at.saturating_duration_since(Instant::now())),
+          None if self.queue.is_empty() => return Ok(()),
+          None => {}
+        }
+        continue;
+      }
+
+      self.multi.perform()?;
+
+      let mut finished = Vec::new();
+      self.multi.messages(|message| {
+        for (i, active) in self.active.iter().enumerate() {
+          if let Some(result) = message.result_for2(&active.handle) {
+            finished.push((i, result));
+          }
+        }
+      });
+
+      finished.sort_by_key(|(i, _)| std::cmp::Reverse(*i));
+      for (i, result) in finished {
+        let active = self.active.swap_remove(i);
+        let mut easy = self.multi.remove2(active.handle)?;
+        let transfer = easy.get_mut();
+        transfer.bar.finish_and_clear();
+
+        let mut attempt = active.attempt;
+        attempt.transferred += transfer.written;
+        attempt.counted = active.offset + transfer.written;
+        attempt.headers = std::mem::take(&mut transfer.headers);
+
+        let result = match (result, transfer.write_error.take()) {
+          (_, Some(e)) => Err(Error::io(&transfer.path)(e)),
+          (result, None) => result
+            .map_err(Error::from)
+            .and_then(|()| complete(&mut easy)),
+        };
+        match result {
+          Ok(304) => self.not_modified(attempt)?,
+          result => self.finish(attempt, active.offset, result.map(|_| ()))?,
+        }
+      }
+
+      let delay = self.limiter.borrow_mut().delay();
+      if delay.is_zero() {
+        for active in &mut self.active {
+          if active.handle.get_ref().paused {
+            active.handle.get_mut().paused = false;
+            active.handle.unpause_write()?;
+          }
+        }
+        self.multi.wait(&mut [], Duration::from_millis(100))?;
+      } else {
+        // Paused sockets stay readable, so `wait` would return at once.
+        thread::sleep(delay.min(Duration::from_millis(100)));
+      }
+    }
+  }
+
+  fn start(&mut self, mut attempt: Attempt) -> Result<()> {
+    let job = &self.jobs[attempt.job];
+    let part_path = part_path(&job.path);
+
+    let mut offset = 0;
+    if job.checksum.is_some() && !attempt.from_scratch {
+      offset = fs::metadata(&part_path).map(|m| m.len()).unwrap_or(0);
+      if job.filesize.is_some_and(|size| offset > size) {
+        offset = 0;
+      }
+    }
+    attempt.from_scratch = false;
+    self.credit(&mut attempt, offset);
+
+    if offset > 0 && Some(offset) == job.filesize {
+      // Complete but never verified, e.g. interrupted before the rename.
+      return self.finish(attempt, offset, Ok(()));
+    }
+
+    let url = match job.urls.get(attempt.mirror) {
+      Some(url) => url,
+      None => {
+        self.log(&Error::NoMirrors(job.name.clone()).to_string());
+        self.outcomes[attempt.job] = Some(Err(Error::NoMirrors(job.name.clone())));
+        return Ok(());
+      }
+    };
+
+    let file = OpenOptions::new()
+      .create(true)
+      .write(true)
+      .append(offset > 0)
+      .truncate(offset == 0)
+      .open(&part_path)
+      .map_err(Error::io(&part_path))?;
+
+    let bar = self
+      .multi_progress
+      .add(ProgressBar::new(job.filesize.unwrap_or(0)));
+    bar.set_style(
+      ProgressStyle::default_bar()
+        .template("{prefix:30} [{wide_bar}] {bytes}/{total_bytes}")
+        .progress_chars("=> "),
+    );
+    bar.set_prefix(job.name.clone());
+    bar.set_position(offset);
+
+    let mut easy = Easy2::new(Transfer {
+      file,
+      path: part_path,
+      offset,
+      written: 0,
+      write_error: None,
+      headers: Vec::new(),
+      paused: false,
+      size_known: job.filesize.is_some(),
+      bar,
+      total_bar: self.total_bar.clone(),
+      limiter: self.limiter.clone(),
+    });
+    easy.url(url)?;
+    easy.follow_location(true)?;
+    easy.fail_on_error(true)?;
+    easy.progress(true)?;
+    easy.connect_timeout(Duration::from_secs(30))?;
+    // Treat a stalled connection as a failed mirror.
+    easy.low_speed_limit(1)?;
+    easy.low_speed_time(Duration::from_secs(60))?;
+    if offset > 0 {
+      easy.resume_from(offset)?;
+    }
+    if job.decode {
+      easy.accept_encoding("")?;
+    }
+    if !job.headers.is_empty() {
+      let mut headers = List::new();
+      for header in &job.headers {
+        headers.append(header)?;
+      }
+      easy.http_headers(headers)?;
+    }
+
+    let handle = self.multi.add2(easy)?;
+    self.active.push(Active {
+      handle,
+      attempt,
+      offset,
+    });
+
+    Ok(())
+  }
+
+  fn finish(&mut self, mut attempt: Attempt, offset: u64, result: Result<()>) -> Result<()> {
+    let job = &self.jobs[attempt.job];
+    let part_path = part_path(&job.path);
+    let url = job.urls.get(attempt.mirror).map_or("", String::as_str);
+
+    let result = result.and_then(|()| verify(&part_path, job));
+    let e = match result {
+      Ok(()) => {
+        fs::rename(&part_path, &job.path).map_err(Error::io(&job.path))?;
+        if output::is_verbose() {
+          self.log(&format!("Downloaded {} from {}", job.name, url));
+        }
+        self.outcomes[attempt.job] = Some(Ok(Downloaded {
+          bytes: attempt.transferred,
+          not_modified: false,
+          headers: attempt.headers,
+        }));
+        return Ok(());
+      }
+      Err(e) => e,
+    };
+
+    self.log(&format!("{}: {}", url, e));
+
+    match &e {
+      Error::Io { .. } => {
+        // A local problem such as a full disk; another mirror will not help.
+        self.outcomes[attempt.job] = Some(Err(e));
+        return Ok(());
+      }
+      Error::Curl(curl_error) if curl_error.is_range_error() && offset > 0 => {
+        // The mirror cannot resume; download the whole file from it.
+        attempt.from_scratch = true;
+        self.queue.push_front(attempt);
+        return Ok(());
+      }
+      Error::ChecksumMismatch { .. } | Error::SizeMismatch { .. } => {
+        let _ = fs::remove_file(&part_path);
+        self.credit(&mut attempt, 0);
+        if offset > 0 {
+          // The partial file may have been the corrupt part.
+          attempt.from_scratch = true;
+          self.queue.push_front(attempt);
+          return Ok(());
+        }
+      }
+      _ => {}
+    }
+
+    attempt.mirror += 1;
+    if attempt.mirror < job.urls.len() {
+      self.queue.push_front(attempt);
+      return Ok(());
+    }
+
+    attempt.mirror = 0;
+    attempt.round += 1;
+    if attempt.round > self.options.retries {
+      self.log(&format!("Giving up on {}", job.name));
+      self.outcomes[attempt.job] = Some(Err(e));
+      return Ok(());
+    }
+
+    let delay = retry_delay(self.options.backoff, attempt.round);
+    self.log(&format!(
+      "Retrying {} in {} s ({}/{})",
+      job.name,
+      delay.as_secs_f32(),
+      attempt.round,
+      self.options.retries
+    ));
+    self.waiting.push((Instant::now() + delay, attempt));
+    Ok(())
+  }
+
+  /// Drops the empty partial file of a 304 answer and keeps `path`.
+  fn not_modified(&mut self, attempt: Attempt) -> Result<()> {
+    let job = &self.jobs[attempt.job];
+    let part_path = part_path(&job.path);
+    fs::remove_file(&part_path).map_err(Error::io(&part_path))?;
+    if output::is_verbose() {
+      self.log(&format!("{} is not modified", job.name));
+    }
+    self.outcomes[attempt.job] = Some(Ok(Downloaded {
+      bytes: attempt.transferred,
+      not_modified: true,
+      headers: attempt.headers,
+    }));
+    Ok(())
+  }
+
+  fn wake_retries(&mut self) {
+    let now = Instant::now();
+    let mut i = 0;
+    while i < self.waiting.len() {
+      if self.waiting[i].0 <= now {
+        let (_, attempt) = self.waiting.swap_remove(i);
+        self.queue.push_back(attempt);
+      } else {
+        i += 1;
+      }
+    }
+  }
+
+  /// Moves the total bar so that it counts `bytes` for this job.
+  fn credit(&self, attempt: &mut Attempt, bytes: u64) {
+    let position = self.total_bar.position();
+    self
+      .total_bar
+      .set_position(position.saturating_sub(attempt.counted) + bytes);
+    attempt.counted = bytes;
+  }
+
+  fn log(&self, message: &str) {
+    if self.hidden {
+      eprintln!("{}", message);
+    } else {
+      self.total_bar.println(message);
+    }
+  }
+}
+
+/// The response code of a finished transfer, failing it when fewer bytes
+/// arrived than Content-Length announced, which counts them as sent
+/// before any decoding.
+fn complete(easy: &mut Easy2<Transfer>) -> Result<u32> {
+  let expected = easy.content_length_download()?;
+  let received = easy.download_size()?;
+  if expected >= 0.0 && received < expected {
+    return Err(Error::InvalidDownload {
+      url: easy.effective_url()?.unwrap_or_default().to_string(),
+      message: format!("got {} of {} bytes", received, expected),
+    });
+  }
+  Ok(easy.response_code()?)
+}
+
+/// The pause before retry `round`, counting from 1: `backoff`, then twice
+/// as long for each further round.
+fn retry_delay(backoff: Duration, round: u32) -> Duration {
+  backoff.saturating_mul(2u32.saturating_pow(round.saturating_sub(1)))
+}
+
+/// Checks the size and checksum of `path` against what `job` expects.
+pub fn verify(path: &Path, job: &Job) -> Result<()> {
+  if let Some(filesize) = job.filesize {
+    let size = fs::metadata(path).map_err(Error::io(path))?.len();
+    if size != filesize {
+      return Err(Error::SizeMismatch {
+        path: path.to_path_buf(),
+        expected: filesize,
+        actual: size,
+      });
+    }
+  }
+
+  match &job.checksum {
+    Some(checksum) => checksum::verify_file(path, checksum),
+    None => Ok(()),
+  }
+}
+
+struct Transfer {
+  file: File,
+  path: PathBuf,
+  offset: u64,
+  written: u64,
+  write_error: Option<io::Error>,
+  headers: Vec<String>,
+  paused: bool,
+  size_known: bool,
+  bar: ProgressBar,
+  total_bar: ProgressBar,
+  limiter: Rc<RefCell<RateLimiter>>,
+}
+
+impl Handler for Transfer {
+  fn write(&mut self, data: &[u8]) -> std::result::Result<usize, WriteError> {
+    if !self.limiter.borrow_mut().take(data.len() as u64) {
+      self.paused = true;
+      return Err(WriteError::Pause);
+    }
+
+    match self.file.write_all(data) {
+      Ok(()) => {
+        self.written += data.len() as u64;
+        self.bar.inc(data.len() as u64);
+        self.total_bar.inc(data.len() as u64);
+        Ok(data.len())
+      }
+      Err(e) => {
+        // Returning a short count makes curl abort the transfer.
+        self.write_error = Some(e);
+        Ok(0)
+      }
+    }
+  }
+
+  fn header(&mut self, data: &[u8]) -> bool {
+    let line = String::from_utf8_lossy(data).trim_end().to_string();
+    // Only keep the headers of the last response after redirects.
+    if line.starts_with("HTTP/") {
+      self.headers.clear();
+    }
+    if !line.is_empty() {
+      self.headers.push(line);
+    }
+    true
+  }
+
+  fn progress(&mut self, dltotal: f64, _dlnow: f64, _ultotal: f64, _ulnow: f64) -> bool {
+    let total = self.offset + dltotal as u64;
+    if !self.size_known && total > self.offset {
+      self.size_known = true;
+      self.bar.set_length(total);
+      self.total_bar.inc_length(total);
+    }
+    true
+  }
+}
+
+/// Token bucket shared by all transfers. Writes may overdraw it; the
+/// debt is paid back before any paused transfer resumes.
+struct RateLimiter {
+  rate: Option<u64>,
+  tokens: f64,
+  last: Instant,
+}
+
+impl RateLimiter {
+  fn new(rate: Option<u64>) -> RateLimiter {
+    RateLimiter {
+      rate,
+      tokens: rate.unwrap_or(0) as f64,
+      last: Instant::now(),
+    }
+  }
+
+  fn refill(&mut self) {
+    if let Some(rate) = self.rate {
+      let now = Instant::now();
+      let elapsed = now.duration_since(self.last).as_secs_f64();
+      self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+      self.last = now;
+    }
+  }
+
+  /// How long until the debt is paid back.
+  fn delay(&mut self) -> Duration {
+    self.refill();
+    match self.rate {
+      Some(rate) if self.tokens <= 0.0 => Duration::from_secs_f64(-self.tokens / rate as f64),
+      _ => Duration::ZERO,
+    }
+  }
+
+  fn take(&mut self, bytes: u64) -> bool {
+    if !self.delay().is_zero() {
+      return false;
+    }
+    if self.rate.is_some() {
+      self.tokens -= bytes as f64;
+    }
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::mpsc;
+
+  use tiny_http::{Header, Response, Server};
+
+  #[test]
+  fn parses_sizes_and_rates() {
+    assert_eq!(parse_size("1048576"), Some(1048576));
+    assert_eq!(parse_size("500K"), Some(500 * 1024));
+    assert_eq!(parse_size(" 2m "), Some(2 * 1024 * 1024));
+    assert_eq!(parse_size("1G"), Some(1024 * 1024 * 1024));
+    assert_eq!(parse_size("10B"), Some(10));
+    assert_eq!(parse_size("0"), Some(0));
+    assert_eq!(parse_size(""), None);
+    assert_eq!(parse_size("K"), None);
+    assert_eq!(parse_size("1T"), None);
+    assert_eq!(parse_size("1.5M"), None);
+    assert_eq!(parse_size("-1"), None);
+    assert_eq!(parse_size("99999999999999999999G"), None);
+    assert_eq!(parse_size("17179869184G"), None);
+
+    assert_eq!(parse_rate("2M"), Some(2 * 1024 * 1024));
+    assert_eq!(parse_rate("0"), None);
+    assert_eq!(parse_rate("0K"), None);
+  }
+
+  #[test]
+  fn retry_delay_doubles_per_round() {
+    let backoff = Duration::from_millis(500);
+    let delays: Vec<_> = (1..=4).map(|round| retry_delay(backoff, round)).collect();
+    assert_eq!(
+      delays,
+      [500, 1000, 2000, 4000].map(Duration::from_millis).to_vec()
+    );
+    assert_eq!(retry_delay(Duration::ZERO, 3), Duration::ZERO);
+    // Many rounds saturate instead of overflowing.
+    assert_eq!(retry_delay(backoff, 100), backoff * u32::MAX);
+  }
+
+  #[test]
+  fn rate_limiter_pays_back_overdraft() {
+    let mut unlimited = RateLimiter::new(None);
+    assert!(unlimited.take(u64::MAX));
+    assert!(unlimited.take(u64::MAX));
+    assert_eq!(unlimited.delay(), Duration::ZERO);
+
+    let mut limiter = RateLimiter::new(Some(1000));
+    // A write may overdraw the bucket by more than a second's worth.
+    assert!(limiter.take(3000));
+    let delay = limiter.delay();
+    assert!(delay > Duration::from_millis(1900) && delay <= Duration::from_secs(2));
+    assert!(!limiter.take(1));
+
+    // Pretend the debt has been paid back.
+    limiter.last -= Duration::from_secs(3);
+    assert_eq!(limiter.delay(), Duration::ZERO);
+    assert!(limiter.take(1));
+    // Refilling never saves up more than a second's worth.
+    limiter.last -= Duration::from_secs(60);
+    limiter.refill();
+    assert!(limiter.tokens <= 1000.0);
+  }
+
+  /// Serves `body` at /file, honouring `Range: bytes=N-`, and 404 at any
+  /// other path. Sends each request's path and Range header back.
+  fn serve(body: &'static [u8]) -> (String, mpsc::Receiver<(String, Option<String>)>) {
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", server.server_addr().to_ip().unwrap());
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+      for request in server.incoming_requests() {
+        let range = request
+          .headers()
+          .iter()
+          .find(|h| h.field.equiv("Range"))
+          .map(|h| h.value.to_string());
+        sender
+          .send((request.url().to_string(), range.clone()))
+          .unwrap();
+
+        if request.url() != "/file" {
+          request
+            .respond(Response::from_string("").with_status_code(404))
+            .unwrap();
+          continue;
+        }
+
+        let start = range
+          .as_deref()
+          .and_then(|r| r.strip_prefix("bytes="))
+          .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+        let response = match start {
+          Some(start) => {
+            let content_range = format!("bytes {}-{}/{}", start, body.len() - 1, body.len());
+            Response::from_data(&body[start..])
+              .with_status_code(206)
+              .with_header(Header::from_bytes("Content-Range", content_range).unwrap())
+          }
+          None => Response::from_data(body),
+        };
+        request.respond(response).unwrap();
+      }
+    });
+
+    (base, receiver)
+  }
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("neb-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn job(dir: &Path, urls: Vec<String>, body: &[u8]) -> Job {
+    let reference = dir.join("reference");
+    fs::write(&reference, body).unwrap();
+    let sha256 = checksum::sha256_file(&reference).unwrap();
+
+    Job {
+      name: "file".to_string(),
+      urls,
+      path: dir.join("file"),
+      filesize: Some(body.len() as u64),
+      checksum: Some(Checksum("sha256".to_string(), sha256)),
+      headers: Vec::new(),
+      decode: false,
+    }
+  }
+
+  fn options() -> Options {
+    Options {
+      retries: 0,
+      backoff: Duration::ZERO,
+      ..Options::default()
+    }
+  }
+
+  static BODY: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+  #[test]
+  fn fails_over_to_next_mirror() {
+    let dir = temp_dir("engine-failover");
+    let (base, requests) = serve(BODY);
+    let job = job(
+      &dir,
+      vec![format!("{}/missing", base), format!("{}/file", base)],
+      BODY,
+    );
+
+    let outcomes = download(std::slice::from_ref(&job), &options()).unwrap();
+    assert_eq!(outcomes[0].as_ref().unwrap().bytes, BODY.len() as u64);
+    assert_eq!(fs::read(&job.path).unwrap(), BODY);
+    assert!(!part_path(&job.path).exists());
+
+    let paths: Vec<_> = requests.try_iter().map(|(path, _)| path).collect();
+    assert_eq!(paths, ["/missing", "/file"]);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn resumes_partial_download() {
+    let dir = temp_dir("engine-resume");
+    let (base, requests) = serve(BODY);
+    let job = job(&dir, vec![format!("{}/file", base)], BODY);
+    fs::write(part_path(&job.path), &BODY[..10]).unwrap();
+
+    let outcomes = download(std::slice::from_ref(&job), &options()).unwrap();
+    assert_eq!(outcomes[0].as_ref().unwrap().bytes, BODY.len() as u64 - 10);
+    assert_eq!(fs::read(&job.path).unwrap(), BODY);
+
+    let ranges: Vec<_> = requests.try_iter().map(|(_, range)| range).collect();
+    assert_eq!(ranges, [Some("bytes=10-".to_string())]);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn gives_up_when_no_mirror_works() {
+    let dir = temp_dir("engine-give-up");
+    let (base, requests) = serve(BODY);
+    let job = job(&dir, vec![format!("{}/missing", base)], BODY);
+    let options = Options {
+      retries: 2,
+      ..options()
+    };
+
+    let outcomes = download(std::slice::from_ref(&job), &options).unwrap();
+    assert!(outcomes[0].is_err());
+    assert!(!job.path.exists());
+    assert_eq!(requests.try_iter().count(), 3);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn verify_checks_size_before_checksum() {
+    let dir = temp_dir("engine-verify");
+    let mut job = job(&dir, Vec::new(), BODY);
+    fs::write(&job.path, BODY).unwrap();
+    verify(&job.path, &job).unwrap();
+
+    fs::write(&job.path, &BODY[1..]).unwrap();
+    assert!(matches!(
+      verify(&job.path, &job),
+      Err(Error::SizeMismatch { expected, actual, .. })
+        if expected == BODY.len() as u64 && actual == BODY.len() as u64 - 1
+    ));
+
+    let mut changed = BODY.to_vec();
+    changed[0] = b'x';
+    fs::write(&job.path, &changed).unwrap();
+    assert!(matches!(
+      verify(&job.path, &job),
+      Err(Error::ChecksumMismatch { .. })
+    ));
+
+    job.filesize = None;
+    job.checksum = None;
+    verify(&job.path, &job).unwrap();
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
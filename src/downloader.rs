@@ -1,126 +1,456 @@
 use std::fs::{self, File};
-use std::io::Write;
-use std::path::Path;
-use std::process;
-use std::str;
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
 
-use curl::easy::Easy;
-use indicatif::{ProgressBar, ProgressStyle};
-use regex::Regex;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
 
-pub fn fetch(json_path: &Path) {
-  let url = "https://fsnebula.org/storage/repo.json";
+use crate::download_engine::{self, Downloaded, Job, Options};
+use crate::error::{Error, Result};
+use crate::json::{self, Compression};
+use crate::knossos::ModFile;
+use crate::status;
 
-  let path = json_path.to_string_lossy();
-  let header_path_string = format!("{}.header", path);
-  let header_path = Path::new(&header_path_string);
-  let json_part_path_string = format!("{}.part", path);
-  let json_part_path = Path::new(&json_part_path_string);
+#[derive(Debug, Default, Serialize)]
+pub struct DownloadSummary {
+  pub downloaded: usize,
+  pub skipped: usize,
+  pub failed: Vec<String>,
+  pub bytes: u64,
+}
 
-  // DOWNLOAD HEADER
+/// Cache validators of a fetched repo json, kept next to it as JSON in
+/// `<json>.meta` so that the next fetch only downloads a changed file.
+#[derive(Debug, Serialize, Deserialize)]
+struct FetchMeta {
+  url: String,
+  etag: Option<String>,
+  last_modified: Option<String>,
+}
 
-  let prev_header = fs::read_to_string(header_path).unwrap_or_else(|_| "".to_string());
-  let curr_header = download_header(url);
+/// Downloads the repo json of each `(path, url)` in `files` through the
+/// download engine, with GETs conditional on the validators of the last
+/// fetch unless `force`.
+///
+/// A download goes to `<json>.download` and only replaces the json once
+/// it is complete and holds a `mods` array, compressed to match the json
+/// file name. The validators are saved after that, so an interrupted or
+/// bad download is fetched again. Every file is handled before the first
+/// error is returned.
+pub fn fetch(files: &[(PathBuf, String)], force: bool, options: &Options) -> Result<()> {
+  let jobs: Vec<Job> = files
+    .iter()
+    .map(|(json_path, url)| {
+      let prev_meta = read_meta(&suffixed_path(json_path, ".meta"))
+        .filter(|meta| !force && &meta.url == url && json_path.exists());
+      let mut headers = Vec::new();
+      if let Some(etag) = prev_meta.as_ref().and_then(|meta| meta.etag.as_ref()) {
+        headers.push(format!("If-None-Match: {}", etag));
+      }
+      if let Some(date) = prev_meta
+        .as_ref()
+        .and_then(|meta| meta.last_modified.as_ref())
+      {
+        headers.push(format!("If-Modified-Since: {}", date));
+      }
 
-  let re_etag = Regex::new(r#"(?mi)^etag: "(.+)""#).unwrap();
-  let prev_etag = re_etag.captures(&prev_header).map(|cap| cap[1].to_string());
-  let curr_etag = re_etag.captures(&curr_header).map(|cap| cap[1].to_string());
+      Job {
+        name: file_name(&json_path.to_string_lossy())
+          .display()
+          .to_string(),
+        urls: vec![url.clone()],
+        path: suffixed_path(json_path, ".download"),
+        filesize: None,
+        checksum: None,
+        headers,
+        decode: true,
+      }
+    })
+    .collect();
 
-  if prev_etag == curr_etag {
-    println!("Already most recent version.");
-    return;
+  let outcomes = download_engine::download(&jobs, options)?;
+  let mut first_error = None;
+  for (((json_path, url), job), outcome) in files.iter().zip(&jobs).zip(outcomes) {
+    let result = outcome.and_then(|downloaded| save(json_path, url, &job.path, downloaded));
+    if let Err(e) = result {
+      let _ = fs::remove_file(&job.path);
+      let _ = fs::remove_file(download_engine::part_path(&job.path));
+      match first_error {
+        None => first_error = Some(e),
+        Some(_) => eprintln!("{}", e),
+      }
+    }
   }
 
-  // DOWNLOAD BODY
+  first_error.map_or(Ok(()), Err)
+}
 
-  // Create placeholder files until "part" finishes downloading.
-  create_placeholder_file(header_path);
-  create_placeholder_file(json_path);
+/// Moves a checked download from `download_path` over `json_path` and
+/// saves its validators.
+fn save(json_path: &Path, url: &str, download_path: &Path, downloaded: Downloaded) -> Result<()> {
+  if downloaded.not_modified {
+    status!("Already most recent version: {}", json_path.display());
+    return Ok(());
+  }
 
-  download_json_file(url, json_part_path);
+  validate(download_path, url)?;
+  let part_path = download_engine::part_path(json_path);
+  compress(download_path, &part_path, Compression::from_path(json_path))?;
+  replace(&part_path, json_path)?;
+  let _ = fs::remove_file(download_path);
 
-  fs::write(header_path, curr_header).unwrap_or_else(|_| {
-    println!("Failed to write header file: {}", header_path.display());
-  });
-  fs::rename(json_part_path, json_path).unwrap_or_else(|_| {
-    println!("Failed to move repo file to '{}'", json_path.display());
-    process::exit(1);
-  });
-}
+  let meta_path = suffixed_path(json_path, ".meta");
+  let meta = FetchMeta {
+    url: url.to_string(),
+    etag: downloaded.header("etag").map(String::from),
+    last_modified: downloaded.header("last-modified").map(String::from),
+  };
+  if write_meta(&meta_path, &meta).is_err() {
+    status!("Failed to write metadata file: {}", meta_path.display());
+  }
 
-fn download_header(url: &str) -> String {
-  let mut headers = Vec::new();
-  let mut header_handle = Easy::new();
-  header_handle.url(url).unwrap();
-  header_handle.nobody(true).unwrap();
-
-  let mut transfer = header_handle.transfer();
-  transfer
-    .header_function(|data| {
-      headers.push(str::from_utf8(data).unwrap().to_string());
-      true
-    })
-    .unwrap();
+  // Older versions kept the raw response headers here.
+  let _ = fs::remove_file(suffixed_path(json_path, ".header"));
+
+  Ok(())
+}
 
-  transfer.perform().unwrap_or_else(|_| {
-    println!("Failed to download header.");
-  });
+/// Checks that `path` holds a whole repo json: valid JSON with a `mods`
+/// array. The mods themselves are skipped rather than kept in memory.
+fn validate(path: &Path, url: &str) -> Result<()> {
+  #[derive(Deserialize)]
+  struct Repo {
+    #[allow(dead_code)]
+    mods: Vec<IgnoredAny>,
+  }
 
-  drop(transfer);
+  let reader = BufReader::new(json::open(path)?);
+  serde_json::from_reader::<_, Repo>(reader).map_err(|e| Error::InvalidDownload {
+    url: url.to_string(),
+    message: e.to_string(),
+  })?;
+  Ok(())
+}
 
-  headers.join("")
+/// Writes the plain json at `from` to `to` with `compression` and syncs it.
+fn compress(from: &Path, to: &Path, compression: Compression) -> Result<()> {
+  let mut reader = File::open(from).map_err(Error::io(from))?;
+  let file = File::create(to).map_err(Error::io(to))?;
+  let mut writer = json::Writer::new(file, compression).map_err(Error::io(to))?;
+  io::copy(&mut reader, &mut writer).map_err(Error::io(to))?;
+  let file = writer.finish().map_err(Error::io(to))?;
+  file.sync_all().map_err(Error::io(to))
 }
 
-fn create_placeholder_file(path: &Path) {
-  if !path.exists() {
-    File::create(path).unwrap_or_else(|_| {
-      println!("Could not create file: {}", path.display());
-      process::exit(1);
-    });
+/// Renames `part_path` over `path`, so that readers see either the old
+/// or the new file, and syncs the directory to make the rename durable.
+fn replace(part_path: &Path, path: &Path) -> Result<()> {
+  fs::rename(part_path, path).map_err(Error::io(path))?;
+
+  let dir = match path.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => Path::new("."),
+  };
+  // Directories cannot be opened for syncing on every platform.
+  if let Ok(dir) = File::open(dir) {
+    let _ = dir.sync_all();
   }
+  Ok(())
 }
 
-fn download_json_file(url: &str, json_part_path: &Path) {
-  let mut json_part_file = File::create(json_part_path).unwrap();
-  let mut download_handle = Easy::new();
-  download_handle.url(url).unwrap();
-  add_progress_bar(&mut download_handle);
+fn read_meta(path: &Path) -> Option<FetchMeta> {
+  let text = fs::read_to_string(path).ok()?;
+  serde_json::from_str(&text).ok()
+}
 
-  download_handle
-    .write_function(move |data| {
-      json_part_file.write_all(data).unwrap();
-      Ok(data.len())
-    })
-    .unwrap();
+fn write_meta(path: &Path, meta: &FetchMeta) -> Result<()> {
+  let part_path = download_engine::part_path(path);
+  let text = serde_json::to_string_pretty(meta)?;
+  let mut file = File::create(&part_path).map_err(Error::io(&part_path))?;
+  file
+    .write_all(text.as_bytes())
+    .and_then(|()| file.sync_all())
+    .map_err(Error::io(&part_path))?;
+  replace(&part_path, path)
+}
 
-  download_handle.perform().unwrap_or_else(|_| {
-    println!("Download failed.");
-    process::exit(1);
-  });
+fn suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+  let mut path = path.as_os_str().to_os_string();
+  path.push(suffix);
+  PathBuf::from(path)
 }
 
-fn add_progress_bar(download_handle: &mut Easy) {
-  let progress_bar = ProgressBar::new(0);
-  progress_bar.set_style(
-    ProgressStyle::default_bar()
-      .template("[{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
-      .progress_chars("=> "),
-  );
-
-  download_handle.progress(true).unwrap();
-  download_handle
-    .progress_function(move |dltotal, dlnow, _ultotal, _ulnow| {
-      let now = dlnow as u64;
-      let total = dltotal as u64;
-
-      if progress_bar.length() != total && total > 0 {
-        progress_bar.set_length(total);
+/// Downloads `files` into `dest_dir`, trying the mirrors of each file in
+/// order. Files already present with the right size and checksum are
+/// skipped, and interrupted downloads resume from their `.part` file.
+pub fn download_files(
+  files: &[&ModFile],
+  dest_dir: &Path,
+  options: &Options,
+) -> Result<DownloadSummary> {
+  fs::create_dir_all(dest_dir).map_err(Error::io(dest_dir))?;
+  let mut summary = DownloadSummary::default();
+  let mut jobs = Vec::new();
+
+  for file in files {
+    let job = Job {
+      name: file.filename.clone(),
+      urls: file.urls.clone(),
+      path: archive_path(dest_dir, &file.filename),
+      filesize: Some(file.filesize),
+      checksum: Some(file.checksum.clone()),
+      headers: Vec::new(),
+      decode: false,
+    };
+
+    if job.path.exists() && download_engine::verify(&job.path, &job).is_ok() {
+      status!("==> {} (already downloaded)", file.filename);
+      summary.skipped += 1;
+    } else {
+      jobs.push(job);
+    }
+  }
+
+  let outcomes = download_engine::download(&jobs, options)?;
+  for (job, outcome) in jobs.iter().zip(outcomes) {
+    match outcome {
+      Ok(downloaded) => {
+        summary.downloaded += 1;
+        summary.bytes += downloaded.bytes;
       }
+      Err(_) => summary.failed.push(job.name.clone()),
+    }
+  }
+
+  Ok(summary)
+}
+
+/// Where the archive `filename` is saved in `dest_dir`, and where
+/// `installer` looks for it. Only the last component is kept so a filename
+/// cannot escape `dest_dir`.
+pub fn archive_path(dest_dir: &Path, filename: &str) -> PathBuf {
+  dest_dir.join(file_name(filename))
+}
+
+fn file_name(filename: &str) -> &Path {
+  Path::new(filename)
+    .file_name()
+    .map(Path::new)
+    .unwrap_or_else(|| Path::new(filename))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::mpsc;
+  use std::thread;
+  use std::time::Duration;
+
+  use flate2::write::GzEncoder;
+  use tiny_http::{Header, Response, Server};
+
+  fn fetch_one(json_path: &Path, url: &str, force: bool) -> Result<()> {
+    let options = Options {
+      retries: 0,
+      ..Options::default()
+    };
+    fetch(
+      &[(json_path.to_path_buf(), url.to_string())],
+      force,
+      &options,
+    )
+  }
+
+  /// Method, If-None-Match and If-Modified-Since of a request.
+  type Request = (String, Option<String>, Option<String>);
 
-      if now > 0 {
-        progress_bar.set_position(now);
+  /// Serves one of `bodies` per request, the first with ETag "v1", the
+  /// second with "v2" and so on, and answers a request for "v1" with
+  /// 304. Sends each request's method and conditional headers back.
+  fn serve(bodies: Vec<&'static str>) -> (String, mpsc::Receiver<Request>) {
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/repo.json", server.server_addr().to_ip().unwrap());
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+      for (i, (request, body)) in server.incoming_requests().zip(bodies).enumerate() {
+        let header = |name: &'static str| {
+          request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv(name))
+            .map(|h| h.value.to_string())
+        };
+        let if_none_match = header("If-None-Match");
+        let if_modified_since = header("If-Modified-Since");
+        let not_modified = if_none_match.as_deref() == Some("\"v1\"");
+        sender
+          .send((
+            request.method().to_string(),
+            if_none_match,
+            if_modified_since,
+          ))
+          .unwrap();
+
+        let etag = Header::from_bytes("ETag", format!("\"v{}\"", i + 1)).unwrap();
+        let date = Header::from_bytes("Last-Modified", "Sat, 01 Jul 2023 00:00:00 GMT").unwrap();
+        let response = if not_modified {
+          Response::from_string("").with_status_code(304)
+        } else {
+          Response::from_string(body)
+        };
+        request
+          .respond(response.with_header(etag).with_header(date))
+          .unwrap();
       }
-      true
+    });
+
+    (url, receiver)
+  }
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("neb-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn fetch_is_conditional_on_stored_validators() {
+    let dir = temp_dir("fetch");
+    let json_path = dir.join("repo.json");
+    let (url, requests) = serve(vec![r#"{"mods": []}"#; 2]);
+
+    fetch_one(&json_path, &url, false).unwrap();
+    assert_eq!(fs::read_to_string(&json_path).unwrap(), r#"{"mods": []}"#);
+    assert_eq!(requests.recv().unwrap(), ("GET".to_string(), None, None));
+
+    let meta = read_meta(&dir.join("repo.json.meta")).unwrap();
+    assert_eq!(meta.url, url);
+    assert_eq!(meta.etag.as_deref(), Some("\"v1\""));
+    assert_eq!(
+      meta.last_modified.as_deref(),
+      Some("Sat, 01 Jul 2023 00:00:00 GMT")
+    );
+
+    fetch_one(&json_path, &url, false).unwrap();
+    assert_eq!(
+      requests.recv().unwrap(),
+      (
+        "GET".to_string(),
+        Some("\"v1\"".to_string()),
+        Some("Sat, 01 Jul 2023 00:00:00 GMT".to_string())
+      )
+    );
+    assert_eq!(fs::read_to_string(&json_path).unwrap(), r#"{"mods": []}"#);
+    assert!(!download_engine::part_path(&json_path).exists());
+    assert!(!dir.join("repo.json.download").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn fetch_retries_through_download_engine() {
+    let dir = temp_dir("retry");
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/repo.json", server.server_addr().to_ip().unwrap());
+    thread::spawn(move || {
+      for (i, request) in server.incoming_requests().enumerate() {
+        let response = match i {
+          0 => Response::from_string("busy").with_status_code(503),
+          _ => Response::from_string(r#"{"mods": []}"#),
+        };
+        request.respond(response).unwrap();
+      }
+    });
+
+    let json_path = dir.join("repo.json");
+    let options = Options {
+      retries: 1,
+      backoff: Duration::ZERO,
+      ..Options::default()
+    };
+    fetch(&[(json_path.clone(), url)], false, &options).unwrap();
+    assert_eq!(fs::read_to_string(&json_path).unwrap(), r#"{"mods": []}"#);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn fetch_ignores_validators_without_the_file() {
+    let dir = temp_dir("refetch");
+    let json_path = dir.join("repo.json");
+    let (url, requests) = serve(vec![r#"{"mods": []}"#; 2]);
+
+    fetch_one(&json_path, &url, false).unwrap();
+    fs::remove_file(&json_path).unwrap();
+    fetch_one(&json_path, &url, false).unwrap();
+
+    assert_eq!(requests.recv().unwrap().1, None);
+    assert_eq!(requests.recv().unwrap().1, None);
+    assert_eq!(fs::read_to_string(&json_path).unwrap(), r#"{"mods": []}"#);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn forced_fetch_keeps_file_and_validators_on_bad_download() {
+    let dir = temp_dir("truncated");
+    let json_path = dir.join("repo.json");
+    let meta_path = dir.join("repo.json.meta");
+    let (url, requests) = serve(vec![r#"{"mods": []}"#, r#"{"mods": [{"id""#]);
+
+    fetch_one(&json_path, &url, false).unwrap();
+    let meta = fs::read_to_string(&meta_path).unwrap();
+
+    let err = fetch_one(&json_path, &url, true).unwrap_err();
+    assert!(matches!(err, Error::InvalidDownload { .. }));
+    assert_eq!(requests.recv().unwrap().1, None);
+    assert_eq!(requests.recv().unwrap().1, None);
+
+    assert_eq!(fs::read_to_string(&json_path).unwrap(), r#"{"mods": []}"#);
+    assert_eq!(fs::read_to_string(&meta_path).unwrap(), meta);
+    assert!(!download_engine::part_path(&json_path).exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn fetch_decodes_gzip_and_stores_zstd() {
+    let dir = temp_dir("encoding");
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/repo.json", server.server_addr().to_ip().unwrap());
+
+    thread::spawn(move || {
+      let request = server.incoming_requests().next().unwrap();
+      let accepts_gzip = request
+        .headers()
+        .iter()
+        .any(|h| h.field.equiv("Accept-Encoding") && h.value.as_str().contains("gzip"));
+      assert!(accepts_gzip);
+
+      let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+      encoder.write_all(br#"{"mods": [{}]}"#).unwrap();
+      let encoding = Header::from_bytes("Content-Encoding", "gzip").unwrap();
+      let response = Response::from_data(encoder.finish().unwrap()).with_header(encoding);
+      request.respond(response).unwrap();
+    });
+
+    let json_path = dir.join("repo.json.zst");
+    fetch_one(&json_path, &url, false).unwrap();
+    assert!(fs::read(&json_path)
+      .unwrap()
+      .starts_with(&[0x28, 0xb5, 0x2f, 0xfd]));
+
+    // Found next to the plain name as well.
+    let mut mods = 0;
+    json::for_each_mod(&dir.join("repo.json"), |_| {
+      mods += 1;
+      Ok(())
     })
     .unwrap();
+    assert_eq!(mods, 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
 }
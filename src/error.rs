@@ -0,0 +1,311 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+  Io {
+    path: PathBuf,
+    source: io::Error,
+  },
+  FileNotFound(PathBuf),
+  NotADatabase(PathBuf),
+  SchemaTooNew {
+    found: u32,
+    supported: u32,
+  },
+  SchemaOutdated {
+    found: u32,
+    current: u32,
+  },
+  Sql(rusqlite::Error),
+  Json(serde_json::Error),
+  JsonShape(String),
+  MissingField {
+    mid: String,
+    field: String,
+  },
+  ModSchema {
+    mid: String,
+    field: String,
+    source: serde_json::Error,
+  },
+  InvalidVersionSpec(String),
+  InvalidFormat(String),
+  InvalidFilter {
+    name: String,
+    value: String,
+  },
+  NoMatchingVersion {
+    mid: String,
+    spec: String,
+  },
+  ModNotFound {
+    mid: String,
+    required_by: Option<String>,
+  },
+  PackageNotFound {
+    mid: String,
+    package: String,
+  },
+  DependencyConflict {
+    mid: String,
+    constraints: Vec<String>,
+  },
+  DependencyCycle(Vec<String>),
+  InvalidSelection(String),
+  Curl(curl::Error),
+  CurlMulti(curl::MultiError),
+  UnsupportedChecksum(String),
+  ChecksumMismatch {
+    path: PathBuf,
+    expected: String,
+    actual: String,
+  },
+  SizeMismatch {
+    path: PathBuf,
+    expected: u64,
+    actual: u64,
+  },
+  NoMirrors(String),
+  DownloadFailed(Vec<String>),
+  InvalidDownload {
+    url: String,
+    message: String,
+  },
+  UnsupportedArchive(PathBuf),
+  Archive {
+    path: PathBuf,
+    message: String,
+  },
+  UnsafePath(String),
+  MissingArchiveEntry {
+    archive: String,
+    name: String,
+  },
+  NotInstalled {
+    mid: String,
+    version: String,
+  },
+  VerifyFailed {
+    missing: usize,
+    modified: usize,
+  },
+  InvalidEnvVar(&'static str),
+  Config {
+    path: PathBuf,
+    message: String,
+  },
+  MissingSetting {
+    name: &'static str,
+    flag: &'static str,
+  },
+  StdinWithSources(usize),
+}
+
+impl Error {
+  /// Returns a closure for `map_err` that attaches `path` to an I/O error.
+  pub fn io(path: &Path) -> impl FnOnce(io::Error) -> Error + '_ {
+    move |source| Error::Io {
+      path: path.to_path_buf(),
+      source,
+    }
+  }
+
+  /// The exit status of the process when a command fails with this
+  /// error. 2 is shared with argument errors reported by the parser.
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      Error::InvalidVersionSpec(_)
+      | Error::InvalidFormat(_)
+      | Error::InvalidFilter { .. }
+      | Error::InvalidSelection(_)
+      | Error::InvalidEnvVar(_)
+      | Error::MissingSetting { .. }
+      | Error::StdinWithSources(_) => 2,
+      Error::FileNotFound(_)
+      | Error::NoMatchingVersion { .. }
+      | Error::ModNotFound { .. }
+      | Error::PackageNotFound { .. }
+      | Error::NotInstalled { .. } => 3,
+      Error::VerifyFailed { .. } => 4,
+      Error::Curl(_)
+      | Error::CurlMulti(_)
+      | Error::NoMirrors(_)
+      | Error::DownloadFailed(_)
+      | Error::InvalidDownload { .. }
+      | Error::ChecksumMismatch { .. }
+      | Error::SizeMismatch { .. } => 5,
+      _ => 1,
+    }
+  }
+
+  pub fn missing_field(mid: &str, field: &str) -> Error {
+    Error::MissingField {
+      mid: mid.to_string(),
+      field: field.to_string(),
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+      Error::FileNotFound(path) => write!(f, "File not found: {}", path.display()),
+      Error::NotADatabase(path) => write!(f, "Not a DB file: {}", path.display()),
+      Error::SchemaTooNew { found, supported } => write!(
+        f,
+        "DB schema version {} is newer than supported version {}; please upgrade neb",
+        found, supported
+      ),
+      Error::SchemaOutdated { found, current } => write!(
+        f,
+        "DB schema version {} is older than version {}; run 'neb update' to migrate it",
+        found, current
+      ),
+      Error::Sql(e) => write!(f, "DB error: {}", e),
+      Error::Json(e) => write!(f, "Error parsing JSON: {}", e),
+      Error::JsonShape(msg) => write!(f, "Unexpected JSON: {}", msg),
+      Error::MissingField { mid, field } => {
+        write!(f, "Missing field '{}' in mod '{}'", field, mid)
+      }
+      Error::ModSchema { mid, field, source } => {
+        write!(f, "Invalid field '{}' in mod '{}': {}", field, mid, source)
+      }
+      Error::InvalidVersionSpec(spec) => write!(f, "Invalid version spec: {}", spec),
+      Error::InvalidFormat(format) => write!(
+        f,
+        "Unknown output format '{}', expected text, json, jsonl, csv or tsv",
+        format
+      ),
+      Error::InvalidFilter { name, value } => {
+        write!(f, "Invalid value for filter '{}': {}", name, value)
+      }
+      Error::NoMatchingVersion { mid, spec } => {
+        write!(f, "No version of '{}' matches '{}'", mid, spec)
+      }
+      Error::ModNotFound { mid, required_by } => match required_by {
+        Some(by) => write!(f, "Mod not found: {} (required by {})", mid, by),
+        None => write!(f, "Mod not found: {}", mid),
+      },
+      Error::PackageNotFound { mid, package } => {
+        write!(f, "Package '{}' not found in mod '{}'", package, mid)
+      }
+      Error::DependencyConflict { mid, constraints } => write!(
+        f,
+        "No version of '{}' satisfies all of: {}",
+        mid,
+        constraints.join("; ")
+      ),
+      Error::DependencyCycle(mids) => write!(f, "Dependency cycle: {}", mids.join(" -> ")),
+      Error::InvalidSelection(text) => write!(
+        f,
+        "Invalid selection '{}', expected MID[@VERSION][:PACKAGE,...]",
+        text
+      ),
+      Error::Curl(e) => write!(f, "Download failed: {}", e),
+      Error::CurlMulti(e) => write!(f, "Download failed: {}", e),
+      Error::UnsupportedChecksum(algorithm) => {
+        write!(f, "Unsupported checksum algorithm: {}", algorithm)
+      }
+      Error::ChecksumMismatch {
+        path,
+        expected,
+        actual,
+      } => write!(
+        f,
+        "Checksum mismatch for {}: expected {}, got {}",
+        path.display(),
+        expected,
+        actual
+      ),
+      Error::SizeMismatch {
+        path,
+        expected,
+        actual,
+      } => write!(
+        f,
+        "Size mismatch for {}: expected {} bytes, got {}",
+        path.display(),
+        expected,
+        actual
+      ),
+      Error::NoMirrors(filename) => write!(f, "No download URLs for {}", filename),
+      Error::DownloadFailed(filenames) => {
+        write!(f, "Failed to download: {}", filenames.join(", "))
+      }
+      Error::InvalidDownload { url, message } => {
+        write!(f, "Invalid download from {}: {}", url, message)
+      }
+      Error::UnsupportedArchive(path) => {
+        write!(f, "Not a zip or 7z archive: {}", path.display())
+      }
+      Error::Archive { path, message } => write!(f, "{}: {}", path.display(), message),
+      Error::UnsafePath(name) => write!(f, "Refusing unsafe path: {}", name),
+      Error::MissingArchiveEntry { archive, name } => {
+        write!(f, "File '{}' not found in archive '{}'", name, archive)
+      }
+      Error::NotInstalled { mid, version } => {
+        write!(f, "Mod not installed: {} {}", mid, version)
+      }
+      Error::VerifyFailed { missing, modified } => write!(
+        f,
+        "Verification failed: {} missing, {} modified",
+        missing, modified
+      ),
+      Error::InvalidEnvVar(name) => write!(f, "Invalid {} env variable.", name),
+      Error::Config { path, message } => write!(f, "{}: {}", path.display(), message),
+      Error::MissingSetting { name, flag } => write!(
+        f,
+        "No {} given; pass {} or set '{}' in the config file",
+        name, flag, name
+      ),
+      Error::StdinWithSources(count) => write!(
+        f,
+        "Cannot read - for {} sources; update from their files or configure a single source",
+        count
+      ),
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Error::Io { source, .. } => Some(source),
+      Error::Sql(e) => Some(e),
+      Error::Json(e) => Some(e),
+      Error::ModSchema { source, .. } => Some(source),
+      Error::Curl(e) => Some(e),
+      Error::CurlMulti(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<rusqlite::Error> for Error {
+  fn from(e: rusqlite::Error) -> Error {
+    Error::Sql(e)
+  }
+}
+
+impl From<serde_json::Error> for Error {
+  fn from(e: serde_json::Error) -> Error {
+    Error::Json(e)
+  }
+}
+
+impl From<curl::Error> for Error {
+  fn from(e: curl::Error) -> Error {
+    Error::Curl(e)
+  }
+}
+
+impl From<curl::MultiError> for Error {
+  fn from(e: curl::MultiError) -> Error {
+    Error::CurlMulti(e)
+  }
+}
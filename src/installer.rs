@@ -0,0 +1,399 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::{Error, Result};
+use crate::knossos::{KnossosMod, ModFile, Package};
+use crate::{archive, downloader};
+use crate::{detail, status};
+
+/// Lists what `install` put into a mod folder so `uninstall` can take
+/// exactly that out again.
+pub const RECORD_FILE: &str = "neb-installed.json";
+
+const MOD_JSON_FILE: &str = "mod.json";
+const STAGING_DIR: &str = ".neb-extract";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct InstallRecord {
+  pub mid: String,
+  pub version: String,
+  pub packages: BTreeSet<String>,
+  /// Paths relative to the mod folder, with `/` separators.
+  pub files: BTreeSet<String>,
+}
+
+pub fn mod_dir(root: &Path, mid: &str, version: &str) -> PathBuf {
+  root.join(format!("{}-{}", mid, version))
+}
+
+/// Installs `packages` of `km` from the archives in `archive_dir` into
+/// `FS2DIR/<mid>-<version>`. Files named in a package filelist are placed
+/// at their path in the mod folder; an archive without filelist entries
+/// is extracted whole into the package folder.
+pub fn install(
+  km: &KnossosMod,
+  mod_json: &Value,
+  packages: &[&Package],
+  archive_dir: &Path,
+  root: &Path,
+) -> Result<InstallRecord> {
+  let dir = mod_dir(root, &km.id, &km.version);
+  fs::create_dir_all(&dir).map_err(Error::io(&dir))?;
+
+  let mut record = read_record(&dir)?.unwrap_or_else(|| InstallRecord {
+    mid: km.id.clone(),
+    version: km.version.clone(),
+    ..InstallRecord::default()
+  });
+
+  let staging = dir.join(STAGING_DIR);
+  for package in packages {
+    for file in &package.files {
+      status!("==> Extracting {}", file.filename);
+      remove_dir_if_exists(&staging)?;
+      let archive_path = downloader::archive_path(archive_dir, &file.filename);
+      let extracted = archive::extract(&archive_path, &staging)?;
+
+      // Record what was placed even after an error, so that uninstall can
+      // remove it.
+      let placed = place_files(package, file, &extracted, &staging, &dir, &mut record.files);
+      write_record(&dir, &record)?;
+      placed?;
+    }
+    record.packages.insert(package.name.clone());
+  }
+  remove_dir_if_exists(&staging)?;
+
+  write_mod_json(&dir, mod_json, &record.packages)?;
+  write_record(&dir, &record)?;
+  Ok(record)
+}
+
+/// Removes the files recorded by `install`, the local mod.json and any
+/// directories left empty.
+pub fn uninstall(root: &Path, mid: &str, version: &str) -> Result<InstallRecord> {
+  let dir = mod_dir(root, mid, version);
+  let record = read_record(&dir)?.ok_or_else(|| Error::NotInstalled {
+    mid: mid.to_string(),
+    version: version.to_string(),
+  })?;
+
+  let mut parents = BTreeSet::new();
+  for file in &record.files {
+    let relative = archive::safe_relative_path(file)?;
+    remove_file_if_exists(&dir.join(&relative))?;
+    parents.extend(relative.ancestors().skip(1).map(Path::to_path_buf));
+  }
+  remove_file_if_exists(&dir.join(MOD_JSON_FILE))?;
+  remove_file_if_exists(&dir.join(RECORD_FILE))?;
+
+  // Deepest first so that parents are empty by the time they come up.
+  let mut parents: Vec<PathBuf> = parents.into_iter().collect();
+  parents.sort_by_key(|p| std::cmp::Reverse(p.components().count()));
+  for parent in parents {
+    // Fails, as it should, when the directory holds files neb did not put there.
+    let _ = fs::remove_dir(dir.join(parent));
+  }
+
+  Ok(record)
+}
+
+pub fn read_record(dir: &Path) -> Result<Option<InstallRecord>> {
+  let path = dir.join(RECORD_FILE);
+  match fs::read(&path) {
+    Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+    Err(e) => Err(Error::io(&path)(e)),
+  }
+}
+
+/// Moves the files extracted from `file` into the mod folder and adds
+/// their paths relative to it to `placed`.
+fn place_files(
+  package: &Package,
+  file: &ModFile,
+  extracted: &[PathBuf],
+  staging: &Path,
+  dir: &Path,
+  placed: &mut BTreeSet<String>,
+) -> Result<()> {
+  let mut place = |source: &Path, target: &Path| -> Result<()> {
+    move_file(source, &dir.join(target))?;
+    detail!("    {}", slash_path(target));
+    placed.insert(slash_path(target));
+    Ok(())
+  };
+  let entries: Vec<_> = package
+    .filelist
+    .iter()
+    .filter(|entry| entry.archive == file.filename)
+    .collect();
+
+  if entries.is_empty() {
+    let mut base = PathBuf::new();
+    for part in [package.folder.as_deref(), file.dest.as_deref()] {
+      match part {
+        Some(part) if !part.is_empty() => base.push(archive::safe_relative_path(part)?),
+        _ => {}
+      }
+    }
+
+    for relative in extracted {
+      place(&staging.join(relative), &base.join(relative))?;
+    }
+  } else {
+    for entry in entries {
+      let name = entry.orig_name.as_deref().unwrap_or(&entry.filename);
+      let source = staging.join(archive::safe_relative_path(name)?);
+      if !source.is_file() {
+        return Err(Error::MissingArchiveEntry {
+          archive: file.filename.clone(),
+          name: name.to_string(),
+        });
+      }
+
+      place(&source, &archive::safe_relative_path(&entry.filename)?)?;
+    }
+  }
+
+  Ok(())
+}
+
+fn move_file(source: &Path, target: &Path) -> Result<()> {
+  if let Some(parent) = target.parent() {
+    fs::create_dir_all(parent).map_err(Error::io(parent))?;
+  }
+  fs::rename(source, target).map_err(Error::io(target))
+}
+
+/// Writes the mod's repo entry with only the installed packages, the way
+/// Knossos keeps a local mod.json in each mod folder.
+fn write_mod_json(dir: &Path, mod_json: &Value, installed: &BTreeSet<String>) -> Result<()> {
+  let mut local = mod_json.clone();
+  if let Some(packages) = local["packages"].as_array_mut() {
+    packages.retain(|p| {
+      p["name"]
+        .as_str()
+        .is_some_and(|name| installed.contains(name))
+    });
+  }
+
+  write_atomically(
+    &dir.join(MOD_JSON_FILE),
+    &serde_json::to_string_pretty(&local)?,
+  )
+}
+
+fn write_record(dir: &Path, record: &InstallRecord) -> Result<()> {
+  write_atomically(
+    &dir.join(RECORD_FILE),
+    &serde_json::to_string_pretty(record)?,
+  )
+}
+
+/// Writes `contents` to a `.part` file first and renames it over `path`,
+/// so that an interruption leaves either the old or the new file.
+fn write_atomically(path: &Path, contents: &str) -> Result<()> {
+  let mut part_name = path.file_name().unwrap_or_default().to_os_string();
+  part_name.push(".part");
+  let part_path = path.with_file_name(part_name);
+
+  let mut file = File::create(&part_path).map_err(Error::io(&part_path))?;
+  file
+    .write_all(contents.as_bytes())
+    .and_then(|_| file.sync_all())
+    .map_err(Error::io(&part_path))?;
+  fs::rename(&part_path, path).map_err(Error::io(path))
+}
+
+fn slash_path(path: &Path) -> String {
+  path
+    .components()
+    .map(|c| c.as_os_str().to_string_lossy())
+    .collect::<Vec<_>>()
+    .join("/")
+}
+
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+  match fs::remove_file(path) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::io(path)(e)),
+    _ => Ok(()),
+  }
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<()> {
+  match fs::remove_dir_all(path) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::io(path)(e)),
+    _ => Ok(()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::archive::tests::write_zip;
+  use serde_json::json;
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("neb-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn file(filename: &str, dest: Option<&str>) -> Value {
+    json!({
+      "filename": filename, "dest": dest, "checksum": ["sha256", "00"],
+      "filesize": 0, "urls": []
+    })
+  }
+
+  fn entry(filename: &str, archive: &str, orig_name: Option<&str>) -> Value {
+    json!({
+      "filename": filename, "archive": archive, "orig_name": orig_name,
+      "checksum": ["sha256", "00"]
+    })
+  }
+
+  /// Core places two files of core.zip by its filelist, HD extracts all of
+  /// hd/hd.zip into its folder and dest, and Extra is not installed.
+  fn mod_json() -> Value {
+    json!({
+      "id": "MVPS", "title": "MediaVPs", "version": "4.6.0", "type": "mod",
+      "packages": [
+        {
+          "name": "Core", "status": "required",
+          "files": [file("core.zip", None)],
+          "filelist": [
+            entry("data/tables/ships.tbl", "core.zip", Some("ships.tbl")),
+            entry("readme.txt", "core.zip", None)
+          ]
+        },
+        {
+          "name": "HD", "status": "recommended", "folder": "hd",
+          "files": [file("hd/hd.zip", Some("effects"))], "filelist": []
+        },
+        {"name": "Extra", "status": "optional", "files": [], "filelist": []}
+      ]
+    })
+  }
+
+  fn install_packages(root: &Path, cache: &Path, names: &[&str]) -> Result<InstallRecord> {
+    let mod_json = mod_json();
+    let km = KnossosMod::from_value(&mod_json).unwrap();
+    let packages: Vec<&Package> = names.iter().filter_map(|n| km.package(n)).collect();
+    install(&km, &mod_json, &packages, cache, root)
+  }
+
+  #[test]
+  fn install_places_files_and_uninstall_removes_them() {
+    let dir = temp_dir("install");
+    let (root, cache) = (dir.join("fs2"), dir.join("cache"));
+    fs::create_dir_all(&cache).unwrap();
+    write_zip(
+      &cache.join("core.zip"),
+      &[
+        ("ships.tbl", "ships"),
+        ("readme.txt", "hi"),
+        ("unlisted.txt", ""),
+      ],
+    );
+    write_zip(
+      &downloader::archive_path(&cache, "hd/hd.zip"),
+      &[("fx/", ""), ("fx/fire.vp", "fire")],
+    );
+
+    let record = install_packages(&root, &cache, &["Core", "HD"]).unwrap();
+    let mod_dir = mod_dir(&root, "MVPS", "4.6.0");
+    assert_eq!(
+      record.files,
+      BTreeSet::from([
+        "data/tables/ships.tbl".to_string(),
+        "hd/effects/fx/fire.vp".to_string(),
+        "readme.txt".to_string(),
+      ])
+    );
+    assert_eq!(
+      fs::read_to_string(mod_dir.join("data/tables/ships.tbl")).unwrap(),
+      "ships"
+    );
+    assert!(mod_dir.join("hd/effects/fx/fire.vp").is_file());
+    assert!(!mod_dir.join("unlisted.txt").exists());
+    assert!(!mod_dir.join(STAGING_DIR).exists());
+
+    let stored = read_record(&mod_dir).unwrap().unwrap();
+    assert_eq!(stored.files, record.files);
+    assert_eq!(
+      stored.packages,
+      BTreeSet::from(["Core".into(), "HD".into()])
+    );
+    let local: Value =
+      serde_json::from_str(&fs::read_to_string(mod_dir.join(MOD_JSON_FILE)).unwrap()).unwrap();
+    let names: Vec<&str> = local["packages"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|p| p["name"].as_str().unwrap())
+      .collect();
+    assert_eq!(names, ["Core", "HD"]);
+
+    let mut leftovers: Vec<String> = fs::read_dir(&mod_dir)
+      .unwrap()
+      .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+      .collect();
+    leftovers.sort();
+    assert_eq!(
+      leftovers,
+      ["data", "hd", "mod.json", "neb-installed.json", "readme.txt"]
+    );
+
+    // A file neb did not put there keeps its folder.
+    fs::write(mod_dir.join("data/notes.txt"), "mine").unwrap();
+    uninstall(&root, "MVPS", "4.6.0").unwrap();
+    assert!(mod_dir.join("data/notes.txt").is_file());
+    assert!(!mod_dir.join("data/tables").exists());
+    assert!(!mod_dir.join("hd").exists());
+    assert!(!mod_dir.join(MOD_JSON_FILE).exists());
+    assert!(!mod_dir.join(RECORD_FILE).exists());
+    assert!(matches!(
+      uninstall(&root, "MVPS", "4.6.0"),
+      Err(Error::NotInstalled { .. })
+    ));
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn failed_install_records_placed_files() {
+    let dir = temp_dir("install-missing");
+    let (root, cache) = (dir.join("fs2"), dir.join("cache"));
+    fs::create_dir_all(&cache).unwrap();
+    // readme.txt is placed before ships.tbl turns out to be missing.
+    write_zip(&cache.join("core.zip"), &[("readme.txt", "hi")]);
+
+    let mut mod_json = mod_json();
+    mod_json["packages"][0]["filelist"] = json!([
+      entry("readme.txt", "core.zip", None),
+      entry("data/tables/ships.tbl", "core.zip", Some("ships.tbl"))
+    ]);
+    let km = KnossosMod::from_value(&mod_json).unwrap();
+    let core = km.package("Core").unwrap();
+    assert!(matches!(
+      install(&km, &mod_json, &[core], &cache, &root),
+      Err(Error::MissingArchiveEntry { .. })
+    ));
+
+    let mod_dir = mod_dir(&root, "MVPS", "4.6.0");
+    let record = read_record(&mod_dir).unwrap().unwrap();
+    assert_eq!(record.files, BTreeSet::from(["readme.txt".to_string()]));
+    uninstall(&root, "MVPS", "4.6.0").unwrap();
+    assert!(!mod_dir.join("readme.txt").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -1,19 +1,298 @@
-use std::fs;
-use std::path::Path;
-use std::process;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
 
+use flate2::bufread::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
 use serde_json::Value;
 
-pub fn read_file(json_path: &Path) -> Value {
-  println!("==> Reading Knossos repo file...");
+use crate::error::{Error, Result};
+use crate::status;
 
-  let json_file = fs::read(&json_path).unwrap_or_else(|_| {
-    println!("File read error: {}", &json_path.display());
-    process::exit(1);
-  });
+/// How a repo json file is stored on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+  None,
+  Gzip,
+  Zstd,
+}
+
+impl Compression {
+  /// Chooses by file extension: `.gz` or `.zst`, otherwise none.
+  pub fn from_path(path: &Path) -> Compression {
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some("gz") => Compression::Gzip,
+      Some("zst") => Compression::Zstd,
+      _ => Compression::None,
+    }
+  }
+
+  /// Chooses by the magic number at the start of the data.
+  fn sniff(data: &[u8]) -> Compression {
+    if data.starts_with(&[0x1f, 0x8b]) {
+      Compression::Gzip
+    } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+      Compression::Zstd
+    } else {
+      Compression::None
+    }
+  }
+}
+
+/// Calls `f` with each mod in the `mods` array of the repo json file at
+/// `json_path`, or stdin for `-`, parsing one mod at a time so that the
+/// memory used does not grow with the file. The file may be gzip or zstd
+/// compressed, and when `json_path` does not exist, `<json_path>.zst` or
+/// `.gz` is read instead.
+pub fn for_each_mod<F>(json_path: &Path, mut f: F) -> Result<()>
+where
+  F: FnMut(Value) -> Result<()>,
+{
+  status!("==> Reading Knossos repo file...");
+
+  let path = find_file(json_path);
+  let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(open(&path)?));
+  let mut stopped = None;
+  let seed = RepoSeed {
+    f: &mut f,
+    stopped: &mut stopped,
+  };
+
+  let found = seed.deserialize(&mut deserializer);
+  if let Some(e) = stopped {
+    return Err(e);
+  }
+  if !found? {
+    return Err(Error::JsonShape(
+      "expected a top-level 'mods' array".to_string(),
+    ));
+  }
+  deserializer.end()?;
+  Ok(())
+}
+
+/// Opens `path`, or stdin for `-`, for reading, decompressing it if
+/// needed.
+pub fn open(path: &Path) -> Result<Box<dyn Read>> {
+  let reader: Box<dyn Read> = if path == Path::new("-") {
+    Box::new(io::stdin())
+  } else {
+    Box::new(File::open(path).map_err(Error::io(path))?)
+  };
+  let mut reader = BufReader::new(reader);
+  let start = reader.fill_buf().map_err(Error::io(path))?;
+
+  let reader: Box<dyn Read> = match Compression::sniff(start) {
+    Compression::None => Box::new(reader),
+    Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+    Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader).map_err(Error::io(path))?),
+  };
+  Ok(reader)
+}
+
+/// Visits the top-level object of a repo json, skipping everything but
+/// `mods`. Yields whether there was a `mods` array.
+struct RepoSeed<'a, F> {
+  f: &'a mut F,
+  /// The error of `f` that stopped the parse, if any.
+  stopped: &'a mut Option<Error>,
+}
+
+impl<'de, F> DeserializeSeed<'de> for RepoSeed<'_, F>
+where
+  F: FnMut(Value) -> Result<()>,
+{
+  type Value = bool;
+
+  fn deserialize<D>(self, deserializer: D) -> std::result::Result<bool, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    deserializer.deserialize_map(self)
+  }
+}
+
+impl<'de, F> Visitor<'de> for RepoSeed<'_, F>
+where
+  F: FnMut(Value) -> Result<()>,
+{
+  type Value = bool;
+
+  fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    formatter.write_str("a repo json object")
+  }
+
+  fn visit_map<A>(self, mut map: A) -> std::result::Result<bool, A::Error>
+  where
+    A: MapAccess<'de>,
+  {
+    let mut found = false;
+    while let Some(key) = map.next_key::<String>()? {
+      if key == "mods" {
+        map.next_value_seed(ModsSeed {
+          f: &mut *self.f,
+          stopped: &mut *self.stopped,
+        })?;
+        found = true;
+      } else {
+        map.next_value::<IgnoredAny>()?;
+      }
+    }
+    Ok(found)
+  }
+}
+
+/// Visits the `mods` array, handing each mod to `f` as soon as it is
+/// parsed.
+struct ModsSeed<'a, F> {
+  f: &'a mut F,
+  stopped: &'a mut Option<Error>,
+}
+
+impl<'de, F> DeserializeSeed<'de> for ModsSeed<'_, F>
+where
+  F: FnMut(Value) -> Result<()>,
+{
+  type Value = ();
+
+  fn deserialize<D>(self, deserializer: D) -> std::result::Result<(), D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    deserializer.deserialize_seq(self)
+  }
+}
+
+impl<'de, F> Visitor<'de> for ModsSeed<'_, F>
+where
+  F: FnMut(Value) -> Result<()>,
+{
+  type Value = ();
+
+  fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    formatter.write_str("an array of mods")
+  }
+
+  fn visit_seq<A>(self, mut seq: A) -> std::result::Result<(), A::Error>
+  where
+    A: SeqAccess<'de>,
+  {
+    while let Some(mod_json) = seq.next_element::<Value>()? {
+      if let Err(e) = (self.f)(mod_json) {
+        *self.stopped = Some(e);
+        return Err(de::Error::custom("stopped"));
+      }
+    }
+    Ok(())
+  }
+}
+
+fn find_file(json_path: &Path) -> PathBuf {
+  if json_path != Path::new("-") && !json_path.exists() {
+    for suffix in [".zst", ".gz"] {
+      let mut path = json_path.as_os_str().to_os_string();
+      path.push(suffix);
+      let path = PathBuf::from(path);
+      if path.exists() {
+        return path;
+      }
+    }
+  }
+  json_path.to_path_buf()
+}
+
+/// Writes a repo json file with the given compression. `finish` must be
+/// called to complete the file.
+pub enum Writer {
+  Plain(File),
+  Gzip(GzEncoder<File>),
+  Zstd(zstd::Encoder<'static, File>),
+}
+
+impl Writer {
+  pub fn new(file: File, compression: Compression) -> io::Result<Writer> {
+    Ok(match compression {
+      Compression::None => Writer::Plain(file),
+      Compression::Gzip => Writer::Gzip(GzEncoder::new(file, flate2::Compression::default())),
+      Compression::Zstd => Writer::Zstd(zstd::Encoder::new(file, 0)?),
+    })
+  }
+
+  pub fn finish(self) -> io::Result<File> {
+    match self {
+      Writer::Plain(mut file) => {
+        file.flush()?;
+        Ok(file)
+      }
+      Writer::Gzip(encoder) => encoder.finish(),
+      Writer::Zstd(encoder) => encoder.finish(),
+    }
+  }
+}
+
+impl Write for Writer {
+  fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+    match self {
+      Writer::Plain(file) => file.write(data),
+      Writer::Gzip(encoder) => encoder.write(data),
+      Writer::Zstd(encoder) => encoder.write(data),
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match self {
+      Writer::Plain(file) => file.flush(),
+      Writer::Gzip(encoder) => encoder.flush(),
+      Writer::Zstd(encoder) => encoder.flush(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  fn mids(text: &str) -> Result<Vec<String>> {
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+    let n = FILES.fetch_add(1, Ordering::Relaxed);
+    let path = std::env::temp_dir().join(format!("neb-json-{}-{}.json", std::process::id(), n));
+    fs::write(&path, text).unwrap();
+    let mut mids = Vec::new();
+    let result = for_each_mod(&path, |mod_json| {
+      if mod_json["id"] == "BAD" {
+        return Err(Error::missing_field("BAD", "title"));
+      }
+      mids.push(mod_json["id"].as_str().unwrap_or("").to_string());
+      Ok(())
+    });
+    fs::remove_file(&path).unwrap();
+    result.map(|()| mids)
+  }
+
+  #[test]
+  fn for_each_mod_skips_other_keys() {
+    let text = r#"{"version": 1, "mods": [{"id": "A"}, {"id": "B"}], "extra": {"mods": 0}}"#;
+    assert_eq!(mids(text).unwrap(), ["A", "B"]);
+  }
+
+  #[test]
+  fn for_each_mod_stops_at_first_error() {
+    let text = r#"{"mods": [{"id": "A"}, {"id": "BAD"}, {"id": "C"}]}"#;
+    assert!(matches!(mids(text), Err(Error::MissingField { .. })));
+  }
 
-  serde_json::from_slice(&json_file).unwrap_or_else(|_| {
-    println!("Error parsing JSON");
-    process::exit(1);
-  })
+  #[test]
+  fn for_each_mod_rejects_bad_shapes() {
+    assert!(matches!(mids(r#"{"mod": []}"#), Err(Error::JsonShape(_))));
+    assert!(matches!(mids(r#"{"mods": {}}"#), Err(Error::Json(_))));
+    assert!(matches!(
+      mids(r#"{"mods": [{"id": "A"}"#),
+      Err(Error::Json(_))
+    ));
+    assert!(matches!(mids(r#"{"mods": []} []"#), Err(Error::Json(_))));
+  }
 }
@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::{Error, Result};
+
+/// A single release of a mod as it appears in the Knossos repo.json `mods`
+/// array (and as `mod.json` inside an installed mod folder).
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct KnossosMod {
+  pub id: String,
+  pub title: String,
+  pub version: String,
+  #[serde(rename = "type", default)]
+  pub mod_type: String,
+  #[serde(default)]
+  pub stability: Option<String>,
+  #[serde(default)]
+  pub parent: Option<String>,
+  #[serde(default)]
+  pub description: Option<String>,
+  #[serde(default)]
+  pub notes: Option<String>,
+  #[serde(default)]
+  pub logo: Option<String>,
+  #[serde(default)]
+  pub tile: Option<String>,
+  #[serde(default)]
+  pub banner: Option<String>,
+  #[serde(default)]
+  pub release_thread: Option<String>,
+  #[serde(default)]
+  pub videos: Vec<String>,
+  #[serde(default)]
+  pub screenshots: Vec<String>,
+  #[serde(default)]
+  pub attachments: Vec<String>,
+  #[serde(default)]
+  pub first_release: Option<String>,
+  #[serde(default)]
+  pub last_update: Option<String>,
+  #[serde(default)]
+  pub cmdline: Option<String>,
+  #[serde(default)]
+  pub mod_flag: Vec<String>,
+  #[serde(default)]
+  pub packages: Vec<Package>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Package {
+  pub name: String,
+  #[serde(default)]
+  pub notes: Option<String>,
+  #[serde(default)]
+  pub status: Option<String>,
+  #[serde(default)]
+  pub environment: Option<String>,
+  #[serde(default)]
+  pub folder: Option<String>,
+  #[serde(default)]
+  pub is_vp: Option<bool>,
+  #[serde(default)]
+  pub dependencies: Vec<Dependency>,
+  #[serde(default)]
+  pub files: Vec<ModFile>,
+  #[serde(default)]
+  pub filelist: Vec<FileListEntry>,
+  #[serde(default)]
+  pub executables: Vec<Executable>,
+}
+
+/// An archive to download for a package.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModFile {
+  pub filename: String,
+  #[serde(default)]
+  pub dest: Option<String>,
+  pub checksum: Checksum,
+  pub filesize: u64,
+  #[serde(default)]
+  pub urls: Vec<String>,
+}
+
+/// A file extracted from one of the package archives.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileListEntry {
+  pub filename: String,
+  pub archive: String,
+  #[serde(default)]
+  pub orig_name: Option<String>,
+  pub checksum: Checksum,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Dependency {
+  pub id: String,
+  #[serde(default)]
+  pub version: Option<String>,
+  #[serde(default)]
+  pub packages: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Executable {
+  pub file: String,
+  #[serde(default)]
+  pub label: Option<String>,
+  #[serde(default)]
+  pub properties: Option<Value>,
+}
+
+/// `["sha256", "<hex digest>"]`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checksum(pub String, pub String);
+
+impl Checksum {
+  pub fn algorithm(&self) -> &str {
+    &self.0
+  }
+
+  pub fn digest(&self) -> &str {
+    &self.1
+  }
+}
+
+impl KnossosMod {
+  /// Builds a mod from one entry of the repo.json `mods` array.
+  pub fn from_value(value: &Value) -> Result<KnossosMod> {
+    let mid = value["id"].as_str().unwrap_or("<unknown>");
+    serde_path_to_error::deserialize(value).map_err(|e| schema_error(mid, e))
+  }
+
+  /// Parses the `mod_json` text stored for `mid`.
+  pub fn from_json_str(mid: &str, json: &str) -> Result<KnossosMod> {
+    let de = &mut serde_json::Deserializer::from_str(json);
+    serde_path_to_error::deserialize(de).map_err(|e| schema_error(mid, e))
+  }
+
+  pub fn is_total_conversion(&self) -> bool {
+    self.mod_type == "tc"
+  }
+
+  pub fn is_engine(&self) -> bool {
+    self.mod_type == "engine"
+  }
+
+  pub fn files(&self) -> impl Iterator<Item = &ModFile> {
+    self.packages.iter().flat_map(|p| p.files.iter())
+  }
+
+  pub fn package(&self, name: &str) -> Option<&Package> {
+    self.packages.iter().find(|p| p.name == name)
+  }
+
+  /// Packages installed unless the user picks otherwise.
+  pub fn default_packages(&self) -> impl Iterator<Item = &Package> {
+    self
+      .packages
+      .iter()
+      .filter(|p| matches!(p.status.as_deref(), Some("required" | "recommended")))
+  }
+}
+
+fn schema_error(mid: &str, e: serde_path_to_error::Error<serde_json::Error>) -> Error {
+  let field = e.path().to_string();
+  Error::ModSchema {
+    mid: mid.to_string(),
+    field,
+    source: e.into_inner(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn schema_error(value: &Value) -> (String, String, String) {
+    match KnossosMod::from_value(value) {
+      Err(Error::ModSchema { mid, field, source }) => (mid, field, source.to_string()),
+      other => panic!("expected a schema error, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn parses_minimal_release_with_defaults() {
+    let km =
+      KnossosMod::from_value(&json!({"id": "MVPS", "title": "MediaVPs", "version": "4.6.0"}))
+        .unwrap();
+
+    assert_eq!(km.mod_type, "");
+    assert!(!km.is_engine() && !km.is_total_conversion());
+    assert_eq!(km.stability, None);
+    assert!(km.packages.is_empty() && km.mod_flag.is_empty());
+
+    let km = KnossosMod::from_json_str(
+      "FSO",
+      r#"{"id": "FSO", "title": "FSO", "version": "23.0.0", "type": "engine"}"#,
+    )
+    .unwrap();
+    assert!(km.is_engine());
+  }
+
+  #[test]
+  fn missing_title_or_version_is_a_schema_error() {
+    let (mid, _, message) = schema_error(&json!({"id": "MVPS", "version": "4.6.0"}));
+    assert_eq!(mid, "MVPS");
+    assert!(message.contains("`title`"), "{}", message);
+
+    let (_, _, message) = schema_error(&json!({"id": "MVPS", "title": "MediaVPs"}));
+    assert!(message.contains("`version`"), "{}", message);
+
+    let (mid, _, _) = schema_error(&json!({"title": "MediaVPs", "version": "4.6.0"}));
+    assert_eq!(mid, "<unknown>");
+  }
+
+  #[test]
+  fn wrong_field_type_names_the_field() {
+    let (mid, field, _) = schema_error(&json!({
+      "id": "MVPS", "title": "MediaVPs", "version": "4.6.0",
+      "packages": [{
+        "name": "Core",
+        "files": [{"filename": "core.7z", "checksum": ["sha256", "00"], "filesize": "big"}]
+      }]
+    }));
+    assert_eq!(mid, "MVPS");
+    assert_eq!(field, "packages[0].files[0].filesize");
+
+    let (_, field, _) = schema_error(&json!({
+      "id": "MVPS", "title": "MediaVPs", "version": "4.6.0", "mod_flag": "MVPS"
+    }));
+    assert_eq!(field, "mod_flag");
+
+    match KnossosMod::from_json_str("MVPS", r#"{"id": "MVPS", "title": 1"#) {
+      Err(Error::ModSchema { mid, .. }) => assert_eq!(mid, "MVPS"),
+      other => panic!("expected a schema error, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn default_packages_are_required_and_recommended() {
+    let km = KnossosMod::from_value(&json!({
+      "id": "MVPS", "title": "MediaVPs", "version": "4.6.0",
+      "packages": [
+        {"name": "Core", "status": "required"},
+        {"name": "HD", "status": "recommended"},
+        {"name": "Extra", "status": "optional"},
+        {"name": "Old"}
+      ]
+    }))
+    .unwrap();
+
+    let names: Vec<&str> = km.default_packages().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, ["Core", "HD"]);
+    assert_eq!(
+      km.package("Extra").unwrap().status.as_deref(),
+      Some("optional")
+    );
+    assert!(km.package("Missing").is_none());
+  }
+}
@@ -1,5 +1,20 @@
+pub mod archive;
+pub mod checksum;
 pub mod command;
+pub mod config;
+pub mod download_engine;
 pub mod downloader;
+pub mod error;
+pub mod installer;
 pub mod json;
+pub mod knossos;
+pub mod library;
+pub mod migrations;
+pub mod output;
 pub mod repo;
+pub mod resolver;
+pub mod verifier;
+pub mod version;
 pub mod web;
+
+pub use error::{Error, Result};
@@ -0,0 +1,604 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use rusqlite::Connection;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::{Error, Result};
+use crate::knossos::KnossosMod;
+use crate::status;
+use crate::version::{self, VersionSpec};
+use crate::{output, repo};
+
+/// How deep below FS2DIR to look for mod.json files. Covers
+/// `<mid>-<version>`, `<mid>/<version>` and Knossos' `<parent>/<mid>-<version>`.
+const MAX_DEPTH: usize = 3;
+
+/// A mod release found on disk in a FreeSpace 2 directory.
+#[derive(Debug, Clone)]
+pub struct InstalledMod {
+  pub mid: String,
+  pub version: String,
+  pub dir: PathBuf,
+  pub packages: Vec<String>,
+}
+
+/// Finds installed mods by their mod.json files. Unreadable ones are
+/// reported and skipped.
+pub fn scan(root: &Path) -> Result<Vec<InstalledMod>> {
+  if !root.is_dir() {
+    return Err(Error::FileNotFound(root.to_path_buf()));
+  }
+
+  let mut mods = Vec::new();
+  scan_dir(root, 0, &mut mods)?;
+  mods.sort_by(|a, b| {
+    a.mid
+      .cmp(&b.mid)
+      .then_with(|| version::compare(&b.version, &a.version))
+  });
+  Ok(mods)
+}
+
+/// Keeps descending below a mod folder, since Knossos installs the mods
+/// of a TC inside the TC's own folder.
+fn scan_dir(dir: &Path, depth: usize, mods: &mut Vec<InstalledMod>) -> Result<()> {
+  let mod_json = dir.join("mod.json");
+  if depth > 0 && mod_json.is_file() {
+    match read_installed_mod(&mod_json) {
+      Ok(m) => mods.push(m),
+      Err(e) => eprintln!("Skipping {}: {}", mod_json.display(), e),
+    }
+  }
+
+  if depth == MAX_DEPTH {
+    return Ok(());
+  }
+
+  for entry in fs::read_dir(dir).map_err(Error::io(dir))? {
+    let entry = entry.map_err(Error::io(dir))?;
+    let path = entry.path();
+    // Skips neb's own cache and other hidden folders.
+    if path.is_dir() && !entry.file_name().to_string_lossy().starts_with('.') {
+      scan_dir(&path, depth + 1, mods)?;
+    }
+  }
+
+  Ok(())
+}
+
+fn read_installed_mod(path: &Path) -> Result<InstalledMod> {
+  let bytes = fs::read(path).map_err(Error::io(path))?;
+  let json: Value = serde_json::from_slice(&bytes)?;
+  let field = |name: &str| {
+    json[name]
+      .as_str()
+      .map(String::from)
+      .ok_or_else(|| Error::JsonShape(format!("missing '{}'", name)))
+  };
+
+  Ok(InstalledMod {
+    mid: field("id")?,
+    version: field("version")?,
+    dir: path.parent().unwrap_or(path).to_path_buf(),
+    packages: json["packages"]
+      .as_array()
+      .map(|packages| {
+        packages
+          .iter()
+          .filter_map(|p| p["name"].as_str().map(String::from))
+          .collect()
+      })
+      .unwrap_or_default(),
+  })
+}
+
+/// Prints installed mods whose newest installed version is older than the
+/// newest release in the repo, with the download size of the upgrade.
+pub fn outdated(conn: &Connection, root: &Path) -> Result<()> {
+  let installed = scan(root)?;
+  let (rows, unknown) = find_outdated(conn, &installed)?;
+
+  output::print_records(&rows, || {
+    if !rows.is_empty() {
+      println!(
+        "{:<30} {:<20} {:<20} {:>15}",
+        "MID", "INSTALLED", "LATEST", "DOWNLOAD"
+      );
+      for r in &rows {
+        println!(
+          "{:<30} {:<20} {:<20} {:>15}",
+          r.mid, r.installed, r.latest, r.download_size
+        );
+      }
+      println!();
+    }
+    Ok(())
+  })?;
+  for mid in &unknown {
+    eprintln!("Not in repo: {}", mid);
+  }
+
+  let mids: BTreeSet<&str> = installed.iter().map(|m| m.mid.as_str()).collect();
+  status!(
+    "==> {} of {} installed mods are outdated",
+    rows.len(),
+    mids.len()
+  );
+  Ok(())
+}
+
+/// The outdated mods among `installed`, and the mids the repo lacks.
+fn find_outdated(
+  conn: &Connection,
+  installed: &[InstalledMod],
+) -> Result<(Vec<OutdatedRecord>, Vec<String>)> {
+  // `scan` sorts newest first, so the first entry per mid is the one
+  // that counts.
+  let mut newest: BTreeMap<&str, &InstalledMod> = BTreeMap::new();
+  for m in installed {
+    newest.entry(&m.mid).or_insert(m);
+  }
+
+  let mut rows = Vec::new();
+  let mut unknown = Vec::new();
+  for (mid, m) in &newest {
+    let mid = mid.to_string();
+    let latest = match repo::get_sorted_versions(conn, &mid)?.into_iter().next() {
+      Some(latest) => latest,
+      None => {
+        unknown.push(mid);
+        continue;
+      }
+    };
+
+    if version::compare(&m.version, &latest) == Ordering::Less {
+      let size = upgrade_size(conn, &mid, &latest, &m.packages)?;
+      rows.push(OutdatedRecord {
+        mid,
+        installed: m.version.clone(),
+        latest,
+        download_size: size,
+      });
+    }
+  }
+
+  Ok((rows, unknown))
+}
+
+/// Bytes to download for `version` of the packages that are installed
+/// now, or of the default packages when none of them exist any more.
+fn upgrade_size(
+  conn: &Connection,
+  mid: &String,
+  version: &str,
+  installed: &[String],
+) -> Result<u64> {
+  let m = match repo::get_mod(conn, mid, &Some(version.to_string()))? {
+    Some(m) => m,
+    None => return Ok(0),
+  };
+  let km = m.knossos_mod()?;
+
+  let mut packages: Vec<_> = km
+    .packages
+    .iter()
+    .filter(|p| installed.contains(&p.name))
+    .collect();
+  if packages.is_empty() {
+    packages = km.default_packages().collect();
+  }
+
+  Ok(
+    packages
+      .iter()
+      .flat_map(|p| p.files.iter())
+      .map(|f| f.filesize)
+      .sum(),
+  )
+}
+
+/// Removes installed versions that are neither the newest installed
+/// version of their mod nor required, directly or through other kept
+/// mods, by a kept mod's dependencies. Folders holding another mod, such
+/// as a TC with its mods inside, are left alone. Asks first unless `yes`
+/// is set.
+pub fn gc(conn: &Connection, root: &Path, yes: bool) -> Result<()> {
+  let installed = scan(root)?;
+  let keep = versions_to_keep(conn, &installed)?;
+
+  let mut garbage = Vec::new();
+  for m in &installed {
+    if keep.contains(&(m.mid.clone(), m.version.clone())) {
+      continue;
+    }
+
+    let nested = installed
+      .iter()
+      .find(|other| other.dir != m.dir && other.dir.starts_with(&m.dir));
+    match nested {
+      Some(other) => eprintln!(
+        "Keeping {} {}: {} also contains {} {}",
+        m.mid,
+        m.version,
+        m.dir.display(),
+        other.mid,
+        other.version
+      ),
+      None => garbage.push((m, dir_size(&m.dir)?)),
+    }
+  }
+
+  if garbage.is_empty() {
+    status!("==> Nothing to remove");
+    return Ok(());
+  }
+
+  let records: Vec<GcRecord> = garbage
+    .iter()
+    .map(|(m, size)| GcRecord {
+      mid: &m.mid,
+      version: &m.version,
+      size: *size,
+      dir: m.dir.to_string_lossy().into_owned(),
+    })
+    .collect();
+  output::print_records(&records, || {
+    println!("{:<30} {:<20} {:>15}  DIR", "MID", "VERSION", "SIZE");
+    for r in &records {
+      println!("{:<30} {:<20} {:>15}  {}", r.mid, r.version, r.size, r.dir);
+    }
+    println!();
+    Ok(())
+  })?;
+  let total: u64 = garbage.iter().map(|(_, size)| size).sum();
+  status!(
+    "==> {} old versions, {} bytes can be reclaimed",
+    garbage.len(),
+    total
+  );
+
+  if !yes && !confirm("Remove them?")? {
+    return Ok(());
+  }
+
+  for (m, _) in &garbage {
+    status!("==> Removing {} {}", m.mid, m.version);
+    fs::remove_dir_all(&m.dir).map_err(Error::io(&m.dir))?;
+    // Drops the `<mid>` folder of a `<mid>/<version>` layout once it is empty.
+    if let Some(parent) = m.dir.parent() {
+      if parent != root {
+        let _ = fs::remove_dir(parent);
+      }
+    }
+  }
+  status!("==> Reclaimed {} bytes", total);
+  Ok(())
+}
+
+/// The newest installed version of each mod plus, transitively, the
+/// newest installed version satisfying each of their dependencies.
+fn versions_to_keep(
+  conn: &Connection,
+  installed: &[InstalledMod],
+) -> Result<BTreeSet<(String, String)>> {
+  let mut by_mid: BTreeMap<&str, Vec<&InstalledMod>> = BTreeMap::new();
+  for m in installed {
+    // Stays newest first, as sorted by `scan`.
+    by_mid.entry(&m.mid).or_default().push(m);
+  }
+
+  let mut keep = BTreeSet::new();
+  let mut queue: Vec<&InstalledMod> = by_mid.values().map(|versions| versions[0]).collect();
+  while let Some(m) = queue.pop() {
+    if !keep.insert((m.mid.clone(), m.version.clone())) {
+      continue;
+    }
+
+    let km = installed_knossos_mod(conn, m)?;
+    for package in &km.packages {
+      if !m.packages.is_empty() && !m.packages.contains(&package.name) {
+        continue;
+      }
+
+      for dependency in &package.dependencies {
+        let candidates = by_mid.get(dependency.id.as_str()).into_iter().flatten();
+        let spec = match dependency.version.as_deref().map(VersionSpec::parse) {
+          Some(Ok(spec)) => spec,
+          None => VersionSpec::any(),
+          Some(Err(e)) => {
+            // Without the spec there is no telling which version is
+            // needed, so none of them go.
+            eprintln!(
+              "Warning: {} {}: {}; keeping every installed version of {}",
+              m.mid, m.version, e, dependency.id
+            );
+            queue.extend(candidates);
+            continue;
+          }
+        };
+        if let Some(required) = candidates.into_iter().find(|d| spec.matches(&d.version)) {
+          queue.push(required);
+        }
+      }
+    }
+  }
+
+  Ok(keep)
+}
+
+/// The repo entry of an installed release, or its local mod.json when the
+/// release is no longer in the repo.
+fn installed_knossos_mod(conn: &Connection, m: &InstalledMod) -> Result<KnossosMod> {
+  match repo::get_mod(conn, &m.mid, &Some(m.version.clone()))? {
+    Some(repo_mod) => repo_mod.knossos_mod(),
+    None => {
+      let path = m.dir.join("mod.json");
+      let bytes = fs::read(&path).map_err(Error::io(&path))?;
+      KnossosMod::from_json_str(&m.mid, &String::from_utf8_lossy(&bytes))
+    }
+  }
+}
+
+fn dir_size(dir: &Path) -> Result<u64> {
+  let mut size = 0;
+  for entry in fs::read_dir(dir).map_err(Error::io(dir))? {
+    let entry = entry.map_err(Error::io(dir))?;
+    let path = entry.path();
+    let metadata = entry.metadata().map_err(Error::io(&path))?;
+    if metadata.is_dir() {
+      size += dir_size(&path)?;
+    } else {
+      size += metadata.len();
+    }
+  }
+  Ok(size)
+}
+
+#[derive(Default, Serialize)]
+struct OutdatedRecord {
+  mid: String,
+  installed: String,
+  latest: String,
+  download_size: u64,
+}
+
+#[derive(Default, Serialize)]
+struct GcRecord<'a> {
+  mid: &'a str,
+  version: &'a str,
+  size: u64,
+  dir: String,
+}
+
+fn confirm(question: &str) -> Result<bool> {
+  // The prompt goes to stderr when stdout carries machine-readable output.
+  if output::is_text() {
+    print!("{} [y/N] ", question);
+    io::stdout()
+      .flush()
+      .map_err(Error::io(Path::new("<stdout>")))?;
+  } else {
+    eprint!("{} [y/N] ", question);
+  }
+
+  let mut answer = String::new();
+  io::stdin()
+    .read_line(&mut answer)
+    .map_err(Error::io(Path::new("<stdin>")))?;
+  Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::repo::tests::db;
+  use serde_json::json;
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("neb-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  /// Writes the mod.json of `mid` `version` into `root/folder`.
+  fn install(root: &Path, folder: &str, mid: &str, version: &str) {
+    install_json(root, folder, &release(mid, version, &[]));
+  }
+
+  /// Writes `mod_json` into `root/folder` as a mod.json.
+  fn install_json(root: &Path, folder: &str, mod_json: &Value) {
+    let dir = root.join(folder);
+    fs::create_dir_all(&dir).unwrap();
+    let json = serde_json::to_string_pretty(mod_json).unwrap();
+    fs::write(dir.join("mod.json"), json).unwrap();
+  }
+
+  /// A release as Knossos publishes it in the repo and keeps it in the
+  /// mod.json of an installed mod. Its Core package is one 100 byte
+  /// archive and depends on `deps`, each a mod id and a version spec.
+  fn release(mid: &str, version: &str, deps: &[(&str, &str)]) -> Value {
+    let dependencies: Vec<Value> = deps
+      .iter()
+      .map(|(id, spec)| json!({"id": id, "version": spec, "packages": []}))
+      .collect();
+
+    json!({
+      "id": mid, "title": format!("{} title", mid), "version": version,
+      "type": "mod", "parent": "FS2", "stability": "stable",
+      "description": "", "logo": null, "tile": null, "banner": null,
+      "release_thread": null, "videos": [], "screenshots": [], "attachments": [],
+      "notes": "", "first_release": "2020-01-01", "last_update": "2023-01-01",
+      "cmdline": "", "mod_flag": [mid],
+      "packages": [{
+        "name": "Core", "notes": "", "status": "required", "environment": "",
+        "folder": "", "dependencies": dependencies, "is_vp": false,
+        "executables": [], "filelist": [],
+        "files": [{
+          "filename": "core.7z", "dest": "", "checksum": ["sha256", "0"],
+          "filesize": 100, "urls": ["https://example.com/core.7z"]
+        }]
+      }]
+    })
+  }
+
+  fn found(mods: &[InstalledMod], root: &Path) -> Vec<String> {
+    mods
+      .iter()
+      .map(|m| {
+        let dir = m.dir.strip_prefix(root).unwrap().to_string_lossy();
+        format!("{} {} {}", m.mid, m.version, dir.replace('\\', "/"))
+      })
+      .collect()
+  }
+
+  #[test]
+  fn scan_finds_mods_inside_mod_folders() {
+    let root = temp_dir("scan");
+    install(&root, "MVPS-4.6.0", "MVPS", "4.6.0");
+    install(&root, "MVPS/4.5.1", "MVPS", "4.5.1");
+    install(&root, "BtA-1.0", "BtA", "1.0");
+    install(&root, "BtA-1.0/BtA-Voice-1.0", "BtA-Voice", "1.0");
+    install(&root, "a/b/c/too-deep-1.0", "deep", "1.0");
+    install(&root, ".neb/cache-1.0", "cache", "1.0");
+
+    assert_eq!(
+      found(&scan(&root).unwrap(), &root),
+      [
+        "BtA 1.0 BtA-1.0",
+        "BtA-Voice 1.0 BtA-1.0/BtA-Voice-1.0",
+        "MVPS 4.6.0 MVPS-4.6.0",
+        "MVPS 4.5.1 MVPS/4.5.1",
+      ]
+    );
+    fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn outdated_compares_newest_installed_with_repo() {
+    let root = temp_dir("outdated");
+    install(&root, "MVPS-4.5.1", "MVPS", "4.5.1");
+    install(&root, "MVPS-4.6.0", "MVPS", "4.6.0");
+    install(&root, "FSO-22.0.0", "FSO", "22.0.0");
+    install(&root, "local-1.0", "local", "1.0");
+    let conn = db(&[
+      release("MVPS", "4.6.0", &[]),
+      release("FSO", "22.0.0", &[]),
+      release("FSO", "23.0.0", &[]),
+    ]);
+
+    let (rows, unknown) = find_outdated(&conn, &scan(&root).unwrap()).unwrap();
+    let rows: Vec<_> = rows
+      .iter()
+      .map(|r| {
+        (
+          r.mid.as_str(),
+          r.installed.as_str(),
+          r.latest.as_str(),
+          r.download_size,
+        )
+      })
+      .collect();
+    assert_eq!(rows, [("FSO", "22.0.0", "23.0.0", 100)]);
+    assert_eq!(unknown, ["local"]);
+    fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn versions_to_keep_follows_dependencies() {
+    let root = temp_dir("keep");
+    install(&root, "FSO-22.0.0", "FSO", "22.0.0");
+    install(&root, "FSO-23.0.0", "FSO", "23.0.0");
+    install(&root, "MVPS-4.5.1", "MVPS", "4.5.1");
+    install(&root, "MVPS-4.6.0", "MVPS", "4.6.0");
+    install(&root, "BtA-1.0", "BtA", "1.0");
+    let conn = db(&[
+      release("FSO", "22.0.0", &[]),
+      release("FSO", "23.0.0", &[]),
+      release("MVPS", "4.5.1", &[]),
+      release("MVPS", "4.6.0", &[]),
+      release("BtA", "1.0", &[("FSO", "<23"), ("MVPS", "4.x")]),
+    ]);
+
+    let keep = versions_to_keep(&conn, &scan(&root).unwrap()).unwrap();
+    let keep: Vec<_> = keep
+      .iter()
+      .map(|(mid, v)| format!("{} {}", mid, v))
+      .collect();
+    // BtA needs FSO 22, and its unparsable MVPS spec keeps both MVPS.
+    assert_eq!(
+      keep,
+      [
+        "BtA 1.0",
+        "FSO 22.0.0",
+        "FSO 23.0.0",
+        "MVPS 4.5.1",
+        "MVPS 4.6.0"
+      ]
+    );
+    fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn versions_to_keep_reads_mod_json_of_releases_gone_from_repo() {
+    let root = temp_dir("keep-local");
+    install(&root, "FSO-22.0.0", "FSO", "22.0.0");
+    install(&root, "FSO-23.0.0", "FSO", "23.0.0");
+    install_json(
+      &root,
+      "local-1.0",
+      &release("local", "1.0", &[("FSO", "<23")]),
+    );
+    let conn = db(&[release("FSO", "22.0.0", &[]), release("FSO", "23.0.0", &[])]);
+
+    let keep = versions_to_keep(&conn, &scan(&root).unwrap()).unwrap();
+    let keep: Vec<_> = keep
+      .iter()
+      .map(|(mid, v)| format!("{} {}", mid, v))
+      .collect();
+    assert_eq!(keep, ["FSO 22.0.0", "FSO 23.0.0", "local 1.0"]);
+    fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn gc_removes_old_versions_but_not_folders_with_mods_inside() {
+    let root = temp_dir("gc");
+    install(&root, "MVPS/4.5.1", "MVPS", "4.5.1");
+    install(&root, "MVPS/4.6.0", "MVPS", "4.6.0");
+    install(&root, "FSO-22.0.0", "FSO", "22.0.0");
+    install(&root, "FSO-23.0.0", "FSO", "23.0.0");
+    install(&root, "BtA-0.9", "BtA", "0.9");
+    install(&root, "BtA-0.9/BtA-Voice-1.0", "BtA-Voice", "1.0");
+    install(&root, "BtA-1.0", "BtA", "1.0");
+    fs::write(root.join("FSO-22.0.0/fs2_open"), "engine").unwrap();
+    let conn = db(&[
+      release("FSO", "22.0.0", &[]),
+      release("FSO", "23.0.0", &[]),
+      release("MVPS", "4.5.1", &[]),
+      release("MVPS", "4.6.0", &[]),
+      release("BtA", "0.9", &[]),
+      release("BtA", "1.0", &[("FSO", "<23")]),
+      release("BtA-Voice", "1.0", &[("BtA", "")]),
+    ]);
+
+    gc(&conn, &root, true).unwrap();
+
+    assert_eq!(
+      found(&scan(&root).unwrap(), &root),
+      [
+        "BtA 1.0 BtA-1.0",
+        "BtA 0.9 BtA-0.9",
+        "BtA-Voice 1.0 BtA-0.9/BtA-Voice-1.0",
+        "FSO 23.0.0 FSO-23.0.0",
+        "FSO 22.0.0 FSO-22.0.0",
+        "MVPS 4.6.0 MVPS/4.6.0",
+      ]
+    );
+    fs::remove_dir_all(root).unwrap();
+  }
+}
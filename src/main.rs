@@ -1,175 +1,929 @@
 use std::env;
-use std::path::Path;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process;
 
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+
 use neb::command;
+use neb::config::{Config, Source};
+use neb::download_engine::{self, Options};
+use neb::output::{self, Format, Verbosity};
+use neb::repo;
+
+/// Command-line program and web view for exploring Knossos/FSNebula data.
+#[derive(Parser)]
+#[command(name = "neb", version, after_help = AFTER_HELP)]
+struct Cli {
+  /// Repo database file (default: NEB_DB or the db setting)
+  #[arg(
+    long,
+    global = true,
+    help_heading = "Global options",
+    value_name = "PATH"
+  )]
+  db: Option<PathBuf>,
+
+  /// Config file (default: ~/.config/neb/config.toml)
+  #[arg(
+    long,
+    global = true,
+    help_heading = "Global options",
+    env = "NEB_CONFIG",
+    value_name = "PATH"
+  )]
+  config: Option<PathBuf>,
+
+  /// Output format: text, json, jsonl, csv or tsv
+  #[arg(
+    long,
+    global = true,
+    help_heading = "Global options",
+    value_name = "FORMAT",
+    default_value = "text",
+    hide_possible_values = true,
+    value_parser = PossibleValuesParser::new(Format::NAMES).try_map(|s| s.parse::<Format>())
+  )]
+  format: Format,
+
+  /// Print only results, warnings and errors
+  #[arg(
+    short,
+    long,
+    global = true,
+    help_heading = "Global options",
+    conflicts_with = "verbose"
+  )]
+  quiet: bool,
+
+  /// Print details of each step, such as every downloaded file
+  #[arg(short, long, global = true, help_heading = "Global options")]
+  verbose: bool,
+
+  #[command(subcommand)]
+  command: Command,
+}
 
-fn main() {
-  let argv_0 = env::args().nth(1).unwrap_or_else(|| "".to_string());
-  let argv_1 = env::args().nth(2).unwrap_or_else(|| "".to_string());
-  let argv_2 = env::args().nth(3).unwrap_or_else(|| "".to_string());
-  let argv_3 = env::args().nth(4);
+#[derive(Subcommand)]
+enum Command {
+  /// Download remote repo json files
+  #[command(after_help = FETCH_HELP)]
+  Fetch {
+    /// Where to save the repo json (default: the json setting)
+    json: Option<PathBuf>,
+
+    /// Download even when the saved copy is current
+    #[arg(long)]
+    force: bool,
+    #[command(flatten)]
+    transfer: Transfer,
+  },
+
+  /// Same as 'fetch' followed by 'update'
+  #[command(after_help = FETCH_HELP)]
+  FetchUpdate {
+    /// Where to save the repo json (default: the json setting)
+    json: Option<PathBuf>,
+
+    /// Download even when the saved copy is current
+    #[arg(long)]
+    force: bool,
+    #[command(flatten)]
+    transfer: Transfer,
+  },
+
+  /// Update repo database from the repo json of every source
+  #[command(after_help = UPDATE_HELP)]
+  Update {
+    /// Repo json file, e.g. from 'fetch', or - for stdin (default: the
+    /// json setting)
+    json: Option<PathBuf>,
+
+    /// Print the releases that would be added, updated and deleted
+    /// without changing the database
+    #[arg(long)]
+    dry_run: bool,
+  },
+
+  /// Print schema version, row counts and last update
+  DbInfo,
+
+  /// Print list of mods as titles in plain text
+  List {
+    /// Only mods with a release from the named source
+    #[arg(long, value_name = "NAME")]
+    source: Option<String>,
+  },
+
+  /// Print list of mods as JSON (deprecated: use --format json list)
+  ListJson,
+
+  /// Print mod ids matching query and filters, best match first
+  #[command(after_help = SEARCH_HELP)]
+  Search {
+    /// Words to look for
+    query: Option<String>,
+    /// mod, tc, engine or tool
+    #[arg(long = "type", value_name = "TYPE")]
+    mod_type: Option<String>,
+    /// stable, rc or nightly
+    #[arg(long, value_name = "NAME")]
+    stability: Option<String>,
+    /// Mods for the total conversion MID, e.g. FS2
+    #[arg(long, value_name = "MID")]
+    parent: Option<String>,
+    /// First release date, e.g. 2023-01-01..2023-06-30
+    #[arg(long, value_name = "RANGE")]
+    released: Option<String>,
+    /// Last update date, e.g. 2023.. for 2023 or later
+    #[arg(long, value_name = "RANGE")]
+    updated: Option<String>,
+    /// Download size of the required and recommended packages only,
+    /// e.g. ..500M or 1G..
+    #[arg(long, value_name = "RANGE")]
+    size: Option<String>,
+  },
+
+  /// Print list of versions of mod id, newest first
+  #[command(after_help = VERSIONS_HELP)]
+  Versions {
+    /// Mod id
+    mid: String,
+    /// Print only versions matching SPEC
+    #[arg(long = "match", value_name = "SPEC")]
+    matching: Option<String>,
+    /// Print the newest version matching SPEC, or fail
+    #[arg(long, value_name = "SPEC", conflicts_with = "matching")]
+    latest_matching: Option<String>,
+    /// Skip RC, beta and nightly builds
+    #[arg(long)]
+    stable_only: bool,
+  },
+
+  /// Print mod.json of mod id (default: latest)
+  Json(Release),
+
+  /// Print command-line opts of mod id [and version]
+  Cmdline(Release),
+
+  /// Print mod params of mod id [and version]
+  #[command(after_help = MOD_HELP)]
+  Mod {
+    /// Mod id
+    #[arg(required_unless_present = "resolve")]
+    mid: Option<String>,
+    /// Version (default: latest)
+    version: Option<String>,
+    /// Print mod params resolved from the dependencies of SEL
+    #[arg(long, value_name = "SEL", num_args = 1.., conflicts_with = "mid")]
+    resolve: Vec<String>,
+  },
+
+  /// Print sha256sums of files by mod id [and version]
+  Sha256sum(Release),
+
+  /// Print total download size by mod id [and version]
+  Dlsize(Release),
+
+  /// Print resolved dependency tree of mod id [and version]
+  Deps(Release),
+
+  /// Print resolved install set as JSON (deprecated: use --format json deps)
+  DepsJson(Release),
+
+  /// Download and verify package files of mod id [and version]
+  #[command(after_help = DOWNLOAD_HELP)]
+  Download {
+    #[command(flatten)]
+    release: Release,
+    /// Packages to download (default: required and recommended)
+    #[arg(long, value_name = "NAME,...", value_delimiter = ',')]
+    packages: Option<Vec<String>>,
+    /// Directory to save files in (default: the download_dir setting)
+    #[arg(long, value_name = "DIR")]
+    dest: Option<PathBuf>,
+    #[command(flatten)]
+    transfer: Transfer,
+  },
+
+  /// Download and install mod id [and version] into FS2DIR
+  #[command(after_help = INSTALL_HELP)]
+  Install {
+    #[command(flatten)]
+    release: Release,
+    /// FreeSpace 2 directory to install into (default: the fs2_root setting)
+    #[arg(long, value_name = "FS2DIR")]
+    root: Option<PathBuf>,
+    /// Packages to install (default: required and recommended)
+    #[arg(long, value_name = "NAME,...", value_delimiter = ',')]
+    packages: Option<Vec<String>>,
+    /// Where archives are kept (default: FS2DIR/.neb/cache)
+    #[arg(long, value_name = "DIR")]
+    cache: Option<PathBuf>,
+    #[command(flatten)]
+    transfer: Transfer,
+  },
+
+  /// Remove an installed mod version from FS2DIR
+  Uninstall {
+    /// Mod id
+    mid: String,
+    /// Installed version
+    version: String,
+    /// FreeSpace 2 directory the mod is installed in (default: the fs2_root setting)
+    #[arg(long, value_name = "FS2DIR")]
+    root: Option<PathBuf>,
+  },
+
+  /// Print installed mods with a newer release and the upgrade size
+  #[command(after_help = OUTDATED_HELP)]
+  Outdated {
+    /// FreeSpace 2 directory to look in (default: the fs2_root setting)
+    #[arg(long, value_name = "FS2DIR")]
+    root: Option<PathBuf>,
+  },
+
+  /// Remove old installed mod versions that nothing requires
+  #[command(after_help = GC_HELP)]
+  Gc {
+    /// FreeSpace 2 directory to clean up (default: the fs2_root setting)
+    #[arg(long, value_name = "FS2DIR")]
+    root: Option<PathBuf>,
+    /// Remove without asking
+    #[arg(long)]
+    yes: bool,
+  },
+
+  /// Check files in DIR against the filelist checksums
+  #[command(after_help = VERIFY_HELP)]
+  Verify {
+    #[command(flatten)]
+    release: Release,
+    /// Installed mod folder, e.g. FS2DIR/MID-VERSION
+    #[arg(long, value_name = "DIR")]
+    dir: PathBuf,
+  },
+
+  /// Same as 'verify' with a JSON report (deprecated: use --format json verify)
+  VerifyJson {
+    #[command(flatten)]
+    release: Release,
+    /// Installed mod folder, e.g. FS2DIR/MID-VERSION
+    #[arg(long, value_name = "DIR")]
+    dir: PathBuf,
+  },
+
+  /// Start a web server to view mod info in the browser
+  #[command(after_help = WEB_HELP)]
+  Web,
+
+  /// Print a shell completion script
+  #[command(after_help = COMPLETIONS_HELP)]
+  Completions { shell: Shell },
+
+  /// Show the settings from the config file
+  #[command(subcommand, after_help = CONFIG_HELP)]
+  Config(ConfigCommand),
+}
 
-  match argv_0.as_str() {
-    "--help" | "help" => print_help(),
-    "--version" => print_version(),
+#[derive(Subcommand)]
+enum ConfigCommand {
+  /// Print the effective settings and where each one came from
+  Show,
+}
 
-    "fetch" => command::fetch(as_path(&argv_1)),
+#[derive(Args)]
+struct Release {
+  /// Mod id
+  mid: String,
+  /// Version (default: latest)
+  version: Option<String>,
+}
 
-    "search" => command::search(as_path(&argv_1), as_string(&argv_2)),
-    "versions" => command::versions(as_path(&argv_1), as_string(&argv_2)),
+#[derive(Args)]
+struct Transfer {
+  /// Number of files to download at once
+  #[arg(long, value_name = "N", default_value = "4", value_parser = parse_jobs)]
+  jobs: usize,
+  /// Total download speed limit in bytes/s, e.g. 500K or 2M
+  #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+  limit_rate: Option<u64>,
+}
 
-    "list" => command::list(as_path(&argv_1)),
-    "list-json" => command::list_json(as_path(&argv_1)),
+impl Transfer {
+  fn options(&self) -> Options {
+    Options {
+      concurrency: self.jobs,
+      max_recv_speed: self.limit_rate,
+      ..Options::default()
+    }
+  }
+}
 
-    "fetch-update" => command::fetch_update(as_path(&argv_1), as_path(&argv_2)),
-    "update" => command::update(as_path(&argv_1), as_path(&argv_2)),
+/// Commands that took the database as their first argument before it
+/// became the --db option.
+const LEGACY_DB_COMMANDS: [&str; 12] = [
+  "fetch-update",
+  "update",
+  "list",
+  "list-json",
+  "search",
+  "versions",
+  "json",
+  "cmdline",
+  "mod",
+  "sha256sum",
+  "dlsize",
+  "web",
+];
 
-    "json" => command::json(as_path(&argv_1), as_string(&argv_2), &argv_3),
-    "cmdline" => command::cmdline(as_path(&argv_1), as_string(&argv_2), &argv_3),
-    "mod" => command::modline(as_path(&argv_1), as_string(&argv_2), &argv_3),
-    "sha256sum" => command::sha256sum(as_path(&argv_1), as_string(&argv_2), &argv_3),
-    "dlsize" => command::dlsize(as_path(&argv_1), as_string(&argv_2), &argv_3),
+fn main() {
+  let cli = Cli::parse_from(legacy_db_args(env::args_os().collect()));
+  output::set_format(cli.format);
+  output::set_verbosity(if cli.quiet {
+    Verbosity::Quiet
+  } else if cli.verbose {
+    Verbosity::Verbose
+  } else {
+    Verbosity::Normal
+  });
+
+  if let Err(e) = run(&cli) {
+    eprintln!("{}", e);
+    process::exit(e.exit_code());
+  }
+}
 
-    "web" => command::web(as_path(&argv_1)),
+fn run(cli: &Cli) -> neb::Result<()> {
+  let mut config = Config::load(cli.config.as_deref())?;
+  config.db.set_from(cli.db.clone(), Source::Flag("--db"));
+
+  match &cli.command {
+    Command::Fetch {
+      json,
+      force,
+      transfer,
+    } => command::fetch(
+      or_json(json, &config)?,
+      &config.sources.value,
+      *force,
+      &transfer.options(),
+    ),
+    Command::FetchUpdate {
+      json,
+      force,
+      transfer,
+    } => command::fetch_update(
+      config.db()?,
+      or_json(json, &config)?,
+      &config.sources.value,
+      *force,
+      &transfer.options(),
+    ),
+    Command::Update { json, dry_run } => command::update(
+      config.db()?,
+      or_json(json, &config)?,
+      &config.sources.value,
+      *dry_run,
+    ),
+    Command::DbInfo => command::db_info(config.db()?),
+    Command::List { source } => command::list(config.db()?, source.as_deref()),
+    Command::ListJson => {
+      deprecated("list-json", "list");
+      match output::format() {
+        Format::Text => command::list_json(config.db()?),
+        _ => command::list(config.db()?, None),
+      }
+    }
 
-    _ => {
-      print_help();
-      process::exit(1);
+    Command::Search {
+      query,
+      mod_type,
+      stability,
+      parent,
+      released,
+      updated,
+      size,
+    } => {
+      let filters: Vec<(&str, String)> = [
+        ("type", mod_type),
+        ("stability", stability),
+        ("parent", parent),
+        ("released", released),
+        ("updated", updated),
+        ("size", size),
+      ]
+      .into_iter()
+      .filter_map(|(name, value)| value.clone().map(|v| (name, v)))
+      .collect();
+      command::search(config.db()?, query.as_deref().unwrap_or_default(), &filters)
+    }
+    Command::Versions {
+      mid,
+      matching,
+      latest_matching,
+      stable_only,
+    } => command::versions(
+      config.db()?,
+      mid,
+      &matching.clone().or_else(|| latest_matching.clone()),
+      *stable_only,
+      latest_matching.is_some(),
+    ),
+
+    Command::Json(r) => command::json(config.db()?, &r.mid, &r.version),
+    Command::Cmdline(r) => command::cmdline(config.db()?, &r.mid, &r.version),
+    Command::Mod {
+      mid: Some(mid),
+      version,
+      ..
+    } => command::modline(config.db()?, mid, version),
+    Command::Mod { resolve, .. } => command::modline_resolved(config.db()?, resolve),
+    Command::Sha256sum(r) => command::sha256sum(config.db()?, &r.mid, &r.version),
+    Command::Dlsize(r) => command::dlsize(config.db()?, &r.mid, &r.version),
+    Command::Deps(r) => command::deps(config.db()?, &r.mid, &r.version),
+    Command::DepsJson(r) => {
+      deprecated("deps-json", "deps");
+      match output::format() {
+        Format::Text => command::deps_json(config.db()?, &r.mid, &r.version),
+        _ => command::deps(config.db()?, &r.mid, &r.version),
+      }
     }
-  };
-}
 
-fn print_version() {
-  let version = env!("CARGO_PKG_VERSION");
-  println!("{}", version);
-}
+    Command::Download {
+      release,
+      packages,
+      dest,
+      transfer,
+    } => command::download(
+      config.db()?,
+      &release.mid,
+      &release.version,
+      packages,
+      dest.as_deref().unwrap_or(&config.download_dir.value),
+      &transfer.options(),
+    ),
+    Command::Install {
+      release,
+      root,
+      packages,
+      cache,
+      transfer,
+    } => {
+      let root = or_root(root, &config)?;
+      command::install(
+        config.db()?,
+        &release.mid,
+        &release.version,
+        packages,
+        root,
+        &cache
+          .clone()
+          .unwrap_or_else(|| root.join(".neb").join("cache")),
+        &transfer.options(),
+      )
+    }
+    Command::Uninstall { mid, version, root } => {
+      command::uninstall(or_root(root, &config)?, mid, version)
+    }
 
-fn print_help() {
-  let help = format!(
-    r###"
-Usage: {cmd_name} COMMAND [ARGS]
+    Command::Outdated { root } => command::outdated(config.db()?, or_root(root, &config)?),
+    Command::Gc { root, yes } => command::gc(config.db()?, or_root(root, &config)?, *yes),
+    Command::Verify { release, dir } => {
+      command::verify(config.db()?, &release.mid, &release.version, dir)
+    }
+    Command::VerifyJson { release, dir } => {
+      deprecated("verify-json", "verify");
+      match output::format() {
+        Format::Text => command::verify_json(config.db()?, &release.mid, &release.version, dir),
+        _ => command::verify(config.db()?, &release.mid, &release.version, dir),
+      }
+    }
 
-FLAGS
+    Command::Web => command::web(config.db()?, config.web_bind.value, config.web_port.value),
 
-  --help                          : Print this message
-  --version                       : Print version
+    Command::Completions { shell } => {
+      clap_complete::generate(*shell, &mut Cli::command(), "neb", &mut io::stdout());
+      Ok(())
+    }
+    Command::Config(ConfigCommand::Show) => command::config_show(&config),
+  }
+}
 
+/// Rewrites the deprecated `neb COMMAND REPO ...` into `neb --db REPO
+/// COMMAND ...`. REPO is the first argument that is not a flag, recognized
+/// as an existing SQLite file, or for update and fetch-update, as the first
+/// of two such arguments.
+fn legacy_db_args(mut args: Vec<OsString>) -> Vec<OsString> {
+  let mut i = 1;
+  while let Some(arg) = args.get(i).map(|a| a.to_string_lossy()) {
+    if matches!(arg.as_ref(), "--db" | "--config" | "--format") {
+      i += 2;
+    } else if arg.starts_with('-') {
+      i += 1;
+    } else {
+      break;
+    }
+  }
 
-COMMANDS
+  let command = match args.get(i) {
+    Some(command) => command.to_string_lossy().into_owned(),
+    None => return args,
+  };
+  if !LEGACY_DB_COMMANDS.contains(&command.as_str()) {
+    return args;
+  }
 
-  help                            : Print this message
-  fetch        JSON               : Download remote repo json file
-  fetch-update REPO JSON          : Same as 'fetch' followed by 'update'
-  update       REPO JSON          : Update repo database
-  list         REPO               : Print list of mods as titles in plain text
-  list-json    REPO               : Print list of mods as JSON
-  search       REPO QUERY         : Print mod ids of queried title
-  versions     REPO MID           : Print list of versions of mod id
-  json         REPO MID [VERSION] : Print mod.json of mod id (default: latest)
-  cmdline      REPO MID [VERSION] : Print command-line opts of mod id [and version]
-  mod          REPO MID [VERSION] : Print mod params of mod id [and version]
-  sha256sum    REPO MID [VERSION] : Print sha256sums of files by mod id [and version]
-  dlsize       REPO MID [VERSION] : Print total download size by mod id [and version]
-  web          REPO               : Start a web server to view mod info in the browser
+  let positionals = positional_args(&command, &args[i + 1..]);
+  let repo_index = match positionals.first() {
+    Some(&n) => i + 1 + n,
+    None => return args,
+  };
 
+  let is_db = if matches!(command.as_str(), "update" | "fetch-update") {
+    positionals.len() == 2
+  } else {
+    let repo_arg = Path::new(&args[repo_index]);
+    repo_arg.is_file() && repo::ensure_sqlite3_db(repo_arg).is_ok()
+  };
+  if !is_db {
+    return args;
+  }
 
-WEB
+  eprintln!(
+    "Warning: 'neb {} REPO' is deprecated; use 'neb --db REPO {}' instead",
+    command, command
+  );
+  let repo_arg = args.remove(repo_index);
+  args.splice(1..1, [OsString::from("--db"), repo_arg]);
+  args
+}
 
-  The "web" command supports BIND and PORT environment variables.
+/// The indices of the arguments to `command` that are not flags or flag
+/// values.
+fn positional_args(command: &str, args: &[OsString]) -> Vec<usize> {
+  let cli = Cli::command();
+  let takes_value = |flag: &str| {
+    cli
+      .find_subcommand(command)
+      .into_iter()
+      .flat_map(|c| c.get_arguments())
+      .chain(cli.get_arguments())
+      .any(|a| a.get_long() == Some(flag) && a.get_action().takes_values())
+  };
 
-  BIND and PORT are optional. The default values are:
+  let mut positionals = Vec::new();
+  let mut n = 0;
+  while let Some(arg) = args.get(n).map(|a| a.to_string_lossy()) {
+    match arg.strip_prefix("--") {
+      Some(flag) if !flag.contains('=') && takes_value(flag) => n += 1,
+      Some(_) => {}
+      None if arg.starts_with('-') && arg != "-" => {}
+      None => positionals.push(n),
+    }
+    n += 1;
+  }
 
-  BIND=127.0.0.1
-  PORT=3200
+  positionals
+}
 
-  Example:
+fn deprecated(old: &str, new: &str) {
+  eprintln!(
+    "Warning: '{}' is deprecated; use '--format json {}' instead",
+    old, new
+  );
+}
+
+/// The JSON argument, or the json setting without one.
+fn or_json<'a>(json: &'a Option<PathBuf>, config: &'a Config) -> neb::Result<&'a Path> {
+  json.as_deref().map_or_else(|| config.json(), Ok)
+}
 
-      BIND=0.0.0.0 PORT=3000 {cmd_name} web repo.db
+/// The --root flag, or the fs2_root setting without one.
+fn or_root<'a>(root: &'a Option<PathBuf>, config: &'a Config) -> neb::Result<&'a Path> {
+  root.as_deref().map_or_else(|| config.fs2_root(), Ok)
+}
+
+fn parse_jobs(text: &str) -> Result<usize, String> {
+  match text.parse() {
+    Ok(jobs) if jobs > 0 => Ok(jobs),
+    _ => Err("expected a number greater than 0".to_string()),
+  }
+}
 
+fn parse_rate(text: &str) -> Result<u64, String> {
+  download_engine::parse_rate(text).ok_or_else(|| "expected bytes/s, e.g. 500K or 2M".to_string())
+}
 
-EXAMPLES
+static AFTER_HELP: &str = r###"Output format:
+  Every command prints its results in the --format given. json prints
+  an array of records, or one object for commands with a single result
+  such as db-info and json; jsonl prints one record per line; csv and
+  tsv print a header line of field names, with nested values as JSON.
+  Field names are stable across releases. Progress messages go to
+  stderr when the format is not text. The deprecated list-json,
+  deps-json and verify-json commands print their old JSON layout by
+  default; with a --format they act like list, deps and verify.
+
+  search       : mid, version, title, tile, snippet
+  list         : mid, version, title, tile, source
+  versions     : version, stable
+  cmdline      : mid, version, cmdline
+  mod          : mid, version, mod_flag
+  sha256sum    : filename, algorithm, checksum
+  dlsize       : filename, filesize
+  deps         : mid, version, title, type, packages, dependencies
+  outdated     : mid, installed, latest, download_size
+  gc           : mid, version, size, dir
+  update       : action, mid, version, title, source (with --dry-run)
+
+Older arguments:
+  Commands used to take the database as their first argument, as in
+  'neb list repo.db' or 'neb update repo.db repo.json'. This still works
+  with a warning but is deprecated; use --db or NEB_DB instead.
+
+Exit status:
+  0  Success
+  1  Other errors, e.g. I/O or database errors
+  2  Invalid arguments
+  3  Mod, version, package or file not found
+  4  Verification found missing or modified files
+  5  Download failed
+
+Examples:
+  # Download remote repo json file
+  neb fetch repo.json
 
-  # Update database
-  {cmd_name} update repo.db repo.json
+  # Update database, then keep using it without --db
+  neb --db repo.db update repo.json
+  export NEB_DB=repo.db
 
   # Update temporary in-memory database (SQLite3 feature)
-  {cmd_name} update ':memory:' repo.json
+  neb --db ':memory:' update repo.json
 
-  # Print list of mods as titles in plaint text
-  {cmd_name} list repo.db
+  # Download remote repo and update local db
+  neb fetch-update repo.json
 
-  # Print list of mods as JSON
-  {cmd_name} list-json repo.db
+  # Print schema version, row counts and last update time of database
+  neb db-info
 
-  # Print mid and title of query
-  {cmd_name} search repo.db silent
+  # Find mods by words in their title, description or notes
+  neb search shivan
 
-  # Print list of versions of mod id
-  {cmd_name} versions repo.db str
+  # Find total conversions updated since the start of 2023
+  neb search --type tc --updated 2023..
 
-  # Print mod.json of mod by mid of latest version
-  {cmd_name} json repo.db str
+  # Print the newest stable 4.x release
+  neb versions MVPS --latest-matching '>=4, <5' --stable-only
 
   # Print mod.json of mod by mid of version 1.6.0
-  {cmd_name} json repo.db str 1.6.0
-
-  # Print the command-line options of mod by mid
-  {cmd_name} cmdline repo.db MVPS
+  neb json str 1.6.0
 
   # Print the command-line options of mod by mid and version
-  {cmd_name} cmdline repo.db MVPS 4.5.1
+  neb cmdline MVPS 4.5.1
 
-  # Print the mod params of mod by mid
-  {cmd_name} mod repo.db MVPS
+  # Print the mod params computed from the resolved dependencies
+  neb mod --resolve 'MVPS@4.5.1:Core,Extra'
 
-  # Print the mod params of mod by mid and version
-  {cmd_name} mod repo.db MVPS 4.5.1
+  # Print the file sizes of a mod as CSV
+  neb --format csv dlsize MVPS
 
-  # Print sha256sums of mod files by mid (mod id)
-  {cmd_name} sha256sum repo.db MVPS
+  # Print the resolved dependency tree of mod by mid
+  neb deps str
 
-  # Print sha256sums of mod files by mid (mod id) and version
-  {cmd_name} sha256sum repo.db MVPS 4.5.1
+  # Download 8 files at a time using at most 2 MiB/s in total
+  neb download MVPS --dest downloads --jobs 8 --limit-rate 2M
 
-  # Print download size of mod files by mid (mod id)
-  {cmd_name} dlsize repo.db MVPS
+  # Install the default packages of mod by mid into ~/fs2, then remove it
+  neb install MVPS --root ~/fs2
+  neb uninstall MVPS 4.6.0 --root ~/fs2
 
-  # Print download size of mod files by mid (mod id) and version
-  {cmd_name} dlsize repo.db MVPS 4.5.1
+  # Print installed mods that have a newer release
+  neb outdated --root ~/fs2
 
-  # Download remote repo json file
-  {cmd_name} fetch repo.json
-
-  # Download remote repo and update local db
-  {cmd_name} fetch-update repo.db repo.json
-    # same as =>
-    #   {cmd_name} fetch repo.json
-    #   {cmd_name} update repo.db repo.json
+  # Check an installed mod folder for missing or corrupted files
+  neb verify MVPS 4.6.0 --dir ~/fs2/MVPS-4.6.0
 
   # Start web server
-  {cmd_name} web repo.db
-    "###,
-    cmd_name = env!("CARGO_PKG_NAME")
-  );
-
-  println!("{}", help.trim());
-}
-
-fn as_path(arg: &String) -> &Path {
-  if arg.is_empty() {
-    print_help();
-    process::exit(1);
+  neb web
+
+Run 'neb help COMMAND' for the options of a command."###;
+
+static FETCH_HELP: &str = r###"Details:
+  Every source with a url in the config file is downloaded. The first
+  is saved as JSON and the others next to it with the source name
+  added, e.g. repo.legacy.json for a source named legacy. Without any
+  sources configured, JSON is the FSNebula repo.
+
+  The ETag and Last-Modified date of each download are kept in
+  JSON.meta, so the next fetch only downloads a file that changed,
+  unless --force is given. A download replaces JSON only once it is
+  complete and valid JSON with a mods array, so an interrupted fetch
+  leaves the previous file in place.
+
+  The sources are downloaded at the same time, each retried up to 3
+  more times with growing pauses, within --limit-rate. The download is
+  compressed in transit when the server supports it, and stored
+  compressed when JSON ends in .gz or .zst."###;
+
+static UPDATE_HELP: &str = r###"Details:
+  Reads the files of every source that 'fetch' saves, and every source
+  with a path in the config file. When a release (mid and version) is
+  in more than one source, the one listed first in the config file
+  wins. 'neb list --source NAME' shows the mods of one source.
+
+  Files compressed with gzip or zstd are read as they are, and when
+  JSON does not exist, JSON.zst or JSON.gz is read instead. With - as
+  JSON, the repo json is read from stdin, which needs a single source.
+  Mods are read and stored one at a time, so memory use stays flat
+  however large the file is.
+
+  The database is created when it does not exist and migrated when it
+  was written by an older neb. Other commands only read the database
+  and ask for an update when it needs migrating."###;
+
+static SEARCH_HELP: &str = r###"Details:
+  QUERY words match the start of words in the mid, title, description,
+  notes and package names of the newest release of each mod, the one
+  updated last. A line with the matching text follows each result whose
+  title does not contain the match. Filters apply to the newest release
+  too and can be combined; --size counts its required and recommended
+  packages. A RANGE is FROM..TO with either end left out, or a single
+  value. Dates may be partial, so 2023 covers the whole year. Without
+  QUERY, the matching mods are printed by title. The web view takes the
+  same filters as query parameters, e.g.
+  /mods?q=shivan&type=tc&updated=2023.."###;
+
+static VERSIONS_HELP: &str = r###"Details:
+  SPEC uses the syntax of Knossos dependency versions: a plain version
+  such as 4.5 (matching 4.5 and every 4.5.x release, but not their RC or
+  nightly builds unless named, e.g. 4.5.0-RC1), comparisons (<, <=, >,
+  >=, ==, !=), ~, ~= and ^ ranges, joined by commas, e.g. '>=4.5, <5'."###;
+
+static MOD_HELP: &str = r###"Details:
+  SEL is one or more selections, each MID[@VERSION][:PACKAGE,...]. A
+  VERSION pins exactly that release: MID@1.0 does not pick 1.0.5.
+
+  A selection always includes the required packages of the mod, plus the
+  named packages or, without any, the recommended ones. Dependencies are
+  resolved and the -mod list is printed with the selected mods first and
+  the engine left out. A warning is printed when the stored mod_flag of
+  the first selection differs."###;
+
+static DOWNLOAD_HELP: &str = r###"Details:
+  Each file is tried from its mirrors in order; when all of them fail,
+  the mirrors are tried again up to 3 more times with growing pauses.
+  Partial downloads are kept as FILE.part and resumed. Files are checked
+  against their size and sha256 checksum; files already present and
+  intact are skipped."###;
+
+static INSTALL_HELP: &str = r###"Details:
+  Archives are downloaded into the cache (or reused from it) and
+  extracted into FS2DIR/MID-VERSION. Files named in a package filelist
+  are placed at their path in the mod folder; other archives are
+  extracted into the package folder. A mod.json with the installed
+  packages is written next to them, and the installed files are
+  recorded in neb-installed.json so that "uninstall" removes exactly
+  those."###;
+
+static OUTDATED_HELP: &str = r###"Details:
+  Installed mods are found by their mod.json files up to three levels
+  below FS2DIR, e.g. MID-VERSION/mod.json or MID/VERSION/mod.json. The
+  newest installed version of each mod is compared with the newest
+  release in the repo. DOWNLOAD is the size in bytes of the installed
+  packages in that release."###;
+
+static GC_HELP: &str = r###"Details:
+  An installed version is kept when it is the newest installed version
+  of its mod or when a kept mod depends on it; the newest installed
+  version matching each dependency is the one kept. The rest are listed
+  with their size and removed after confirmation."###;
+
+static VERIFY_HELP: &str = r###"Details:
+  VERSION is the version to check DIR against (default: latest). The
+  packages listed in DIR/mod.json are checked, or the required and
+  recommended packages when there is no mod.json. Files are hashed in
+  parallel and reported as MISSING, MODIFIED or EXTRA (present but in
+  no filelist). The exit status is 4 when a file is missing or
+  modified. EXTRA files, such as configs or saves written later, are
+  only reported and never fail the check."###;
+
+static WEB_HELP: &str = r###"Environment:
+  BIND  Address to listen on (default: web.bind setting or 127.0.0.1)
+  PORT  Port to listen on (default: web.port setting or 3200)
+
+Example:
+  BIND=0.0.0.0 PORT=3000 neb --db repo.db web"###;
+
+static CONFIG_HELP: &str = r###"Details:
+  Settings are read from ~/.config/neb/config.toml (or
+  $XDG_CONFIG_HOME/neb/config.toml), or from the file given with
+  --config. Env variables override the file, and command-line flags
+  override both. Paths may start with ~/ and are otherwise relative to
+  the directory of the config file. Every setting is optional:
+
+    db = "~/neb/repo.db"            # --db, NEB_DB
+    json = "~/neb/repo.json"        # JSON of fetch and update
+    download_dir = "~/Downloads"    # --dest of download
+    fs2_root = "~/games/fs2"        # --root of install, outdated, ...
+
+    [[sources]]                     # repo jsons, first one wins
+    name = "fsnebula"
+    url = "https://fsnebula.org/storage/repo.json"
+
+    [[sources]]
+    name = "internal"
+    path = "~/builds/repo.json"     # local file, not fetched
+
+    [web]
+    bind = "127.0.0.1"              # BIND
+    port = 3200                     # PORT"###;
+
+static COMPLETIONS_HELP: &str = r###"Examples:
+  neb completions bash > ~/.local/share/bash-completion/completions/neb
+  neb completions zsh > ~/.zfunc/_neb
+  neb completions fish > ~/.config/fish/completions/neb.fish"###;
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  fn rewrite(args: &str) -> String {
+    let args = args.split(' ').map(OsString::from).collect();
+    let args: Vec<String> = legacy_db_args(args)
+      .into_iter()
+      .map(|a| a.into_string().unwrap())
+      .collect();
+    args.join(" ")
   }
 
-  Path::new(arg)
-}
-
-fn as_string(arg: &String) -> &String {
-  if arg.is_empty() {
-    print_help();
-    process::exit(1);
+  #[test]
+  fn legacy_db_argument_becomes_db_option() {
+    let db = env::temp_dir().join(format!("neb-legacy-{}.db", process::id()));
+    fs::write(&db, b"SQLite format 3\0").unwrap();
+    let db = db.to_str().unwrap();
+
+    assert_eq!(
+      rewrite(&format!("neb -q --format json list {}", db)),
+      format!("neb --db {} -q --format json list", db)
+    );
+    assert_eq!(
+      rewrite(&format!("neb json {} str 1.6.0", db)),
+      format!("neb --db {} json str 1.6.0", db)
+    );
+    assert_eq!(
+      rewrite("neb update new.db repo.json"),
+      "neb --db new.db update repo.json"
+    );
+    assert_eq!(
+      rewrite("neb update :memory: -"),
+      "neb --db :memory: update -"
+    );
+    assert_eq!(
+      rewrite("neb update --dry-run new.db repo.json"),
+      "neb --db new.db update --dry-run repo.json"
+    );
+    assert_eq!(
+      rewrite("neb fetch-update old.db repo.json --force"),
+      "neb --db old.db fetch-update repo.json --force"
+    );
+    assert_eq!(
+      rewrite("neb fetch-update --jobs 2 --limit-rate=1M old.db repo.json"),
+      "neb --db old.db fetch-update --jobs 2 --limit-rate=1M repo.json"
+    );
+    assert_eq!(
+      rewrite(&format!("neb list --source main {}", db)),
+      format!("neb --db {} list --source main", db)
+    );
+    assert_eq!(
+      rewrite(&format!("neb search --type mod {} shivan", db)),
+      format!("neb --db {} search --type mod shivan", db)
+    );
+    assert_eq!(
+      rewrite(&format!("neb versions --match 1.6 {} str", db)),
+      format!("neb --db {} versions --match 1.6 str", db)
+    );
+    assert_eq!(
+      rewrite(&format!("neb web {}", db)),
+      format!("neb --db {} web", db)
+    );
+
+    for args in [
+      "neb update repo.json",
+      "neb update --dry-run repo.json",
+      "neb fetch-update --jobs 2 repo.json",
+      "neb list --source main",
+      "neb json str 1.6.0",
+      "neb --format json list",
+      "neb fetch old.json repo.json",
+      "neb list",
+    ] {
+      assert_eq!(rewrite(args), args);
+    }
+    fs::remove_file(db).unwrap();
   }
-
-  arg
 }
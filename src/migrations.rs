@@ -0,0 +1,363 @@
+use rusqlite::Connection;
+
+use crate::error::{Error, Result};
+use crate::knossos::KnossosMod;
+use crate::repo;
+use crate::status;
+
+/// Schema version written by this build into `PRAGMA user_version`.
+pub const SCHEMA_VERSION: u32 = 5;
+
+struct Migration {
+  version: u32,
+  description: &'static str,
+  stmts: &'static str,
+  after: Option<fn(&Connection) -> Result<()>>,
+}
+
+static MIGRATIONS: &[Migration] = &[
+  Migration {
+    version: 1,
+    description: "mods table",
+    stmts: CREATE_MODS_TABLE_STMTS,
+    after: None,
+  },
+  Migration {
+    version: 2,
+    description: "normalized package tables",
+    stmts: CREATE_PACKAGE_TABLE_STMTS,
+    after: Some(fill_package_tables),
+  },
+  Migration {
+    version: 3,
+    description: "metadata table",
+    stmts: CREATE_METADATA_TABLE_STMTS,
+    after: None,
+  },
+  Migration {
+    version: 4,
+    description: "full-text search index",
+    stmts: CREATE_SEARCH_TABLE_STMTS,
+    after: Some(fill_search_table),
+  },
+  Migration {
+    version: 5,
+    description: "source of each release",
+    stmts: ADD_SOURCE_COLUMN_STMTS,
+    after: None,
+  },
+];
+
+/// Returns the schema version of the database. Databases created before
+/// versioning was introduced report 0 but already have the `mods` table,
+/// so they are treated as version 1.
+pub fn schema_version(conn: &Connection) -> Result<u32> {
+  let version: u32 = conn.query_row("PRAGMA user_version;", [], |row| row.get(0))?;
+
+  if version == 0 && table_exists(conn, "mods")? {
+    return Ok(1);
+  }
+
+  Ok(version)
+}
+
+/// Errors if the database was written by a newer neb, otherwise reports
+/// whether `migrate` has work to do.
+pub fn needs_upgrade(conn: &Connection) -> Result<bool> {
+  let version = schema_version(conn)?;
+
+  if version > SCHEMA_VERSION {
+    return Err(Error::SchemaTooNew {
+      found: version,
+      supported: SCHEMA_VERSION,
+    });
+  }
+
+  Ok(version < SCHEMA_VERSION)
+}
+
+/// Brings the database up to `SCHEMA_VERSION` in a single transaction.
+pub fn migrate(conn: &Connection) -> Result<()> {
+  if !needs_upgrade(conn)? {
+    return Ok(());
+  }
+
+  let from = schema_version(conn)?;
+  if from == 0 {
+    status!("==> Creating DB...");
+  } else {
+    status!(
+      "==> Migrating DB from schema version {} to {}...",
+      from,
+      SCHEMA_VERSION
+    );
+  }
+
+  let tx = conn.unchecked_transaction()?;
+
+  for migration in MIGRATIONS.iter().filter(|m| m.version > from) {
+    if from > 0 {
+      status!("    {}: {}", migration.version, migration.description);
+    }
+
+    tx.execute_batch(migration.stmts)?;
+
+    if let Some(after) = migration.after {
+      after(&tx)?;
+    }
+  }
+
+  tx.execute_batch(&format!("PRAGMA user_version = {};", SCHEMA_VERSION))?;
+  tx.commit()?;
+  Ok(())
+}
+
+fn table_exists(conn: &Connection, name: &str) -> Result<bool> {
+  let exists = conn.query_row(TABLE_EXISTS_STMT, [name], |row| row.get(0))?;
+  Ok(exists)
+}
+
+fn fill_package_tables(conn: &Connection) -> Result<()> {
+  // The tables may predate schema versioning; refill them from scratch.
+  conn.execute("DELETE FROM packages;", [])?;
+
+  let mut select = conn.prepare(LIST_MOD_JSON_STMT)?;
+  let mut rows = select.query([])?;
+
+  while let Some(row) = rows.next()? {
+    let mod_id: i64 = row.get(0)?;
+    let mid: String = row.get(1)?;
+    let mod_json: String = row.get(2)?;
+    let km = KnossosMod::from_json_str(&mid, &mod_json)?;
+    repo::insert_packages(conn, mod_id, &km)?;
+  }
+
+  Ok(())
+}
+
+fn fill_search_table(conn: &Connection) -> Result<()> {
+  let mut select = conn.prepare(LIST_MOD_JSON_STMT)?;
+  let mut rows = select.query([])?;
+
+  while let Some(row) = rows.next()? {
+    let mod_id: i64 = row.get(0)?;
+    let mid: String = row.get(1)?;
+    let mod_json: String = row.get(2)?;
+    let km = KnossosMod::from_json_str(&mid, &mod_json)?;
+    repo::index_mod(conn, mod_id, &km)?;
+  }
+
+  Ok(())
+}
+
+static TABLE_EXISTS_STMT: &str = r#"
+SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?);
+"#;
+
+static CREATE_MODS_TABLE_STMTS: &str = r#"
+CREATE TABLE IF NOT EXISTS mods (
+  id               INTEGER PRIMARY KEY,
+  mid              TEXT NOT NULL,
+  title            TEXT NOT NULL,
+  tile             TEXT,
+  version          TEXT NOT NULL,
+  first_release    DATE,
+  last_update      DATE,
+  mod_json         JSON NOT NULL
+);
+CREATE UNIQUE INDEX mods_mid_version_unique_index ON mods (mid, version);
+CREATE INDEX mods_title_index ON mods (title);
+CREATE INDEX mods_first_release ON mods (first_release);
+CREATE INDEX mods_last_update ON mods (last_update);
+"#;
+
+static CREATE_PACKAGE_TABLE_STMTS: &str = r#"
+CREATE TABLE IF NOT EXISTS packages (
+  id               INTEGER PRIMARY KEY,
+  mod_id           INTEGER NOT NULL REFERENCES mods (id) ON DELETE CASCADE,
+  name             TEXT NOT NULL,
+  status           TEXT,
+  notes            TEXT,
+  folder           TEXT,
+  environment      TEXT,
+  is_vp            BOOLEAN
+);
+CREATE INDEX IF NOT EXISTS packages_mod_id_index ON packages (mod_id);
+
+CREATE TABLE IF NOT EXISTS package_files (
+  id                 INTEGER PRIMARY KEY,
+  mod_id             INTEGER NOT NULL REFERENCES mods (id) ON DELETE CASCADE,
+  package_id         INTEGER NOT NULL REFERENCES packages (id) ON DELETE CASCADE,
+  filename           TEXT NOT NULL,
+  dest               TEXT,
+  checksum_algorithm TEXT NOT NULL,
+  checksum           TEXT NOT NULL,
+  filesize           INTEGER NOT NULL,
+  urls               JSON NOT NULL
+);
+CREATE INDEX IF NOT EXISTS package_files_mod_id_index ON package_files (mod_id);
+CREATE INDEX IF NOT EXISTS package_files_package_id_index ON package_files (package_id);
+CREATE INDEX IF NOT EXISTS package_files_checksum_index ON package_files (checksum);
+
+CREATE TABLE IF NOT EXISTS package_dependencies (
+  id               INTEGER PRIMARY KEY,
+  mod_id           INTEGER NOT NULL REFERENCES mods (id) ON DELETE CASCADE,
+  package_id       INTEGER NOT NULL REFERENCES packages (id) ON DELETE CASCADE,
+  dep_mid          TEXT NOT NULL,
+  version          TEXT,
+  packages         JSON NOT NULL
+);
+CREATE INDEX IF NOT EXISTS package_dependencies_mod_id_index ON package_dependencies (mod_id);
+CREATE INDEX IF NOT EXISTS package_dependencies_package_id_index ON package_dependencies (package_id);
+CREATE INDEX IF NOT EXISTS package_dependencies_dep_mid_index ON package_dependencies (dep_mid);
+
+CREATE TABLE IF NOT EXISTS executables (
+  id               INTEGER PRIMARY KEY,
+  mod_id           INTEGER NOT NULL REFERENCES mods (id) ON DELETE CASCADE,
+  package_id       INTEGER NOT NULL REFERENCES packages (id) ON DELETE CASCADE,
+  file             TEXT NOT NULL,
+  label            TEXT,
+  properties       JSON
+);
+CREATE INDEX IF NOT EXISTS executables_mod_id_index ON executables (mod_id);
+CREATE INDEX IF NOT EXISTS executables_package_id_index ON executables (package_id);
+
+CREATE TABLE IF NOT EXISTS filelist (
+  id                 INTEGER PRIMARY KEY,
+  mod_id             INTEGER NOT NULL REFERENCES mods (id) ON DELETE CASCADE,
+  package_id         INTEGER NOT NULL REFERENCES packages (id) ON DELETE CASCADE,
+  filename           TEXT NOT NULL,
+  archive            TEXT NOT NULL,
+  orig_name          TEXT,
+  checksum_algorithm TEXT NOT NULL,
+  checksum           TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS filelist_mod_id_index ON filelist (mod_id);
+CREATE INDEX IF NOT EXISTS filelist_package_id_index ON filelist (package_id);
+CREATE INDEX IF NOT EXISTS filelist_filename_index ON filelist (filename);
+"#;
+
+static CREATE_METADATA_TABLE_STMTS: &str = r#"
+CREATE TABLE IF NOT EXISTS metadata (
+  key              TEXT PRIMARY KEY,
+  value            TEXT
+);
+"#;
+
+// The rowid of each entry is the id of its row in `mods`.
+static CREATE_SEARCH_TABLE_STMTS: &str = r#"
+CREATE VIRTUAL TABLE IF NOT EXISTS mods_search USING fts5 (
+  mid,
+  title,
+  description,
+  notes,
+  packages,
+  tokenize = 'unicode61 remove_diacritics 2'
+);
+DELETE FROM mods_search;
+"#;
+
+// Releases stored before named sources all came from FSNebula.
+static ADD_SOURCE_COLUMN_STMTS: &str = r#"
+ALTER TABLE mods ADD COLUMN source TEXT NOT NULL DEFAULT 'fsnebula';
+CREATE INDEX mods_source_index ON mods (source);
+"#;
+
+static LIST_MOD_JSON_STMT: &str = r#"
+SELECT id, mid, mod_json FROM mods
+"#;
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use std::fs;
+  use std::path::Path;
+
+  static MOD_JSON: &str = r#"{
+    "id": "MVPS", "title": "MediaVPs", "version": "4.6.0",
+    "packages": [{"name": "Core", "status": "required", "files": [], "filelist": []}]
+  }"#;
+
+  fn pre_versioning_db() -> Connection {
+    pre_versioning_db_at(Path::new(":memory:"))
+  }
+
+  /// A database as written before schema versioning: only the mods
+  /// table and `user_version` 0.
+  pub(crate) fn pre_versioning_db_at(path: &Path) -> Connection {
+    let conn = Connection::open(path).unwrap();
+    conn.execute_batch(CREATE_MODS_TABLE_STMTS).unwrap();
+    conn
+      .execute(
+        "INSERT INTO mods (mid, title, version, mod_json) VALUES ('MVPS', 'MediaVPs', '4.6.0', ?)",
+        [MOD_JSON],
+      )
+      .unwrap();
+    conn
+  }
+
+  fn count(conn: &Connection, table: &str) -> i64 {
+    let sql = format!("SELECT COUNT(*) FROM {}", table);
+    conn.query_row(&sql, [], |row| row.get(0)).unwrap()
+  }
+
+  #[test]
+  fn migrates_pre_versioning_db_to_current() {
+    let conn = pre_versioning_db();
+    assert_eq!(schema_version(&conn).unwrap(), 1);
+    assert!(needs_upgrade(&conn).unwrap());
+
+    migrate(&conn).unwrap();
+
+    assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+    assert!(!needs_upgrade(&conn).unwrap());
+    assert_eq!(count(&conn, "packages"), 1);
+    assert_eq!(count(&conn, "mods_search"), 1);
+    let source: String = conn
+      .query_row("SELECT source FROM mods", [], |row| row.get(0))
+      .unwrap();
+    assert_eq!(source, "fsnebula");
+  }
+
+  #[test]
+  fn migrating_twice_changes_nothing() {
+    let conn = pre_versioning_db();
+    migrate(&conn).unwrap();
+    migrate(&conn).unwrap();
+    assert_eq!(count(&conn, "packages"), 1);
+  }
+
+  #[test]
+  fn rejects_newer_schema() {
+    let conn = Connection::open_in_memory().unwrap();
+    migrate(&conn).unwrap();
+    conn
+      .execute_batch(&format!("PRAGMA user_version = {};", SCHEMA_VERSION + 1))
+      .unwrap();
+
+    assert!(matches!(
+      migrate(&conn),
+      Err(Error::SchemaTooNew { found, supported })
+        if found == SCHEMA_VERSION + 1 && supported == SCHEMA_VERSION
+    ));
+  }
+
+  #[test]
+  fn read_only_open_does_not_migrate() {
+    let path = std::env::temp_dir().join(format!("neb-migrations-{}.db", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let conn = Connection::open(&path).unwrap();
+    conn.execute_batch(CREATE_MODS_TABLE_STMTS).unwrap();
+    drop(conn);
+
+    assert!(matches!(
+      repo::open_read_only(&path),
+      Err(Error::SchemaOutdated { found: 1, .. })
+    ));
+    let conn = Connection::open(&path).unwrap();
+    assert_eq!(schema_version(&conn).unwrap(), 1);
+    assert!(!table_exists(&conn, "packages").unwrap());
+
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,382 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use indicatif::ProgressDrawTarget;
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+  #[default]
+  Text,
+  Json,
+  Jsonl,
+  Csv,
+  Tsv,
+}
+
+impl Format {
+  pub const NAMES: [&'static str; 5] = ["text", "json", "jsonl", "csv", "tsv"];
+}
+
+impl FromStr for Format {
+  type Err = Error;
+
+  fn from_str(text: &str) -> Result<Format> {
+    match text {
+      "text" => Ok(Format::Text),
+      "json" => Ok(Format::Json),
+      "jsonl" => Ok(Format::Jsonl),
+      "csv" => Ok(Format::Csv),
+      "tsv" => Ok(Format::Tsv),
+      _ => Err(Error::InvalidFormat(text.to_string())),
+    }
+  }
+}
+
+/// How much to print besides the results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Verbosity {
+  /// Only results, warnings and errors.
+  Quiet,
+  #[default]
+  Normal,
+  /// Also the details of each step, such as every downloaded file.
+  Verbose,
+}
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+static VERBOSITY: OnceLock<Verbosity> = OnceLock::new();
+
+/// Sets the output format of every command for the rest of the process.
+/// It is process-wide because progress messages deep inside update,
+/// download and install have to move out of the way of machine-readable
+/// output too.
+///
+/// Only the first call takes effect: a process, tests included, has one
+/// format, and code running inside another program cannot switch it per
+/// call.
+pub fn set_format(format: Format) {
+  let _ = FORMAT.set(format);
+}
+
+pub fn format() -> Format {
+  FORMAT.get().copied().unwrap_or_default()
+}
+
+pub fn is_text() -> bool {
+  format() == Format::Text
+}
+
+pub fn set_verbosity(verbosity: Verbosity) {
+  let _ = VERBOSITY.set(verbosity);
+}
+
+pub fn verbosity() -> Verbosity {
+  VERBOSITY.get().copied().unwrap_or_default()
+}
+
+pub fn is_quiet() -> bool {
+  verbosity() == Verbosity::Quiet
+}
+
+pub fn is_verbose() -> bool {
+  verbosity() == Verbosity::Verbose
+}
+
+/// Where progress bars draw: stderr, or nowhere with `--quiet`.
+pub fn progress_target() -> ProgressDrawTarget {
+  if is_quiet() {
+    ProgressDrawTarget::hidden()
+  } else {
+    ProgressDrawTarget::stderr()
+  }
+}
+
+/// Prints a progress message: on stdout for text output, on stderr when
+/// stdout carries machine-readable output. `--quiet` drops it.
+#[macro_export]
+macro_rules! status {
+  ($($arg:tt)*) => {
+    if $crate::output::is_quiet() {
+    } else if $crate::output::is_text() {
+      println!($($arg)*);
+    } else {
+      eprintln!($($arg)*);
+    }
+  };
+}
+
+/// Prints a detail message on stderr with `--verbose`.
+#[macro_export]
+macro_rules! detail {
+  ($($arg:tt)*) => {
+    if $crate::output::is_verbose() {
+      eprintln!($($arg)*);
+    }
+  };
+}
+
+/// Prints the results of a command in the selected format. `text` does
+/// the printing for the text format.
+pub fn print_records<T: Serialize + Default>(
+  records: &[T],
+  text: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+  match format() {
+    Format::Text => text(),
+    format => {
+      print!("{}", render_records(format, records, &T::default())?);
+      Ok(())
+    }
+  }
+}
+
+/// Like `print_records` for a command with a single result, which JSON
+/// prints as an object rather than an array.
+pub fn print_record<T: Serialize>(record: &T, text: impl FnOnce() -> Result<()>) -> Result<()> {
+  match format() {
+    Format::Text => text(),
+    format => {
+      print!("{}", render_record(format, record)?);
+      Ok(())
+    }
+  }
+}
+
+fn render_record<T: Serialize>(format: Format, record: &T) -> Result<String> {
+  match format {
+    Format::Json => Ok(format!("{}\n", serde_json::to_string_pretty(record)?)),
+    _ => render_records(format, std::slice::from_ref(record), record),
+  }
+}
+
+/// The records in a machine-readable format, with `Text`, which only the
+/// commands themselves can print, taken as JSON. `empty` is a record with
+/// default values that gives CSV and TSV their header when there are no
+/// records.
+fn render_records<T: Serialize>(format: Format, records: &[T], empty: &T) -> Result<String> {
+  let mut out = String::new();
+
+  match format {
+    Format::Text | Format::Json => {
+      out.push_str(&serde_json::to_string_pretty(records)?);
+      out.push('\n');
+    }
+    Format::Jsonl => {
+      for record in records {
+        out.push_str(&serde_json::to_string(record)?);
+        out.push('\n');
+      }
+    }
+    Format::Csv | Format::Tsv => render_table(&mut out, format, records, empty)?,
+  }
+
+  Ok(out)
+}
+
+/// One row per record with a header of field names. Nested arrays and
+/// objects are written as JSON.
+fn render_table<T: Serialize>(
+  out: &mut String,
+  format: Format,
+  records: &[T],
+  empty: &T,
+) -> Result<()> {
+  let escape = match format {
+    Format::Tsv => escape_tsv,
+    _ => escape_csv,
+  };
+  let separator = match format {
+    Format::Tsv => "\t",
+    _ => ",",
+  };
+  let mut line = |cells: Vec<String>| {
+    out.push_str(&cells.join(separator));
+    out.push('\n');
+  };
+
+  let header = ordered_fields(records.first().unwrap_or(empty))?;
+  line(header.iter().map(|(name, _)| escape(name)).collect());
+
+  for record in records {
+    let fields = ordered_fields(record)?;
+    line(
+      fields
+        .iter()
+        .map(|(_, value)| escape(&cell(value)))
+        .collect(),
+    );
+  }
+
+  Ok(())
+}
+
+/// The fields of `record` in declaration order, which a round trip
+/// through `serde_json::Value` would sort by name. Anything but a struct
+/// or map becomes a single `value` field.
+fn ordered_fields<T: Serialize>(record: &T) -> Result<Vec<(String, Value)>> {
+  let json = serde_json::to_string(record)?;
+  match serde_json::from_str::<OrderedFields>(&json) {
+    Ok(OrderedFields(fields)) => Ok(fields),
+    Err(_) => Ok(vec![("value".to_string(), serde_json::from_str(&json)?)]),
+  }
+}
+
+struct OrderedFields(Vec<(String, Value)>);
+
+impl<'de> Deserialize<'de> for OrderedFields {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    struct FieldsVisitor;
+
+    impl<'de> Visitor<'de> for FieldsVisitor {
+      type Value = OrderedFields;
+
+      fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an object")
+      }
+
+      fn visit_map<A: MapAccess<'de>>(
+        self,
+        mut map: A,
+      ) -> std::result::Result<OrderedFields, A::Error> {
+        let mut fields = Vec::new();
+        while let Some(field) = map.next_entry()? {
+          fields.push(field);
+        }
+        Ok(OrderedFields(fields))
+      }
+    }
+
+    deserializer.deserialize_map(FieldsVisitor)
+  }
+}
+
+fn cell(value: &Value) -> String {
+  match value {
+    Value::Null => String::new(),
+    Value::String(s) => s.clone(),
+    Value::Bool(_) | Value::Number(_) => value.to_string(),
+    Value::Array(_) | Value::Object(_) => value.to_string(),
+  }
+}
+
+fn escape_csv(field: &str) -> String {
+  if field.contains([',', '"', '\n', '\r']) {
+    format!("\"{}\"", field.replace('"', "\"\""))
+  } else {
+    field.to_string()
+  }
+}
+
+/// TSV fields cannot hold tabs or line breaks, so they are written as
+/// backslash escapes.
+fn escape_tsv(field: &str) -> String {
+  field
+    .replace('\\', "\\\\")
+    .replace('\t', "\\t")
+    .replace('\n', "\\n")
+    .replace('\r', "\\r")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  /// Fields out of alphabetical order, which columns must keep.
+  #[derive(Default, Serialize)]
+  struct Row {
+    name: String,
+    size: u64,
+    note: Option<String>,
+    alpha: Vec<String>,
+  }
+
+  fn row(name: &str, size: u64, note: Option<&str>, alpha: &[&str]) -> Row {
+    Row {
+      name: name.to_string(),
+      size,
+      note: note.map(String::from),
+      alpha: alpha.iter().map(|a| a.to_string()).collect(),
+    }
+  }
+
+  fn render(format: Format, records: &[Row]) -> String {
+    render_records(format, records, &Row::default()).unwrap()
+  }
+
+  #[test]
+  fn csv_keeps_field_order_and_quotes() {
+    let rows = [
+      row("MediaVPs, 2024", 10, Some("say \"hi\""), &["a", "b"]),
+      row("two\nlines", 0, None, &[]),
+    ];
+
+    assert_eq!(
+      render(Format::Csv, &rows),
+      concat!(
+        "name,size,note,alpha\n",
+        "\"MediaVPs, 2024\",10,\"say \"\"hi\"\"\",\"[\"\"a\"\",\"\"b\"\"]\"\n",
+        "\"two\nlines\",0,,[]\n",
+      )
+    );
+  }
+
+  #[test]
+  fn tsv_escapes_tabs_and_line_breaks() {
+    let rows = [row("a\tb\\c", 1, Some("x\r\ny"), &[])];
+
+    assert_eq!(
+      render(Format::Tsv, &rows),
+      "name\tsize\tnote\talpha\na\\tb\\\\c\t1\tx\\r\\ny\t[]\n"
+    );
+  }
+
+  #[test]
+  fn empty_records_keep_the_header() {
+    assert_eq!(render(Format::Csv, &[]), "name,size,note,alpha\n");
+    assert_eq!(render(Format::Tsv, &[]), "name\tsize\tnote\talpha\n");
+    assert_eq!(render(Format::Json, &[]), "[]\n");
+    assert_eq!(render(Format::Jsonl, &[]), "");
+  }
+
+  #[test]
+  fn jsonl_prints_one_record_per_line() {
+    let rows = [row("a", 1, None, &[]), row("b", 2, Some("n"), &["x"])];
+
+    assert_eq!(
+      render(Format::Jsonl, &rows),
+      concat!(
+        r#"{"name":"a","size":1,"note":null,"alpha":[]}"#,
+        "\n",
+        r#"{"name":"b","size":2,"note":"n","alpha":["x"]}"#,
+        "\n",
+      )
+    );
+  }
+
+  #[test]
+  fn single_record_is_an_object_in_json() {
+    let record = row("a", 1, None, &[]);
+
+    assert_eq!(
+      serde_json::from_str::<Value>(&render_record(Format::Json, &record).unwrap()).unwrap(),
+      json!({"name": "a", "size": 1, "note": null, "alpha": []})
+    );
+    assert_eq!(
+      render_record(Format::Csv, &record).unwrap(),
+      "name,size,note,alpha\na,1,,[]\n"
+    );
+  }
+
+  #[test]
+  fn values_that_are_not_objects_are_one_field() {
+    let fields = ordered_fields(&json!("4.6.0")).unwrap();
+    assert_eq!(fields, [("value".to_string(), json!("4.6.0"))]);
+  }
+}
@@ -1,15 +1,24 @@
-use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, IsTerminal, Read};
 use std::path::Path;
-use std::process;
+use std::time::Duration;
 
 use indicatif::{ProgressBar, ProgressStyle};
-use rusqlite::{named_params, CachedStatement, Connection, OpenFlags, Row};
+use rusqlite::backup::Backup;
+use rusqlite::{
+  named_params, CachedStatement, Connection, OpenFlags, OptionalExtension, Row, ToSql, Transaction,
+  TransactionBehavior,
+};
 use serde::Serialize;
 use serde_json::{json, Value};
 
+use crate::error::{Error, Result};
+use crate::knossos::{KnossosMod, ModFile, Package};
+use crate::version::VersionSpec;
+use crate::{detail, status};
+use crate::{download_engine, migrations, output, version};
+
 #[derive(Debug, PartialEq, Serialize)]
 pub struct Mod {
   pub mid: String,
@@ -20,284 +29,819 @@ pub struct Mod {
   pub first_release: String,
   pub last_update: String,
   pub mod_json: String,
+  pub source: String,
 }
 
-#[derive(Debug, PartialEq)]
-struct FileMeta {
-  name: String,
-  size: u64,
-  sha256: String,
+// Rows printed by the commands for machine-readable formats. The field
+// names are part of the output format, so keep them stable. `Default`
+// gives CSV and TSV their header when there are no rows.
+
+#[derive(Default, Serialize)]
+struct ModRecord<'a> {
+  mid: &'a str,
+  version: &'a str,
+  title: &'a str,
+  tile: &'a str,
+  source: &'a str,
 }
 
-fn ensure_sqlite3_db(path: &Path) {
-  let mut f = File::open(path).unwrap();
-  let mut buffer = [0; 15];
+#[derive(Default, Serialize)]
+struct VersionRecord<'a> {
+  version: &'a str,
+  stable: bool,
+}
 
-  f.read_exact(&mut buffer).unwrap_or_else(|_| {
-    println!("Not a DB file: {}", path.display());
-    process::exit(1);
-  });
+#[derive(Serialize)]
+struct CmdlineRecord<'a> {
+  mid: &'a str,
+  version: &'a str,
+  cmdline: &'a str,
+}
+
+#[derive(Serialize)]
+struct ModFlagRecord<'a> {
+  mid: &'a str,
+  version: &'a str,
+  mod_flag: &'a [String],
+}
+
+#[derive(Default, Serialize)]
+struct ChecksumRecord<'a> {
+  filename: &'a str,
+  algorithm: &'a str,
+  checksum: &'a str,
+}
+
+#[derive(Default, Serialize)]
+struct FileSizeRecord<'a> {
+  filename: &'a str,
+  filesize: u64,
+}
+
+/// Marks the start and end of a matched word in `SearchResult::snippet`.
+pub const SNIPPET_START: char = '\u{2}';
+pub const SNIPPET_END: char = '\u{3}';
+
+#[derive(Debug, PartialEq, Default, Serialize)]
+pub struct SearchResult {
+  pub mid: String,
+  pub version: String,
+  pub title: String,
+  pub tile: String,
+  /// The best matching part of the indexed text, with matches marked by
+  /// `SNIPPET_START` and `SNIPPET_END`.
+  pub snippet: String,
+}
+
+/// Conditions the newest release of a mod has to meet to show up in
+/// `search_mods`, with `size` counting the files of its required and
+/// recommended packages only. Ranges are `FROM..TO` with either end
+/// optional, or a single value meaning `VALUE..VALUE`. Date bounds may be
+/// partial, so `2023..2023` covers the whole year.
+#[derive(Debug, Default, Clone)]
+pub struct SearchFilter {
+  pub mod_type: Option<String>,
+  pub stability: Option<String>,
+  pub parent: Option<String>,
+  pub first_release: (Option<String>, Option<String>),
+  pub last_update: (Option<String>, Option<String>),
+  pub size: (Option<u64>, Option<u64>),
+}
+
+impl SearchFilter {
+  /// Filter names as used by `set`, the `search` options and the web
+  /// query parameters.
+  pub const NAMES: [&'static str; 6] =
+    ["type", "stability", "parent", "released", "updated", "size"];
 
-  if &buffer != b"SQLite format 3" {
-    println!("Not a DB file: {}", path.display());
-    process::exit(1);
+  pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+    let invalid = || Error::InvalidFilter {
+      name: name.to_string(),
+      value: value.to_string(),
+    };
+    let value = value.trim();
+    if value.is_empty() {
+      return Err(invalid());
+    }
+
+    match name {
+      "type" => self.mod_type = Some(value.to_string()),
+      "stability" => self.stability = Some(value.to_string()),
+      "parent" => self.parent = Some(value.to_string()),
+      "released" => {
+        self.first_release = parse_range(value, |v| Some(v.to_string())).ok_or_else(invalid)?
+      }
+      "updated" => {
+        self.last_update = parse_range(value, |v| Some(v.to_string())).ok_or_else(invalid)?
+      }
+      "size" => self.size = parse_range(value, download_engine::parse_size).ok_or_else(invalid)?,
+      _ => return Err(invalid()),
+    }
+    Ok(())
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.mod_type.is_none()
+      && self.stability.is_none()
+      && self.parent.is_none()
+      && self.first_release == (None, None)
+      && self.last_update == (None, None)
+      && self.size == (None, None)
   }
 }
 
-pub fn open_read_only(path: &Path) -> Connection {
-  if path.exists() {
-    ensure_sqlite3_db(path);
-  } else {
-    println!("File not found: {}", path.display());
-    process::exit(1);
+fn parse_range<T>(text: &str, parse: impl Fn(&str) -> Option<T>) -> Option<(Option<T>, Option<T>)> {
+  let bound = |v: &str| {
+    let v = v.trim();
+    if v.is_empty() {
+      Some(None)
+    } else {
+      parse(v).map(Some)
+    }
+  };
+
+  match text.split_once("..") {
+    Some((from, to)) => Some((bound(from)?, bound(to)?)),
+    None => Some((Some(parse(text)?), Some(parse(text)?))),
   }
+}
 
-  Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap_or_else(|_| {
-    println!("DB connection open error: {}", path.display());
-    process::exit(1);
-  })
+/// Narrows down the output of `versions`.
+#[derive(Debug, Default)]
+pub struct VersionQuery {
+  pub spec: Option<VersionSpec>,
+  pub stable_only: bool,
+  /// Print only the newest remaining version, failing if there is none.
+  pub latest: bool,
 }
 
-pub fn open_read_write(path: &Path) -> Connection {
-  let path_exists = path.exists();
-  let need_create = !path_exists;
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DbInfo {
+  pub schema_version: u32,
+  pub last_update: Option<String>,
+  pub mods: u64,
+  pub releases: u64,
+  pub packages: u64,
+  pub package_files: u64,
+  pub package_dependencies: u64,
+  pub executables: u64,
+  pub filelist: u64,
+}
 
-  if path_exists {
-    ensure_sqlite3_db(path);
+impl Mod {
+  pub fn knossos_mod(&self) -> Result<KnossosMod> {
+    KnossosMod::from_json_str(&self.mid, &self.mod_json)
   }
+}
 
-  let conn = Connection::open(&path).unwrap_or_else(|_| {
-    println!("DB connection open error: {}", path.display());
-    process::exit(1);
-  });
+pub fn ensure_sqlite3_db(path: &Path) -> Result<()> {
+  let mut f = File::open(path).map_err(Error::io(path))?;
+  let mut buffer = [0; 15];
 
-  if need_create {
-    create_db(&conn);
+  if f.read_exact(&mut buffer).is_err() || &buffer != b"SQLite format 3" {
+    return Err(Error::NotADatabase(path.to_path_buf()));
   }
 
-  conn
+  Ok(())
 }
 
-fn create_db(conn: &Connection) {
-  println!("==> Creating DB...");
+pub fn open_read_only(path: &Path) -> Result<Connection> {
+  if path.exists() {
+    ensure_sqlite3_db(path)?;
+  } else {
+    return Err(Error::FileNotFound(path.to_path_buf()));
+  }
 
-  if conn.execute_batch(CREATE_TABLE_STMTS).is_err() {
-    println!("DB create table error");
-    process::exit(1);
+  let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+  // Only update writes to the database, migrations included.
+  if migrations::needs_upgrade(&conn)? {
+    return Err(Error::SchemaOutdated {
+      found: migrations::schema_version(&conn)?,
+      current: migrations::SCHEMA_VERSION,
+    });
   }
+
+  Ok(conn)
 }
 
-fn get_all_mod_ids_set(conn: &Connection) -> HashSet<[String; 2]> {
+/// Opens the database at `path` for an update that writes nothing. A
+/// missing database is planned against as an empty one and one with an
+/// older schema against a migrated copy in memory, leaving the file as it
+/// is.
+pub fn open_for_dry_run(path: &Path) -> Result<Connection> {
+  if !path.exists() {
+    return open_read_write(Path::new(":memory:"));
+  }
+
+  ensure_sqlite3_db(path)?;
+  let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+  if !migrations::needs_upgrade(&conn)? {
+    return Ok(conn);
+  }
+
+  status!(
+    "==> Schema version {} is outdated; planning against a migrated copy",
+    migrations::schema_version(&conn)?
+  );
+  let mut copy = Connection::open_in_memory()?;
+  Backup::new(&conn, &mut copy)?.run_to_completion(1000, Duration::ZERO, None)?;
+  migrations::migrate(&copy)?;
+
+  Ok(copy)
+}
+
+pub fn open_read_write(path: &Path) -> Result<Connection> {
+  if path.exists() {
+    ensure_sqlite3_db(path)?;
+  }
+
+  let conn = Connection::open(path)?;
+  // WAL lets readers such as the web view go on during an update, and
+  // with it, NORMAL sync can lose the last commit on power loss but
+  // never corrupts the database.
+  conn.execute_batch(
+    "PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;",
+  )?;
+  migrations::migrate(&conn)?;
+
+  Ok(conn)
+}
+
+fn get_all_mod_ids_set(conn: &Connection) -> Result<HashSet<[String; 2]>> {
   let mut set = HashSet::new();
 
-  let mut select = conn.prepare(LIST_MID_AND_VERSION_STMT).unwrap();
-  let mut rows = select.query([]).unwrap();
+  let mut select = conn.prepare(LIST_MID_AND_VERSION_STMT)?;
+  let mut rows = select.query([])?;
 
-  while let Some(row) = rows.next().unwrap() {
-    let mid: String = row.get(0).unwrap();
-    let version: String = row.get(1).unwrap();
+  while let Some(row) = rows.next()? {
+    let mid: String = row.get(0)?;
+    let version: String = row.get(1)?;
     set.insert([mid, version]);
   }
 
-  set
+  Ok(set)
 }
 
-fn delete_mods(
-  conn: &Connection,
-  stored_ids: &HashSet<[String; 2]>,
-  json_ids: &HashSet<[String; 2]>,
-) {
-  let mut select_stmt = prepare_select_statement(conn);
-  let mut delete_stmt = conn.prepare_cached(DELETE_STMT).unwrap_or_else(|_| {
-    println!("Prepare delete statement error");
-    process::exit(1);
-  });
+/// Brings the database in line with the mods of one or more sources,
+/// given one mod at a time so that a repo json never has to be in memory
+/// as a whole. Sources are added in priority order: a release found in
+/// more than one is taken from the first.
+///
+/// All changes are made in one transaction, committed by `finish`. It is
+/// rolled back if the updater is dropped before, e.g. after an error.
+/// With `dry_run`, nothing is written and `finish` prints the changes
+/// that would be made instead.
+pub struct Updater<'a> {
+  tx: Transaction<'a>,
+  select_stmt: CachedStatement<'a>,
+  insert_stmt: CachedStatement<'a>,
+  update_stmt: CachedStatement<'a>,
+  delete_stmt: CachedStatement<'a>,
+  stored_ids: HashSet<[String; 2]>,
+  first_update: bool,
+  dry_run: bool,
+  /// Releases seen so far and the source each was taken from.
+  json_ids: HashMap<[String; 2], String>,
+  plan: Vec<PlanRecord>,
+  progress_bar: ProgressBar,
+}
 
-  for to_delete in stored_ids.difference(json_ids) {
-    let m = Mod {
-      mid: to_delete[0].to_string(),
-      version: to_delete[1].to_string(),
-      versions: Vec::new(),
-      title: "".to_string(),
-      tile: "".to_string(),
-      first_release: "".to_string(),
-      last_update: "".to_string(),
-      mod_json: "".to_string(),
-    };
+#[derive(Default, Serialize)]
+struct PlanRecord {
+  action: &'static str,
+  mid: String,
+  version: String,
+  title: String,
+  source: String,
+}
 
-    let m_stored = select_mod(&mut select_stmt, &m).unwrap();
+impl PlanRecord {
+  fn new(action: &'static str, m: &Mod) -> PlanRecord {
+    PlanRecord {
+      action,
+      mid: m.mid.clone(),
+      version: m.version.clone(),
+      title: m.title.clone(),
+      source: m.source.clone(),
+    }
+  }
 
-    println!("[DELETE] {} ({})", m_stored.title, m_stored.version);
-    delete_stmt
-      .execute(named_params! { ":mid": m.mid, ":version": m.version })
-      .unwrap();
+  fn line(&self) -> String {
+    let label = match self.action {
+      "add" => "[ADD]   ",
+      "update" => "[UPDATE]",
+      _ => "[DELETE]",
+    };
+    format!("{} {} ({})", label, self.title, self.version)
   }
 }
 
-pub fn update(conn: &Connection, mods_json: &Vec<Value>) {
-  println!("==> Updating local mods database...");
+impl<'a> Updater<'a> {
+  pub fn new(conn: &'a Connection, dry_run: bool) -> Result<Updater<'a>> {
+    status!("==> Updating local mods database...");
 
-  let mut select_stmt = prepare_select_statement(conn);
-  let mut insert_stmt = prepare_insert_statement(conn);
-  let mut update_stmt = prepare_update_statement(conn);
+    // Start writing right away, so that the stored releases cannot change
+    // before the update is committed.
+    let behavior = match dry_run {
+      true => TransactionBehavior::Deferred,
+      false => TransactionBehavior::Immediate,
+    };
+    let tx = Transaction::new_unchecked(conn, behavior)?;
+    let stored_ids = get_all_mod_ids_set(&tx)?;
+    Ok(Updater {
+      tx,
+      select_stmt: conn.prepare_cached(SELECT_STMT)?,
+      insert_stmt: conn.prepare_cached(INSERT_STMT)?,
+      update_stmt: conn.prepare_cached(UPDATE_STMT)?,
+      delete_stmt: conn.prepare_cached(DELETE_STMT)?,
+      first_update: stored_ids.is_empty(),
+      stored_ids,
+      dry_run,
+      json_ids: HashMap::new(),
+      plan: Vec::new(),
+      progress_bar: create_progress_bar(),
+    })
+  }
 
-  let stored_ids = get_all_mod_ids_set(conn);
-  let first_update = stored_ids == HashSet::new();
-  let mut json_ids = HashSet::new();
-  let progress_bar = create_progress_bar(mods_json);
+  /// Adds, updates or, if a source before `source` has it, skips the
+  /// release in `mod_json`.
+  pub fn add(&mut self, source: &str, mod_json: &Value) -> Result<()> {
+    if self.first_update && !self.dry_run {
+      self.progress_bar.inc(1);
+    }
 
-  for mod_json in mods_json {
-    let m = mod_from_serde_json(mod_json);
-    json_ids.insert([m.mid.to_string(), m.version.to_string()]);
+    let km = KnossosMod::from_value(mod_json)?;
+    let m = mod_from_knossos_mod(&km, mod_json, source);
+    let id = [m.mid.to_string(), m.version.to_string()];
+    if let Some(winner) = self.json_ids.get(&id) {
+      detail!(
+        "    {} {} from {} is shadowed by {}",
+        m.mid,
+        m.version,
+        source,
+        winner
+      );
+      return Ok(());
+    }
+    self.json_ids.insert(id, source.to_string());
 
-    match select_mod(&mut select_stmt, &m) {
+    match select_mod(&mut self.select_stmt, &m)? {
       Some(m_stored) => {
         if m != m_stored {
-          println!("[UPDATE] {} ({})", m.title, m.version);
-          update_mod(&mut update_stmt, &m);
+          self.plan(PlanRecord::new("update", &m));
+          if !self.dry_run {
+            update_mod(&mut self.update_stmt, &m)?;
+            let mod_id = select_mod_id(&self.tx, &m)?;
+            self
+              .tx
+              .execute(DELETE_PACKAGES_STMT, named_params! {":mod_id": mod_id})?;
+            insert_packages(&self.tx, mod_id, &km)?;
+            index_mod(&self.tx, mod_id, &km)?;
+          }
         }
       }
 
       None => {
-        if !first_update {
-          println!("[ADD]    {} ({})", m.title, m.version);
+        self.plan(PlanRecord::new("add", &m));
+        if !self.dry_run {
+          insert_mod(&mut self.insert_stmt, &m)?;
+          let mod_id = self.tx.last_insert_rowid();
+          insert_packages(&self.tx, mod_id, &km)?;
+          index_mod(&self.tx, mod_id, &km)?;
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Deletes the releases that no source has and commits, or with
+  /// `dry_run`, prints the plan.
+  pub fn finish(mut self) -> Result<()> {
+    if self.first_update && !self.dry_run {
+      self.progress_bar.finish();
+    }
+
+    let mut to_delete: Vec<_> = self
+      .stored_ids
+      .iter()
+      .filter(|id| !self.json_ids.contains_key(*id))
+      .cloned()
+      .collect();
+    to_delete.sort();
+
+    for [mid, version] in to_delete {
+      let m = Mod {
+        mid,
+        version,
+        versions: Vec::new(),
+        title: "".to_string(),
+        tile: "".to_string(),
+        first_release: "".to_string(),
+        last_update: "".to_string(),
+        mod_json: "".to_string(),
+        source: "".to_string(),
+      };
+
+      if let Some(m_stored) = select_mod(&mut self.select_stmt, &m)? {
+        self.plan(PlanRecord::new("delete", &m_stored));
+        if !self.dry_run {
+          let mod_id = select_mod_id(&self.tx, &m)?;
+          self
+            .tx
+            .execute(DELETE_SEARCH_STMT, named_params! {":mod_id": mod_id})?;
         }
-        insert_mod(&mut insert_stmt, &m);
+      }
+
+      if !self.dry_run {
+        self
+          .delete_stmt
+          .execute(named_params! { ":mid": m.mid, ":version": m.version })?;
       }
     }
 
-    if first_update {
-      progress_bar.inc(1);
+    let count = |action| self.plan.iter().filter(|r| r.action == action).count();
+    let (added, updated, deleted) = (count("add"), count("update"), count("delete"));
+
+    if self.dry_run {
+      output::print_records(&self.plan, || {
+        for record in &self.plan {
+          println!("{}", record.line());
+        }
+        Ok(())
+      })?;
+      status!(
+        "==> Dry run: {} to add, {} to update, {} to delete; nothing was written",
+        added,
+        updated,
+        deleted
+      );
+      return Ok(());
     }
+
+    self.tx.execute(SET_LAST_UPDATE_STMT, [])?;
+    let Updater {
+      tx,
+      select_stmt,
+      insert_stmt,
+      update_stmt,
+      delete_stmt,
+      ..
+    } = self;
+    drop((select_stmt, insert_stmt, update_stmt, delete_stmt));
+    tx.commit()?;
+    status!(
+      "==> {} added, {} updated, {} deleted",
+      added,
+      updated,
+      deleted
+    );
+    Ok(())
   }
 
-  if first_update {
-    progress_bar.finish();
+  /// Notes a change, printed at once unless this is a dry run or the
+  /// first update, which adds everything.
+  fn plan(&mut self, record: PlanRecord) {
+    let quiet = self.dry_run || (self.first_update && record.action == "add");
+    if !quiet {
+      status!("{}", record.line());
+    }
+    self.plan.push(record);
   }
+}
 
-  delete_mods(conn, &stored_ids, &json_ids);
+pub fn get_db_info(conn: &Connection) -> Result<DbInfo> {
+  let schema_version = migrations::schema_version(conn)?;
+  let last_update = conn
+    .query_row(SELECT_LAST_UPDATE_STMT, [], |row| row.get(0))
+    .optional()?;
+
+  let info = conn.query_row(COUNT_ROWS_STMT, [], |row| {
+    Ok(DbInfo {
+      schema_version,
+      last_update,
+      mods: row.get(0)?,
+      releases: row.get(1)?,
+      packages: row.get(2)?,
+      package_files: row.get(3)?,
+      package_dependencies: row.get(4)?,
+      executables: row.get(5)?,
+      filelist: row.get(6)?,
+    })
+  })?;
+
+  Ok(info)
 }
 
-pub fn list(conn: &Connection) {
-  let mut select = conn.prepare(LIST_STMT).unwrap();
-  let mut rows = select.query([]).unwrap();
+pub fn db_info(conn: &Connection) -> Result<()> {
+  let info = get_db_info(conn)?;
+
+  output::print_record(&info, || {
+    let last_update = info.last_update.as_deref().unwrap_or("never");
+    println!("{:<22} {}", "Schema version:", info.schema_version);
+    println!("{:<22} {}", "Last update:", last_update);
+    println!("{:<22} {}", "Mods:", info.mods);
+    println!("{:<22} {}", "Releases:", info.releases);
+    println!("{:<22} {}", "Packages:", info.packages);
+    println!("{:<22} {}", "Package files:", info.package_files);
+    println!(
+      "{:<22} {}",
+      "Package dependencies:", info.package_dependencies
+    );
+    println!("{:<22} {}", "Executables:", info.executables);
+    println!("{:<22} {}", "Filelist entries:", info.filelist);
+    Ok(())
+  })
+}
 
-  while let Some(row) = rows.next().unwrap() {
-    let m = mod_from_sql_row(row);
-    println!("{}", m.title);
-  }
+pub fn list(conn: &Connection, source: Option<&str>) -> Result<()> {
+  let mods = match source {
+    Some(source) => list_mods_from(conn, source)?,
+    None => list_mods(conn)?,
+  };
+  let records: Vec<ModRecord> = mods
+    .iter()
+    .map(|m| ModRecord {
+      mid: &m.mid,
+      version: &m.version,
+      title: &m.title,
+      tile: &m.tile,
+      source: &m.source,
+    })
+    .collect();
+
+  output::print_records(&records, || {
+    for m in &mods {
+      println!("{}", m.title);
+    }
+    Ok(())
+  })
 }
 
-pub fn list_mods(conn: &Connection) -> Vec<Mod> {
-  let mut select = conn.prepare(LIST_STMT).unwrap();
-  let mut rows = select.query([]).unwrap();
+pub fn list_mods(conn: &Connection) -> Result<Vec<Mod>> {
+  let mut select = conn.prepare(LIST_STMT)?;
+  let mut rows = select.query([])?;
 
   let mut list = Vec::new();
-  while let Some(row) = rows.next().unwrap() {
-    let m = mod_from_sql_row(row);
+  while let Some(row) = rows.next()? {
+    let m = mod_from_sql_row(row)?;
     list.push(m);
   }
 
-  list
+  Ok(list)
 }
 
-pub fn list_json(conn: &Connection) {
-  let mut select = conn.prepare(LIST_STMT).unwrap();
-  let mut rows = select.query([]).unwrap();
+/// Like `list_mods`, for the mods with a release from `source`.
+pub fn list_mods_from(conn: &Connection, source: &str) -> Result<Vec<Mod>> {
+  let mut select = conn.prepare(LIST_SOURCE_STMT)?;
+  let mut rows = select.query(named_params! {":source": source})?;
 
   let mut list = Vec::new();
-  while let Some(row) = rows.next().unwrap() {
-    let m = mod_from_sql_row(row);
-
-    list.push(json!({
-      "mid": m.mid,
-      "title": m.title,
-      "poster_url": m.tile,
-    }));
+  while let Some(row) = rows.next()? {
+    let m = mod_from_sql_row(row)?;
+    list.push(m);
   }
 
-  println!("{}", json!({ "mods": list }));
+  Ok(list)
 }
 
-pub fn search(conn: &Connection, query: &String) {
-  let mut select = conn.prepare(SEARCH_STMT).unwrap();
-  let mut rows = select
-    .query(named_params! {":query": format!("%{}%", query)})
-    .unwrap();
-
-  let mut min_width = 15;
-  let mut results = Vec::new();
-  while let Some(row) = rows.next().unwrap() {
-    let m = mod_from_sql_row(row);
+pub fn list_json(conn: &Connection) -> Result<()> {
+  let list: Vec<Value> = list_mods(conn)?
+    .into_iter()
+    .map(|m| {
+      json!({
+        "mid": m.mid,
+        "title": m.title,
+        "poster_url": m.tile,
+      })
+    })
+    .collect();
 
-    if m.mid.len() > min_width {
-      min_width = m.mid.len();
-    }
+  println!("{}", json!({ "mods": list }));
+  Ok(())
+}
 
-    results.push(m);
+pub fn search(conn: &Connection, query: &str, filter: &SearchFilter) -> Result<()> {
+  let results = search_mods(conn, query, filter)?;
+  if !output::is_text() {
+    let records: Vec<SearchResult> = results
+      .into_iter()
+      .map(|r| SearchResult {
+        snippet: r.snippet.replace([SNIPPET_START, SNIPPET_END], ""),
+        ..r
+      })
+      .collect();
+    return output::print_records(&records, || Ok(()));
   }
 
-  for m in results {
-    println!("{:<width$}  {}", m.mid, m.title, width = min_width);
-  }
-}
+  let min_width = results
+    .iter()
+    .map(|r| r.mid.len())
+    .max()
+    .unwrap_or(0)
+    .max(15);
 
-pub fn versions(conn: &Connection, mid: &String) {
-  let sorted_versions = get_sorted_versions(conn, mid);
+  let (start, end) = if io::stdout().is_terminal() {
+    ("\x1b[1m", "\x1b[0m")
+  } else {
+    ("", "")
+  };
 
-  for v in sorted_versions {
-    println!("{}", v);
+  for r in results {
+    println!("{:<width$}  {}", r.mid, r.title, width = min_width);
+
+    // Only worth a line of its own when the match is not in the title.
+    let plain = r.snippet.replace([SNIPPET_START, SNIPPET_END], "");
+    if !plain.is_empty() && plain != r.title && plain != r.mid {
+      let snippet = r
+        .snippet
+        .replace(SNIPPET_START, start)
+        .replace(SNIPPET_END, end);
+      println!("{:<width$}  {}", "", snippet, width = min_width);
+    }
   }
+
+  Ok(())
 }
 
-fn version_cmp(a: &String, b: &String) -> Ordering {
-  if a == b {
-    return Ordering::Equal;
+/// Full-text search over the mid, title, description, notes and package
+/// names of the newest release of each mod, i.e. the one updated last,
+/// keeping those whose newest release passes `filter`, best match first.
+/// Each search word matches words starting with it. Without search words,
+/// the mods passing `filter` are listed by title.
+pub fn search_mods(
+  conn: &Connection,
+  query: &str,
+  filter: &SearchFilter,
+) -> Result<Vec<SearchResult>> {
+  let match_query = fts_query(query);
+  let start = SNIPPET_START.to_string();
+  let end = SNIPPET_END.to_string();
+
+  let mut sql = String::new();
+  let mut params: Vec<(&str, &dyn ToSql)> = Vec::new();
+  if match_query.is_empty() {
+    if !query.trim().is_empty() {
+      // Nothing but punctuation, which the index does not hold.
+      return Ok(Vec::new());
+    }
+    sql.push_str(FILTER_STMT);
+  } else {
+    sql.push_str(SEARCH_STMT);
+    params.push((":query", &match_query));
+    params.push((":start", &start));
+    params.push((":end", &end));
   }
+  sql.push_str(NEWEST_RELEASE_COND);
+
+  let conditions = [
+    (FILTER_TYPE_COND, ":type", sql_param(&filter.mod_type)),
+    (
+      FILTER_STABILITY_COND,
+      ":stability",
+      sql_param(&filter.stability),
+    ),
+    (FILTER_PARENT_COND, ":parent", sql_param(&filter.parent)),
+    (
+      FILTER_RELEASED_FROM_COND,
+      ":released_from",
+      sql_param(&filter.first_release.0),
+    ),
+    (
+      FILTER_RELEASED_TO_COND,
+      ":released_to",
+      sql_param(&filter.first_release.1),
+    ),
+    (
+      FILTER_UPDATED_FROM_COND,
+      ":updated_from",
+      sql_param(&filter.last_update.0),
+    ),
+    (
+      FILTER_UPDATED_TO_COND,
+      ":updated_to",
+      sql_param(&filter.last_update.1),
+    ),
+    (
+      FILTER_SIZE_FROM_COND,
+      ":size_from",
+      sql_param(&filter.size.0),
+    ),
+    (FILTER_SIZE_TO_COND, ":size_to", sql_param(&filter.size.1)),
+  ];
+  for (condition, name, value) in conditions {
+    if let Some(value) = value {
+      sql.push_str(condition);
+      params.push((name, value));
+    }
+  }
+
+  sql.push_str(if match_query.is_empty() {
+    FILTER_ORDER
+  } else {
+    SEARCH_ORDER
+  });
 
-  let mut a_build: Vec<&str> = a.split('-').collect();
-  let mut b_build: Vec<&str> = b.split('-').collect();
+  let mut select = conn.prepare(&sql)?;
+  let mut rows = select.query(params.as_slice())?;
 
-  if a_build.len() < 2 {
-    a_build.push("~");
+  let mut seen = HashSet::new();
+  let mut results = Vec::new();
+  while let Some(row) = rows.next()? {
+    let result = SearchResult {
+      mid: row.get(0)?,
+      version: row.get(1)?,
+      title: row.get(2)?,
+      tile: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+      snippet: row.get(4)?,
+    };
+
+    if seen.insert(result.mid.clone()) {
+      results.push(result);
+    }
   }
-  if b_build.len() < 2 {
-    b_build.push("~");
+
+  if match_query.is_empty() {
+    results.sort_by_key(|r| r.title.to_lowercase());
   }
+  Ok(results)
+}
 
-  if a_build[0] == b_build[0] {
-    if a_build[1] < b_build[1] {
-      return Ordering::Less;
-    } else {
-      return Ordering::Greater;
+fn sql_param<T: ToSql>(value: &Option<T>) -> Option<&dyn ToSql> {
+  value.as_ref().map(|v| v as &dyn ToSql)
+}
+
+/// Quotes each word so that FTS5 query syntax in user input is taken
+/// literally.
+fn fts_query(query: &str) -> String {
+  query
+    .split_whitespace()
+    .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+    .collect::<Vec<String>>()
+    .join(" ")
+}
+
+pub fn versions(conn: &Connection, mid: &String, query: &VersionQuery) -> Result<()> {
+  let sorted_versions = matching_versions(conn, mid, query)?;
+  let records: Vec<VersionRecord> = sorted_versions
+    .iter()
+    .map(|v| VersionRecord {
+      version: v,
+      stable: version::is_stable(v),
+    })
+    .collect();
+
+  output::print_records(&records, || {
+    for v in &sorted_versions {
+      println!("{}", v);
     }
-  }
+    Ok(())
+  })
+}
 
-  let a_version = a_build[0].split('.').map(|x| x.parse::<u32>().unwrap_or(0));
-  let b_version = b_build[0].split('.').map(|x| x.parse::<u32>().unwrap_or(0));
+/// The versions of `mid` passing `query`, newest first.
+fn matching_versions(conn: &Connection, mid: &String, query: &VersionQuery) -> Result<Vec<String>> {
+  let mut sorted_versions: Vec<String> = get_sorted_versions(conn, mid)?
+    .into_iter()
+    .filter(|v| !query.stable_only || version::is_stable(v))
+    .filter(|v| query.spec.as_ref().is_none_or(|spec| spec.matches(v)))
+    .collect();
+
+  if query.latest {
+    if sorted_versions.is_empty() {
+      return Err(Error::NoMatchingVersion {
+        mid: mid.to_string(),
+        spec: query
+          .spec
+          .as_ref()
+          .map_or_else(|| "*".to_string(), |spec| spec.to_string()),
+      });
+    }
+    sorted_versions.truncate(1);
+  }
 
-  a_version.cmp(b_version)
+  Ok(sorted_versions)
 }
 
-fn get_sorted_versions(conn: &Connection, mid: &String) -> Vec<String> {
-  let mut select = conn.prepare(SELECT_VERSIONS_STMT).unwrap();
-  let mut rows = select.query(named_params! {":mid": mid}).unwrap();
+pub fn get_sorted_versions(conn: &Connection, mid: &String) -> Result<Vec<String>> {
+  let mut select = conn.prepare(SELECT_VERSIONS_STMT)?;
+  let mut rows = select.query(named_params! {":mid": mid})?;
   let mut versions: Vec<String> = Vec::new();
 
-  while let Some(row) = rows.next().unwrap() {
-    versions.push(row.get(0).unwrap());
+  while let Some(row) = rows.next()? {
+    versions.push(row.get(0)?);
   }
 
-  versions.sort_by(version_cmp);
-  versions.reverse();
-  versions
+  version::sort_descending(&mut versions);
+  Ok(versions)
 }
 
-pub fn get_mod(conn: &Connection, mid: &String, version: &Option<String>) -> Option<Mod> {
-  let sorted_versions = get_sorted_versions(conn, mid);
+pub fn get_mod(conn: &Connection, mid: &String, version: &Option<String>) -> Result<Option<Mod>> {
+  let sorted_versions = get_sorted_versions(conn, mid)?;
 
   if sorted_versions.is_empty() {
-    return None;
+    return Ok(None);
   }
 
   let version: String = if let Some(ver) = version {
@@ -306,155 +850,270 @@ pub fn get_mod(conn: &Connection, mid: &String, version: &Option<String>) -> Opt
     sorted_versions[0].clone()
   };
 
-  let mut select = conn.prepare(SELECT_STMT).unwrap();
-  let mut rows = select
-    .query(named_params! {":mid": mid, ":version": version})
-    .unwrap();
+  let mut select = conn.prepare(SELECT_STMT)?;
+  let mut rows = select.query(named_params! {":mid": mid, ":version": version})?;
 
-  rows.next().unwrap().map(|r| {
-    let mut m = mod_from_sql_row(r);
-    m.versions = sorted_versions;
-    m
-  })
-}
-
-pub fn json(conn: &Connection, mid: &String, version: &Option<String>) {
-  if let Some(m) = get_mod(conn, mid, version) {
-    let mod_json: Value = serde_json::from_str(m.mod_json.as_str()).unwrap();
-    println!("{}", serde_json::to_string_pretty(&mod_json).unwrap());
-  } else {
-    println!("Not found");
+  match rows.next()? {
+    Some(r) => {
+      let mut m = mod_from_sql_row(r)?;
+      m.versions = sorted_versions;
+      Ok(Some(m))
+    }
+    None => Ok(None),
   }
 }
 
-pub fn cmdline(conn: &Connection, mid: &String, version: &Option<String>) {
-  if let Some(m) = get_mod(conn, mid, version) {
-    let mod_json: Value = serde_json::from_str(m.mod_json.as_str()).unwrap();
-    println!("{}", mod_json["cmdline"].as_str().unwrap());
-  }
+/// Like `get_mod`, but a missing mod or version is an error.
+fn find_mod(conn: &Connection, mid: &String, version: &Option<String>) -> Result<Mod> {
+  get_mod(conn, mid, version)?.ok_or_else(|| Error::ModNotFound {
+    mid: mid.to_string(),
+    required_by: None,
+  })
 }
 
-pub fn modline(conn: &Connection, mid: &String, version: &Option<String>) {
-  if let Some(m) = get_mod(conn, mid, version) {
-    let mod_json: Value = serde_json::from_str(m.mod_json.as_str()).unwrap();
-    let mod_flag_array = mod_json["mod_flag"].as_array().unwrap();
-    let mod_flag: Vec<String> = mod_flag_array
-      .iter()
-      .map(|x| x.as_str().unwrap().to_string())
-      .collect();
-    let mod_string = mod_flag.join(",");
-
-    println!("-mod {}", mod_string);
-  }
+pub fn json(conn: &Connection, mid: &String, version: &Option<String>) -> Result<()> {
+  let m = find_mod(conn, mid, version)?;
+  let mod_json: Value = serde_json::from_str(m.mod_json.as_str())?;
+  output::print_record(&mod_json, || {
+    println!("{}", serde_json::to_string_pretty(&mod_json)?);
+    Ok(())
+  })
 }
 
-pub fn get_sha256sum(conn: &Connection, mid: &String, version: &Option<String>) -> String {
-  let files = filemeta(conn, mid, version);
-  let mut lines: Vec<String> = Vec::new();
+pub fn cmdline(conn: &Connection, mid: &String, version: &Option<String>) -> Result<()> {
+  let km = find_mod(conn, mid, version)?.knossos_mod()?;
+  let record = CmdlineRecord {
+    mid: &km.id,
+    version: &km.version,
+    cmdline: km.cmdline.as_deref().unwrap_or_default(),
+  };
+  output::print_record(&record, || {
+    println!("{}", record.cmdline);
+    Ok(())
+  })
+}
 
-  for file in files {
-    lines.push(format!("{} {}", file.sha256, file.name));
-  }
+pub fn modline(conn: &Connection, mid: &String, version: &Option<String>) -> Result<()> {
+  let km = find_mod(conn, mid, version)?.knossos_mod()?;
+  print_mod_flag(&km.id, &km.version, &km.mod_flag)
+}
 
-  lines.join("\n")
+/// Prints a `-mod` list, shared with the resolved variant in `resolver`.
+pub(crate) fn print_mod_flag(mid: &str, version: &str, mod_flag: &[String]) -> Result<()> {
+  let record = ModFlagRecord {
+    mid,
+    version,
+    mod_flag,
+  };
+  output::print_record(&record, || {
+    println!("-mod {}", mod_flag.join(","));
+    Ok(())
+  })
 }
 
-pub fn sha256sum(conn: &Connection, mid: &String, version: &Option<String>) {
-  println!("{}", get_sha256sum(conn, mid, version));
+pub fn get_sha256sum(conn: &Connection, mid: &String, version: &Option<String>) -> Result<String> {
+  Ok(checksum_lines(&filemeta(conn, mid, version)?))
 }
 
-pub fn dlsize(conn: &Connection, mid: &String, version: &Option<String>) {
-  let files = filemeta(conn, mid, version);
-  let mut total = 0;
+fn checksum_lines(files: &[ModFile]) -> String {
+  let mut lines: Vec<String> = Vec::new();
 
   for file in files {
-    total += file.size;
-    println!("{:<50} {:>20}", file.name, file.size);
+    lines.push(format!("{} {}", file.checksum.digest(), file.filename));
   }
-  println!("\n{:<50} {:>20}", "TOTAL", total);
-}
 
-fn filemeta(conn: &Connection, mid: &String, version: &Option<String>) -> Vec<FileMeta> {
-  if let Some(m) = get_mod(conn, mid, version) {
-    let mod_json: Value = serde_json::from_str(m.mod_json.as_str()).unwrap();
-    let packages_array = mod_json["packages"].as_array().unwrap();
-    packages_array
-      .iter()
-      .flat_map(|p| {
-        let file_info: Vec<FileMeta> = p["files"]
-          .as_array()
-          .unwrap()
-          .iter()
-          .map(|f| FileMeta {
-            name: f["filename"].as_str().unwrap().to_string(),
-            size: f["filesize"].as_u64().unwrap(),
-            sha256: f["checksum"][1].as_str().unwrap().to_string(),
-          })
-          .collect();
-        file_info
-      })
-      .collect()
-  } else {
-    Vec::new()
-  }
+  lines.join("\n")
 }
 
-fn prepare_select_statement(conn: &Connection) -> CachedStatement {
-  conn.prepare_cached(SELECT_STMT).unwrap_or_else(|_| {
-    println!("Prepare select statement error");
-    process::exit(1);
+pub fn sha256sum(conn: &Connection, mid: &String, version: &Option<String>) -> Result<()> {
+  let files = filemeta(conn, mid, version)?;
+  let records: Vec<ChecksumRecord> = files
+    .iter()
+    .map(|f| ChecksumRecord {
+      filename: &f.filename,
+      algorithm: f.checksum.algorithm(),
+      checksum: f.checksum.digest(),
+    })
+    .collect();
+
+  output::print_records(&records, || {
+    println!("{}", checksum_lines(&files));
+    Ok(())
   })
 }
 
-fn prepare_insert_statement(conn: &Connection) -> CachedStatement {
-  conn.prepare_cached(INSERT_STMT).unwrap_or_else(|_| {
-    println!("Prepare insert statement error");
-    process::exit(1);
+pub fn dlsize(conn: &Connection, mid: &String, version: &Option<String>) -> Result<()> {
+  let files = filemeta(conn, mid, version)?;
+  let records: Vec<FileSizeRecord> = files
+    .iter()
+    .map(|f| FileSizeRecord {
+      filename: &f.filename,
+      filesize: f.filesize,
+    })
+    .collect();
+
+  output::print_records(&records, || {
+    let mut total = 0;
+    for file in &files {
+      total += file.filesize;
+      println!("{:<50} {:>20}", file.filename, file.filesize);
+    }
+    println!("\n{:<50} {:>20}", "TOTAL", total);
+    Ok(())
   })
 }
 
-fn prepare_update_statement(conn: &Connection) -> CachedStatement {
-  conn.prepare_cached(UPDATE_STMT).unwrap_or_else(|_| {
-    println!("Prepare update statement error");
-    process::exit(1);
-  })
+fn filemeta(conn: &Connection, mid: &String, version: &Option<String>) -> Result<Vec<ModFile>> {
+  let km = find_mod(conn, mid, version)?.knossos_mod()?;
+  Ok(km.files().cloned().collect())
 }
 
-fn mod_from_serde_json(md: &Value) -> Mod {
+fn mod_from_knossos_mod(km: &KnossosMod, md: &Value, source: &str) -> Mod {
   Mod {
-    mid: md["id"].as_str().unwrap().to_string(),
-    version: md["version"].as_str().unwrap().to_string(),
+    mid: km.id.clone(),
+    version: km.version.clone(),
     versions: Vec::new(),
-    title: md["title"].as_str().unwrap().to_string(),
-    tile: md["tile"].as_str().unwrap_or("").to_string(),
-    first_release: md["first_release"].as_str().unwrap().to_string(),
-    last_update: md["last_update"].as_str().unwrap().to_string(),
+    title: km.title.clone(),
+    tile: km.tile.clone().unwrap_or_default(),
+    first_release: km.first_release.clone().unwrap_or_default(),
+    last_update: km.last_update.clone().unwrap_or_default(),
     mod_json: md.to_string(),
+    source: source.to_string(),
   }
 }
 
-fn mod_from_sql_row(row: &Row) -> Mod {
-  Mod {
-    mid: row.get(0).unwrap(),
-    version: row.get(1).unwrap(),
+fn mod_from_sql_row(row: &Row) -> Result<Mod> {
+  Ok(Mod {
+    mid: row.get(0)?,
+    version: row.get(1)?,
     versions: Vec::new(),
-    title: row.get(2).unwrap(),
+    title: row.get(2)?,
     tile: row.get(3).unwrap_or_else(|_| "".to_string()),
     first_release: row.get(4).unwrap_or_else(|_| "".to_string()),
     last_update: row.get(5).unwrap_or_else(|_| "".to_string()),
     mod_json: row.get(6).unwrap_or_else(|_| "".to_string()),
+    source: row.get(7).unwrap_or_else(|_| "".to_string()),
+  })
+}
+
+fn select_mod(select_stmt: &mut CachedStatement, m: &Mod) -> Result<Option<Mod>> {
+  let mut rows = select_stmt.query(named_params! {":mid": m.mid, ":version": m.version})?;
+
+  match rows.next()? {
+    Some(row) => Ok(Some(mod_from_sql_row(row)?)),
+    None => Ok(None),
+  }
+}
+
+fn select_mod_id(conn: &Connection, m: &Mod) -> Result<i64> {
+  let mut select = conn.prepare_cached(SELECT_MOD_ID_STMT)?;
+  let mod_id = select.query_row(
+    named_params! {":mid": m.mid, ":version": m.version},
+    |row| row.get(0),
+  )?;
+  Ok(mod_id)
+}
+
+pub(crate) fn insert_packages(conn: &Connection, mod_id: i64, km: &KnossosMod) -> Result<()> {
+  for package in &km.packages {
+    insert_package(conn, mod_id, package)?;
   }
+
+  Ok(())
 }
 
-fn select_mod(select_stmt: &mut CachedStatement, m: &Mod) -> Option<Mod> {
-  let mut rows = select_stmt
-    .query(named_params! {":mid": m.mid, ":version": m.version})
-    .unwrap();
-  let maybe_row = rows.next().unwrap();
-  maybe_row.map(mod_from_sql_row)
+/// Replaces the search index entry of the release stored as `mod_id`.
+pub(crate) fn index_mod(conn: &Connection, mod_id: i64, km: &KnossosMod) -> Result<()> {
+  let notes: Vec<&str> = km
+    .notes
+    .iter()
+    .chain(km.packages.iter().filter_map(|p| p.notes.as_ref()))
+    .map(String::as_str)
+    .collect();
+  let packages: Vec<&str> = km.packages.iter().map(|p| p.name.as_str()).collect();
+
+  conn.execute(DELETE_SEARCH_STMT, named_params! {":mod_id": mod_id})?;
+  conn.execute(
+    INSERT_SEARCH_STMT,
+    named_params! {
+      ":mod_id":      mod_id,
+      ":mid":         km.id,
+      ":title":       km.title,
+      ":description": km.description,
+      ":notes":       notes.join("\n"),
+      ":packages":    packages.join(" "),
+    },
+  )?;
+  Ok(())
+}
+
+fn insert_package(conn: &Connection, mod_id: i64, p: &Package) -> Result<()> {
+  let params = named_params! {
+    ":mod_id":      mod_id,
+    ":name":        p.name,
+    ":status":      p.status,
+    ":notes":       p.notes,
+    ":folder":      p.folder,
+    ":environment": p.environment,
+    ":is_vp":       p.is_vp,
+  };
+
+  conn.prepare_cached(INSERT_PACKAGE_STMT)?.execute(params)?;
+  let package_id = conn.last_insert_rowid();
+
+  let mut insert_file = conn.prepare_cached(INSERT_PACKAGE_FILE_STMT)?;
+  for f in &p.files {
+    insert_file.execute(named_params! {
+      ":mod_id":             mod_id,
+      ":package_id":         package_id,
+      ":filename":           f.filename,
+      ":dest":               f.dest,
+      ":checksum_algorithm": f.checksum.algorithm(),
+      ":checksum":           f.checksum.digest(),
+      ":filesize":           f.filesize,
+      ":urls":               serde_json::to_string(&f.urls)?,
+    })?;
+  }
+
+  let mut insert_dependency = conn.prepare_cached(INSERT_PACKAGE_DEPENDENCY_STMT)?;
+  for d in &p.dependencies {
+    insert_dependency.execute(named_params! {
+      ":mod_id":     mod_id,
+      ":package_id": package_id,
+      ":dep_mid":    d.id,
+      ":version":    d.version,
+      ":packages":   serde_json::to_string(&d.packages)?,
+    })?;
+  }
+
+  let mut insert_executable = conn.prepare_cached(INSERT_EXECUTABLE_STMT)?;
+  for e in &p.executables {
+    insert_executable.execute(named_params! {
+      ":mod_id":     mod_id,
+      ":package_id": package_id,
+      ":file":       e.file,
+      ":label":      e.label,
+      ":properties": e.properties,
+    })?;
+  }
+
+  let mut insert_filelist = conn.prepare_cached(INSERT_FILELIST_STMT)?;
+  for f in &p.filelist {
+    insert_filelist.execute(named_params! {
+      ":mod_id":             mod_id,
+      ":package_id":         package_id,
+      ":filename":           f.filename,
+      ":archive":            f.archive,
+      ":orig_name":          f.orig_name,
+      ":checksum_algorithm": f.checksum.algorithm(),
+      ":checksum":           f.checksum.digest(),
+    })?;
+  }
+
+  Ok(())
 }
 
-fn insert_mod(insert_stmt: &mut CachedStatement, m: &Mod) {
+fn insert_mod(insert_stmt: &mut CachedStatement, m: &Mod) -> Result<()> {
   let params = named_params! {
     ":mid":           m.mid,
     ":version":       m.version,
@@ -463,18 +1122,14 @@ fn insert_mod(insert_stmt: &mut CachedStatement, m: &Mod) {
     ":first_release": m.first_release,
     ":last_update":   m.last_update,
     ":mod_json":      m.mod_json,
+    ":source":        m.source,
   };
 
-  insert_stmt.execute(params).unwrap_or_else(|_| {
-    println!(
-      "DB insert mod error: (mid: {}, version: {})",
-      m.mid, m.version
-    );
-    process::exit(1);
-  });
+  insert_stmt.execute(params)?;
+  Ok(())
 }
 
-fn update_mod(update_stmt: &mut CachedStatement, m: &Mod) {
+fn update_mod(update_stmt: &mut CachedStatement, m: &Mod) -> Result<()> {
   let params = named_params! {
     ":mid":           m.mid,
     ":version":       m.version,
@@ -483,48 +1138,81 @@ fn update_mod(update_stmt: &mut CachedStatement, m: &Mod) {
     ":first_release": m.first_release,
     ":last_update":   m.last_update,
     ":mod_json":      m.mod_json,
+    ":source":        m.source,
   };
 
-  update_stmt.execute(params).unwrap_or_else(|_| {
-    println!(
-      "DB update mod error: (mid: {}, version: {})",
-      m.mid, m.version
-    );
-    process::exit(1);
-  });
+  update_stmt.execute(params)?;
+  Ok(())
 }
 
-fn create_progress_bar(mods_json: &Vec<Value>) -> ProgressBar {
-  let progress_bar = ProgressBar::new(mods_json.len().try_into().unwrap());
-  progress_bar.set_style(
-    ProgressStyle::default_bar()
-      .template("[{wide_bar}] {pos}/{len}")
-      .progress_chars("=> "),
-  );
+// The number of mods is not known until the whole file has been read.
+fn create_progress_bar() -> ProgressBar {
+  let progress_bar = ProgressBar::with_draw_target(0, output::progress_target());
+  progress_bar.set_style(ProgressStyle::default_spinner().template("{spinner} {pos} mods"));
   progress_bar
 }
 
-static CREATE_TABLE_STMTS: &str = r#"
-CREATE TABLE IF NOT EXISTS mods (
-  id               INTEGER PRIMARY KEY,
-  mid              TEXT NOT NULL,
-  title            TEXT NOT NULL,
-  tile             TEXT,
-  version          TEXT NOT NULL,
-  first_release    DATE,
-  last_update      DATE,
-  mod_json         JSON NOT NULL
-);
-CREATE UNIQUE INDEX mods_mid_version_unique_index ON mods (mid, version);
-CREATE INDEX mods_title_index ON mods (title);
-CREATE INDEX mods_first_release ON mods (first_release);
-CREATE INDEX mods_last_update ON mods (last_update);
+static SET_LAST_UPDATE_STMT: &str = r#"
+INSERT OR REPLACE INTO metadata (key, value) VALUES ('last_update', datetime('now') || ' UTC');
+"#;
+
+static SELECT_LAST_UPDATE_STMT: &str = r#"
+SELECT value FROM metadata WHERE (key = 'last_update');
+"#;
+
+static COUNT_ROWS_STMT: &str = r#"
+SELECT
+  (SELECT COUNT(DISTINCT mid) FROM mods),
+  (SELECT COUNT(*) FROM mods),
+  (SELECT COUNT(*) FROM packages),
+  (SELECT COUNT(*) FROM package_files),
+  (SELECT COUNT(*) FROM package_dependencies),
+  (SELECT COUNT(*) FROM executables),
+  (SELECT COUNT(*) FROM filelist);
+"#;
+
+static SELECT_MOD_ID_STMT: &str = r#"
+SELECT id FROM mods WHERE (mid = :mid) AND (version = :version);
+"#;
+
+static DELETE_PACKAGES_STMT: &str = r#"
+DELETE FROM packages WHERE (mod_id = :mod_id);
+"#;
+
+static INSERT_PACKAGE_STMT: &str = r#"
+INSERT
+INTO packages (mod_id, name, status, notes, folder, environment, is_vp)
+VALUES (:mod_id, :name, :status, :notes, :folder, :environment, :is_vp);
+"#;
+
+static INSERT_PACKAGE_FILE_STMT: &str = r#"
+INSERT
+INTO package_files (mod_id, package_id, filename, dest, checksum_algorithm, checksum, filesize, urls)
+VALUES (:mod_id, :package_id, :filename, :dest, :checksum_algorithm, :checksum, :filesize, :urls);
+"#;
+
+static INSERT_PACKAGE_DEPENDENCY_STMT: &str = r#"
+INSERT
+INTO package_dependencies (mod_id, package_id, dep_mid, version, packages)
+VALUES (:mod_id, :package_id, :dep_mid, :version, :packages);
+"#;
+
+static INSERT_EXECUTABLE_STMT: &str = r#"
+INSERT
+INTO executables (mod_id, package_id, file, label, properties)
+VALUES (:mod_id, :package_id, :file, :label, :properties);
+"#;
+
+static INSERT_FILELIST_STMT: &str = r#"
+INSERT
+INTO filelist (mod_id, package_id, filename, archive, orig_name, checksum_algorithm, checksum)
+VALUES (:mod_id, :package_id, :filename, :archive, :orig_name, :checksum_algorithm, :checksum);
 "#;
 
 static INSERT_STMT: &str = r#"
 INSERT
-INTO mods (mid, version, title, tile, first_release, last_update, mod_json)
-VALUES (:mid, :version, :title, :tile, :first_release, :last_update, :mod_json);
+INTO mods (mid, version, title, tile, first_release, last_update, mod_json, source)
+VALUES (:mid, :version, :title, :tile, :first_release, :last_update, :mod_json, :source);
 "#;
 
 static DELETE_STMT: &str = r#"
@@ -537,23 +1225,32 @@ SET title = :title,
     tile = :tile,
     first_release = :first_release,
     last_update = :last_update,
-    mod_json = :mod_json
+    mod_json = :mod_json,
+    source = :source
 WHERE (mid = :mid) AND (version = :version);
 "#;
 
 static LIST_STMT: &str = r#"
-SELECT mid, version, title, tile, max(last_update)
+SELECT mid, version, title, tile, first_release, max(last_update), NULL, source
 FROM mods
 GROUP BY mid
 ORDER BY LOWER(title);
 "#;
 
+static LIST_SOURCE_STMT: &str = r#"
+SELECT mid, version, title, tile, first_release, max(last_update), NULL, source
+FROM mods
+WHERE (source = :source)
+GROUP BY mid
+ORDER BY LOWER(title);
+"#;
+
 static LIST_MID_AND_VERSION_STMT: &str = r#"
 SELECT mid, version FROM mods
 "#;
 
 static SELECT_STMT: &str = r#"
-SELECT mid, version, title, tile, first_release, last_update, mod_json
+SELECT mid, version, title, tile, first_release, last_update, mod_json, source
 FROM mods
 WHERE (mid = :mid) AND (version = :version)
 LIMIT 1;
@@ -566,9 +1263,492 @@ WHERE (mid = :mid)
 "#;
 
 static SEARCH_STMT: &str = r#"
-SELECT mid, version, title, tile, max(last_update)
-FROM mods
-WHERE (title LIKE :query)
-GROUP BY mid
-ORDER BY title;
+SELECT m.mid, m.version, m.title, m.tile,
+       snippet(mods_search, -1, :start, :end, '...', 12)
+FROM mods_search s
+JOIN mods m ON m.id = s.rowid
+WHERE mods_search MATCH :query
+"#;
+
+// Ties on last_update go to the release stored last.
+static NEWEST_RELEASE_COND: &str = r#"
+AND m.id = (SELECT n.id FROM mods n WHERE n.mid = m.mid
+            ORDER BY n.last_update DESC, n.id DESC LIMIT 1)
+"#;
+
+// bm25() weighs the columns in order: mid, title, description, notes,
+// packages. Lower ranks are better matches.
+static SEARCH_ORDER: &str = r#"
+ORDER BY bm25(mods_search, 10.0, 5.0, 1.0, 0.5, 2.0), m.last_update DESC;
+"#;
+
+static FILTER_STMT: &str = r#"
+SELECT m.mid, m.version, m.title, m.tile, ''
+FROM mods m
+WHERE 1
+"#;
+
+static FILTER_ORDER: &str = r#"
+ORDER BY m.last_update DESC;
+"#;
+
+static FILTER_TYPE_COND: &str = r#"
+AND json_extract(m.mod_json, '$.type') = :type
+"#;
+
+static FILTER_STABILITY_COND: &str = r#"
+AND json_extract(m.mod_json, '$.stability') = :stability
+"#;
+
+static FILTER_PARENT_COND: &str = r#"
+AND json_extract(m.mod_json, '$.parent') = :parent
+"#;
+
+static FILTER_RELEASED_FROM_COND: &str = r#"
+AND m.first_release >= :released_from
+"#;
+
+// Compares only as much of the date as given, so that `2023` includes
+// every day of 2023.
+static FILTER_RELEASED_TO_COND: &str = r#"
+AND substr(m.first_release, 1, length(:released_to)) <= :released_to
+"#;
+
+static FILTER_UPDATED_FROM_COND: &str = r#"
+AND m.last_update >= :updated_from
+"#;
+
+static FILTER_UPDATED_TO_COND: &str = r#"
+AND substr(m.last_update, 1, length(:updated_to)) <= :updated_to
+"#;
+
+static FILTER_SIZE_FROM_COND: &str = r#"
+AND (SELECT COALESCE(SUM(f.filesize), 0)
+     FROM package_files f
+     JOIN packages p ON p.id = f.package_id
+     WHERE f.mod_id = m.id AND p.status IN ('required', 'recommended')) >= :size_from
+"#;
+
+static FILTER_SIZE_TO_COND: &str = r#"
+AND (SELECT COALESCE(SUM(f.filesize), 0)
+     FROM package_files f
+     JOIN packages p ON p.id = f.package_id
+     WHERE f.mod_id = m.id AND p.status IN ('required', 'recommended')) <= :size_to
 "#;
+
+static INSERT_SEARCH_STMT: &str = r#"
+INSERT
+INTO mods_search (rowid, mid, title, description, notes, packages)
+VALUES (:mod_id, :mid, :title, :description, :notes, :packages);
+"#;
+
+static DELETE_SEARCH_STMT: &str = r#"
+DELETE FROM mods_search WHERE rowid = :mod_id;
+"#;
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use serde_json::json;
+
+  /// An in-memory repo holding `releases`.
+  pub(crate) fn db(releases: &[Value]) -> Connection {
+    let conn = open_read_write(Path::new(":memory:")).unwrap();
+    let mut updater = Updater::new(&conn, false).unwrap();
+    for r in releases {
+      updater.add("test", r).unwrap();
+    }
+    updater.finish().unwrap();
+    conn
+  }
+
+  fn release(mid: &str, version: &str, title: &str, description: &str, updated: &str) -> Value {
+    json!({
+      "id": mid, "title": title, "version": version, "description": description,
+      "last_update": updated,
+      "packages": [{"name": "Core", "status": "required", "files": [], "filelist": []}]
+    })
+  }
+
+  fn search(conn: &Connection, query: &str) -> Vec<String> {
+    search_mods(conn, query, &SearchFilter::default())
+      .unwrap()
+      .iter()
+      .map(|r| format!("{} {}", r.mid, r.version))
+      .collect()
+  }
+
+  #[test]
+  fn fts_query_quotes_words() {
+    assert_eq!(fts_query("shivan war"), r#""shivan"* "war"*"#);
+    assert_eq!(
+      fts_query(r#"  a"b  OR NEAR(c) "#),
+      r#""a""b"* "OR"* "NEAR(c)"*"#
+    );
+    assert_eq!(fts_query(" \t"), "");
+  }
+
+  #[test]
+  fn search_ranks_mid_and_title_above_description() {
+    let conn = db(&[
+      release("lore", "1.0", "Lore", "All about the Shivans", "2023-01-01"),
+      release("shivans", "1.0", "Shivan War", "A campaign", "2020-01-01"),
+    ]);
+
+    assert_eq!(search(&conn, "shivan"), ["shivans 1.0", "lore 1.0"]);
+    assert_eq!(search(&conn, "SHIV"), ["shivans 1.0", "lore 1.0"]);
+    assert!(search(&conn, "vasudan").is_empty());
+    // FTS5 syntax and stray quotes are taken literally.
+    assert!(search(&conn, r#"" OR NEAR(*"#).is_empty());
+    assert!(search(&conn, "-").is_empty());
+  }
+
+  #[test]
+  fn search_matches_newest_release_only() {
+    let conn = db(&[
+      release("MVPS", "4.5.1", "MediaVPs", "Ancient effects", "2020-01-01"),
+      release("MVPS", "4.6.0", "MediaVPs", "Modern effects", "2023-01-01"),
+    ]);
+
+    assert_eq!(search(&conn, "effects"), ["MVPS 4.6.0"]);
+    assert_eq!(search(&conn, "modern"), ["MVPS 4.6.0"]);
+    assert!(search(&conn, "ancient").is_empty());
+  }
+
+  #[test]
+  fn search_marks_matches_in_snippet() {
+    let conn = db(&[release(
+      "MVPS",
+      "4.6.0",
+      "MediaVPs",
+      "New effects for every ship",
+      "2023-01-01",
+    )]);
+
+    let results = search_mods(&conn, "eff", &SearchFilter::default()).unwrap();
+    assert_eq!(results.len(), 1);
+    let marked = format!("{}effects{}", SNIPPET_START, SNIPPET_END);
+    assert!(
+      results[0].snippet.contains(&marked),
+      "{:?}",
+      results[0].snippet
+    );
+
+    let listed = search_mods(&conn, "", &SearchFilter::default()).unwrap();
+    assert_eq!(listed[0].snippet, "");
+  }
+
+  #[test]
+  fn parse_range_takes_open_and_single_bounds() {
+    let text = |v: &str| Some(v.to_string());
+    let range =
+      |from: Option<&str>, to: Option<&str>| Some((from.map(String::from), to.map(String::from)));
+    assert_eq!(
+      parse_range("2023..2024", text),
+      range(Some("2023"), Some("2024"))
+    );
+    assert_eq!(parse_range(" 2023 ..", text), range(Some("2023"), None));
+    assert_eq!(parse_range("..2024", text), range(None, Some("2024")));
+    assert_eq!(parse_range("..", text), range(None, None));
+    assert_eq!(parse_range("2023", text), range(Some("2023"), Some("2023")));
+
+    let size = download_engine::parse_size;
+    assert_eq!(parse_range("1K..2K", size), Some((Some(1024), Some(2048))));
+    assert_eq!(parse_range("1X..2K", size), None);
+    assert_eq!(parse_range("..big", size), None);
+    assert_eq!(parse_range("big", size), None);
+  }
+
+  #[test]
+  fn search_filter_set_checks_names_and_values() {
+    let mut filter = SearchFilter::default();
+    assert!(filter.is_empty());
+
+    filter.set("type", "tc").unwrap();
+    filter.set("updated", "2023..").unwrap();
+    filter.set("size", " ..500M ").unwrap();
+    assert_eq!(filter.mod_type.as_deref(), Some("tc"));
+    assert_eq!(filter.last_update, (Some("2023".to_string()), None));
+    assert_eq!(filter.size, (None, Some(500 * 1024 * 1024)));
+    assert!(!filter.is_empty());
+
+    for (name, value) in [
+      ("type", " "),
+      ("size", "lots"),
+      ("stability", ""),
+      ("color", "red"),
+    ] {
+      assert!(
+        matches!(filter.set(name, value), Err(Error::InvalidFilter { .. })),
+        "{}={}",
+        name,
+        value
+      );
+    }
+  }
+
+  #[test]
+  fn filters_apply_to_newest_release_and_default_packages() {
+    let package = |name: &str, status: &str, size: u64| {
+      json!({
+        "name": name, "status": status, "filelist": [],
+        "files": [{"filename": "a.7z", "checksum": ["sha256", "0"], "filesize": size}]
+      })
+    };
+    let mut old = release("MVPS", "4.5.1", "MediaVPs", "", "2020-01-01");
+    old["type"] = json!("tc");
+    let mut new = release("MVPS", "4.6.0", "MediaVPs", "", "2023-01-01");
+    new["type"] = json!("mod");
+    new["packages"] = json!([
+      package("Core", "required", 100),
+      package("HD", "recommended", 200),
+      package("Music", "optional", 5000)
+    ]);
+    let conn = db(&[old, new]);
+
+    let filtered = |name: &str, value: &str| {
+      let mut filter = SearchFilter::default();
+      filter.set(name, value).unwrap();
+      let results = search_mods(&conn, "", &filter).unwrap();
+      results
+        .iter()
+        .map(|r| format!("{} {}", r.mid, r.version))
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(filtered("type", "mod"), ["MVPS 4.6.0"]);
+    assert!(filtered("type", "tc").is_empty());
+    assert!(filtered("updated", "..2022").is_empty());
+    assert_eq!(filtered("size", "300"), ["MVPS 4.6.0"]);
+    assert!(filtered("size", "1K..").is_empty());
+  }
+
+  #[test]
+  fn first_source_wins_and_list_filters_by_source() {
+    let conn = open_read_write(Path::new(":memory:")).unwrap();
+    let mut updater = Updater::new(&conn, false).unwrap();
+    updater
+      .add(
+        "main",
+        &release("MVPS", "4.6.0", "MediaVPs", "", "2023-01-01"),
+      )
+      .unwrap();
+    updater
+      .add(
+        "legacy",
+        &release("MVPS", "4.6.0", "Shadowed", "", "2023-01-01"),
+      )
+      .unwrap();
+    updater
+      .add(
+        "legacy",
+        &release("MVPS", "4.5.1", "MediaVPs", "", "2020-01-01"),
+      )
+      .unwrap();
+    updater
+      .add(
+        "legacy",
+        &release("str", "1.6.0", "Silent Threat", "", "2021-01-01"),
+      )
+      .unwrap();
+    updater.finish().unwrap();
+
+    let stored = |version: &str| {
+      get_mod(&conn, &"MVPS".to_string(), &Some(version.to_string()))
+        .unwrap()
+        .unwrap()
+    };
+    assert_eq!(stored("4.6.0").title, "MediaVPs");
+    assert_eq!(stored("4.6.0").source, "main");
+    assert_eq!(stored("4.5.1").source, "legacy");
+
+    let from = |source: &str| {
+      list_mods_from(&conn, source)
+        .unwrap()
+        .into_iter()
+        .map(|m| format!("{} {}", m.mid, m.version))
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(from("main"), ["MVPS 4.6.0"]);
+    assert_eq!(from("legacy"), ["MVPS 4.5.1", "str 1.6.0"]);
+    assert!(from("other").is_empty());
+  }
+
+  #[test]
+  fn lookups_of_unknown_mods_fail() {
+    let conn = db(&[release("MVPS", "4.6.0", "MediaVPs", "", "2023-01-01")]);
+    let mid = "MVPS".to_string();
+    let unknown = "Unknown".to_string();
+    let missing = Some("1.0".to_string());
+    let not_found = |result: Result<()>| matches!(result, Err(Error::ModNotFound { .. }));
+
+    let commands = [json, cmdline, modline, sha256sum, dlsize];
+    for command in commands {
+      assert!(command(&conn, &mid, &None).is_ok());
+      assert!(not_found(command(&conn, &unknown, &None)));
+      assert!(not_found(command(&conn, &mid, &missing)));
+    }
+    assert!(matches!(
+      get_sha256sum(&conn, &unknown, &None),
+      Err(Error::ModNotFound { .. })
+    ));
+  }
+
+  #[test]
+  fn updates_replace_and_deletes_remove_package_rows() {
+    let conn = open_read_write(Path::new(":memory:")).unwrap();
+    let sync = |releases: &[&Value]| {
+      let mut updater = Updater::new(&conn, false).unwrap();
+      for r in releases {
+        updater.add("test", r).unwrap();
+      }
+      updater.finish().unwrap();
+    };
+    let package = |name: &str, file: &str, dep: &str, exe: &str| {
+      json!({
+        "name": name, "status": "required",
+        "files": [{"filename": file, "checksum": ["sha256", "00"], "filesize": 1, "urls": []}],
+        "filelist": [{"filename": format!("{}.vp", name), "archive": file, "checksum": ["sha256", "00"]}],
+        "dependencies": [{"id": "FSO", "version": dep, "packages": []}],
+        "executables": [{"file": exe}]
+      })
+    };
+    let mvps = |packages: Vec<Value>| json!({"id": "MVPS", "title": "MediaVPs", "version": "4.6.0", "packages": packages});
+    let st = json!({
+      "id": "str", "title": "Silent Threat", "version": "1.6.0",
+      "packages": [package("Core", "str.7z", ">=19", "str.exe")]
+    });
+    // One line per row of the package tables, naming the release it
+    // belongs to.
+    let rows = || {
+      let sql = "
+        SELECT m.mid || ' ' || p.name FROM packages p JOIN mods m ON m.id = p.mod_id
+        UNION ALL
+        SELECT m.mid || ' file ' || f.filename FROM package_files f JOIN mods m ON m.id = f.mod_id
+        UNION ALL
+        SELECT m.mid || ' dep ' || d.dep_mid || ' ' || d.version
+        FROM package_dependencies d JOIN mods m ON m.id = d.mod_id
+        UNION ALL
+        SELECT m.mid || ' exe ' || e.file FROM executables e JOIN mods m ON m.id = e.mod_id
+        UNION ALL
+        SELECT m.mid || ' list ' || l.filename FROM filelist l JOIN mods m ON m.id = l.mod_id
+        UNION ALL
+        SELECT m.mid || ' search ' || s.packages FROM mods_search s JOIN mods m ON m.id = s.rowid";
+      let mut select = conn.prepare(sql).unwrap();
+      let mut rows: Vec<String> = select
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+      rows.sort();
+      rows
+    };
+    let st_rows = [
+      "str Core",
+      "str dep FSO >=19",
+      "str exe str.exe",
+      "str file str.7z",
+      "str list Core.vp",
+      "str search Core",
+    ];
+
+    sync(&[
+      &mvps(vec![package("Core", "core.7z", ">=23", "fs2.exe")]),
+      &st,
+    ]);
+    let mut expected = vec![
+      "MVPS Core",
+      "MVPS dep FSO >=23",
+      "MVPS exe fs2.exe",
+      "MVPS file core.7z",
+      "MVPS list Core.vp",
+      "MVPS search Core",
+    ];
+    expected.extend(st_rows);
+    assert_eq!(rows(), expected);
+
+    sync(&[
+      &mvps(vec![
+        package("Core", "core2.7z", ">=23.1", "fs2.exe"),
+        package("HD", "hd.7z", ">=23.1", "fs2_hd.exe"),
+      ]),
+      &st,
+    ]);
+    let mut expected = vec![
+      "MVPS Core",
+      "MVPS HD",
+      "MVPS dep FSO >=23.1",
+      "MVPS dep FSO >=23.1",
+      "MVPS exe fs2.exe",
+      "MVPS exe fs2_hd.exe",
+      "MVPS file core2.7z",
+      "MVPS file hd.7z",
+      "MVPS list Core.vp",
+      "MVPS list HD.vp",
+      "MVPS search Core HD",
+    ];
+    expected.extend(st_rows);
+    assert_eq!(rows(), expected);
+
+    sync(&[&st]);
+    assert_eq!(rows(), st_rows);
+  }
+
+  #[test]
+  fn versions_filter_by_spec_and_pick_latest_match() {
+    let conn = db(
+      &["4.5.0-RC1", "4.5.0", "4.5.2", "4.6.0-RC1", "4.6.0", "5.0.0"]
+        .map(|v| release("MVPS", v, "MediaVPs", "", "2023-01-01")),
+    );
+    let mid = "MVPS".to_string();
+    let query = |spec: Option<&str>, stable_only: bool, latest: bool| VersionQuery {
+      spec: spec.map(|s| VersionSpec::parse(s).unwrap()),
+      stable_only,
+      latest,
+    };
+    let matching = |q: VersionQuery| matching_versions(&conn, &mid, &q);
+
+    assert_eq!(
+      matching(query(None, false, false)).unwrap(),
+      ["5.0.0", "4.6.0", "4.6.0-RC1", "4.5.2", "4.5.0", "4.5.0-RC1"]
+    );
+    assert_eq!(
+      matching(query(Some("4.5"), false, false)).unwrap(),
+      ["4.5.2", "4.5.0"]
+    );
+    assert_eq!(
+      matching(query(Some("4.5.0-RC1"), false, false)).unwrap(),
+      ["4.5.0-RC1"]
+    );
+    assert_eq!(
+      matching(query(Some(">=4.5, <5"), false, false)).unwrap(),
+      ["4.6.0", "4.6.0-RC1", "4.5.2", "4.5.0"]
+    );
+    assert_eq!(
+      matching(query(Some(">=4.5, <5"), true, false)).unwrap(),
+      ["4.6.0", "4.5.2", "4.5.0"]
+    );
+
+    assert_eq!(
+      matching(query(Some("4.5"), false, true)).unwrap(),
+      ["4.5.2"]
+    );
+    assert_eq!(matching(query(None, true, true)).unwrap(), ["5.0.0"]);
+    assert_eq!(
+      matching(query(Some("<4.6"), true, true)).unwrap(),
+      ["4.5.2"]
+    );
+    match matching(query(Some("~4.7"), false, true)) {
+      Err(Error::NoMatchingVersion { mid, spec }) => {
+        assert_eq!((mid.as_str(), spec.as_str()), ("MVPS", "~4.7"))
+      }
+      other => panic!("expected no matching version, got {:?}", other),
+    }
+    assert!(matching(query(Some("~4.7"), false, false))
+      .unwrap()
+      .is_empty());
+    assert!(matches!(
+      matching_versions(&conn, &"Unknown".to_string(), &query(None, false, true)),
+      Err(Error::NoMatchingVersion { .. })
+    ));
+  }
+}
@@ -0,0 +1,746 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::str::FromStr;
+
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+use crate::knossos::KnossosMod;
+use crate::repo;
+use crate::version::VersionSpec;
+
+/// Give up if picking versions keeps invalidating earlier picks.
+const MAX_PASSES: usize = 100;
+
+/// A mod the user asked for. The required packages are always selected,
+/// plus `packages` or, without them, the recommended packages.
+#[derive(Debug, Clone)]
+pub struct Selection {
+  pub mid: String,
+  pub version: Option<String>,
+  pub packages: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Resolution {
+  /// Every mod of the install set, each listed before its dependencies.
+  pub mods: Vec<ResolvedMod>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct ResolvedMod {
+  pub mid: String,
+  pub version: String,
+  pub title: String,
+  #[serde(rename = "type")]
+  pub mod_type: String,
+  pub packages: Vec<String>,
+  pub dependencies: Vec<Requirement>,
+  #[serde(skip)]
+  pub knossos_mod: KnossosMod,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Requirement {
+  pub mid: String,
+  pub version: Option<String>,
+  pub packages: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Constraint {
+  spec: VersionSpec,
+  /// The mod and version whose dependency added this, `None` for a
+  /// selection.
+  required_by: Option<(String, String)>,
+}
+
+impl Constraint {
+  fn required_by(&self) -> String {
+    match &self.required_by {
+      Some((mid, version)) => format!("{} {}", mid, version),
+      None => "selection".to_string(),
+    }
+  }
+
+  /// Whether the mod that added this is picked in the current pass.
+  fn is_picked(&self, chosen: &BTreeMap<String, String>) -> bool {
+    match &self.required_by {
+      Some((mid, version)) => chosen.get(mid) == Some(version),
+      None => true,
+    }
+  }
+
+  fn describe(&self) -> String {
+    format!("{} (required by {})", self.spec, self.required_by())
+  }
+}
+
+enum Pass {
+  Resolved(Resolution),
+  /// The mod whose earlier pick was ruled out.
+  Restart(String),
+}
+
+struct Resolver<'a> {
+  conn: &'a Connection,
+  releases: HashMap<(String, String), KnossosMod>,
+  versions: HashMap<String, Vec<String>>,
+  constraints: BTreeMap<String, Vec<Constraint>>,
+}
+
+pub fn resolve(conn: &Connection, selections: &[Selection]) -> Result<Resolution> {
+  Resolver::new(conn).resolve(selections)
+}
+
+impl<'a> Resolver<'a> {
+  fn new(conn: &'a Connection) -> Resolver<'a> {
+    Resolver {
+      conn,
+      releases: HashMap::new(),
+      versions: HashMap::new(),
+      constraints: BTreeMap::new(),
+    }
+  }
+
+  fn resolve(&mut self, selections: &[Selection]) -> Result<Resolution> {
+    for s in selections {
+      let spec = match &s.version {
+        Some(v) => VersionSpec::exact(v),
+        None => VersionSpec::any(),
+      };
+
+      self.add_constraint(&s.mid, spec, None);
+    }
+
+    // A restart keeps the constraint that caused it, so the next pass picks
+    // differently. Constraints added by a version that is no longer picked
+    // are dropped once its replacement is chosen.
+    let mut restarts: BTreeMap<String, usize> = BTreeMap::new();
+    for _ in 0..MAX_PASSES {
+      match self.try_resolve(selections)? {
+        Pass::Resolved(resolution) => return Ok(resolution),
+        Pass::Restart(mid) => *restarts.entry(mid).or_default() += 1,
+      }
+    }
+
+    let mid = restarts
+      .iter()
+      .max_by_key(|(_, &n)| n)
+      .map(|(mid, _)| mid.clone())
+      .unwrap_or_default();
+    let mut constraints: Vec<String> = self
+      .constraints
+      .get(&mid)
+      .map(|cs| cs.iter().map(Constraint::describe).collect())
+      .unwrap_or_default();
+    constraints.push("no stable set of versions found".to_string());
+
+    Err(Error::DependencyConflict { mid, constraints })
+  }
+
+  /// Starts over when a newly found constraint rules out a version that
+  /// was already picked.
+  fn try_resolve(&mut self, selections: &[Selection]) -> Result<Pass> {
+    let mut chosen: BTreeMap<String, String> = BTreeMap::new();
+    let mut packages: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    let mut queue: Vec<String> = Vec::new();
+
+    for s in selections {
+      let version = self.pick(&s.mid, &chosen)?;
+      let km = self.release(&s.mid, &version)?;
+      let selected = match &s.packages {
+        Some(names) => {
+          for name in names {
+            if !km.packages.iter().any(|p| &p.name == name) {
+              return Err(Error::PackageNotFound {
+                mid: s.mid.clone(),
+                package: name.clone(),
+              });
+            }
+          }
+          let mut selected = default_packages(km, &["required"]);
+          selected.extend(names.iter().cloned());
+          selected
+        }
+        None => default_packages(km, &["required", "recommended"]),
+      };
+
+      self.choose(&mut chosen, &s.mid, &version);
+      packages.entry(s.mid.clone()).or_default().extend(selected);
+      queue.push(s.mid.clone());
+    }
+
+    while let Some(mid) = queue.pop() {
+      let version = chosen[&mid].clone();
+      let km = self.release(&mid, &version)?.clone();
+      let selected = packages[&mid].clone();
+
+      for p in km.packages.iter().filter(|p| selected.contains(&p.name)) {
+        for d in &p.dependencies {
+          let spec = match &d.version {
+            Some(v) => VersionSpec::parse(v)?,
+            None => VersionSpec::any(),
+          };
+
+          let required_by = Some((mid.clone(), version.clone()));
+          let ruled_out = matches!(chosen.get(&d.id), Some(v) if !spec.matches(v));
+          self.add_constraint(&d.id, spec, required_by);
+
+          if ruled_out {
+            // Only start over when some version would satisfy every pick
+            // made so far, otherwise the next pass would end up here again.
+            self.pick_for(&d.id, &chosen)?;
+            return Ok(Pass::Restart(d.id.clone()));
+          }
+
+          let dep_version = match chosen.get(&d.id) {
+            Some(v) => v.clone(),
+            None => {
+              let v = self.pick(&d.id, &chosen)?;
+              self.choose(&mut chosen, &d.id, &v);
+              v
+            }
+          };
+
+          let dep = self.release(&d.id, &dep_version)?;
+          let mut wanted = default_packages(dep, &["required"]);
+          wanted.extend(d.packages.iter().cloned());
+
+          let have = packages.entry(d.id.clone()).or_default();
+          let before = have.len();
+          have.extend(wanted);
+
+          if before == 0 || have.len() != before {
+            queue.push(d.id.clone());
+          }
+        }
+      }
+    }
+
+    let mut mods = BTreeMap::new();
+    for (mid, version) in &chosen {
+      let km = self.release(mid, version)?.clone();
+      let selected = &packages[mid];
+      let dependencies = km
+        .packages
+        .iter()
+        .filter(|p| selected.contains(&p.name))
+        .flat_map(|p| p.dependencies.iter())
+        .map(|d| Requirement {
+          mid: d.id.clone(),
+          version: d.version.clone(),
+          packages: d.packages.clone(),
+        })
+        .collect();
+
+      mods.insert(
+        mid.clone(),
+        ResolvedMod {
+          mid: mid.clone(),
+          version: version.clone(),
+          title: km.title.clone(),
+          mod_type: km.mod_type.clone(),
+          packages: km
+            .packages
+            .iter()
+            .filter(|p| selected.contains(&p.name))
+            .map(|p| p.name.clone())
+            .collect(),
+          dependencies,
+          knossos_mod: km,
+        },
+      );
+    }
+
+    let roots: Vec<&str> = selections.iter().map(|s| s.mid.as_str()).collect();
+    let order = dependency_order(&roots, &mods)?;
+    let mods = order
+      .iter()
+      .filter_map(|mid| mods.remove(mid.as_str()))
+      .collect();
+
+    Ok(Pass::Resolved(Resolution { mods }))
+  }
+
+  fn add_constraint(
+    &mut self,
+    mid: &str,
+    spec: VersionSpec,
+    required_by: Option<(String, String)>,
+  ) {
+    if spec.is_any() {
+      return;
+    }
+
+    let constraints = self.constraints.entry(mid.to_string()).or_default();
+    let constraint = Constraint { spec, required_by };
+
+    if !constraints.contains(&constraint) {
+      constraints.push(constraint);
+    }
+  }
+
+  /// Records `version` as the pick for `mid`, dropping the constraints that
+  /// any other version of it added in an earlier pass.
+  fn choose(&mut self, chosen: &mut BTreeMap<String, String>, mid: &str, version: &str) {
+    for constraints in self.constraints.values_mut() {
+      constraints.retain(|c| match &c.required_by {
+        Some((by, v)) => by != mid || v == version,
+        None => true,
+      });
+    }
+
+    chosen.insert(mid.to_string(), version.to_string());
+  }
+
+  /// Picks the newest release of `mid` satisfying every constraint on it.
+  /// Constraints from mods not picked in this pass may be stale, so when
+  /// they cannot all be met only those from picked mods must hold.
+  fn pick(&mut self, mid: &str, chosen: &BTreeMap<String, String>) -> Result<String> {
+    let versions = self.versions(mid)?;
+    let constraints = self.constraints.get(mid).map(Vec::as_slice).unwrap_or(&[]);
+
+    if versions.is_empty() {
+      return Err(Error::ModNotFound {
+        mid: mid.to_string(),
+        required_by: constraints.first().map(Constraint::required_by),
+      });
+    }
+
+    match versions
+      .iter()
+      .find(|v| constraints.iter().all(|c| c.spec.matches(v)))
+    {
+      Some(v) => Ok(v.clone()),
+      None => self.pick_for(mid, chosen),
+    }
+  }
+
+  /// Picks the newest release of `mid` satisfying the constraints added by
+  /// the mods picked in this pass.
+  fn pick_for(&mut self, mid: &str, chosen: &BTreeMap<String, String>) -> Result<String> {
+    let versions = self.versions(mid)?;
+    let constraints: Vec<&Constraint> = self
+      .constraints
+      .get(mid)
+      .map(|cs| cs.iter().filter(|c| c.is_picked(chosen)).collect())
+      .unwrap_or_default();
+
+    versions
+      .iter()
+      .find(|v| constraints.iter().all(|c| c.spec.matches(v)))
+      .cloned()
+      .ok_or_else(|| Error::DependencyConflict {
+        mid: mid.to_string(),
+        constraints: constraints.iter().map(|c| c.describe()).collect(),
+      })
+  }
+
+  fn versions(&mut self, mid: &str) -> Result<Vec<String>> {
+    if !self.versions.contains_key(mid) {
+      let versions = repo::get_sorted_versions(self.conn, &mid.to_string())?;
+      self.versions.insert(mid.to_string(), versions);
+    }
+
+    Ok(self.versions[mid].clone())
+  }
+
+  fn release(&mut self, mid: &str, version: &str) -> Result<&KnossosMod> {
+    let key = (mid.to_string(), version.to_string());
+
+    if !self.releases.contains_key(&key) {
+      let m = repo::get_mod(self.conn, &key.0, &Some(key.1.clone()))?.ok_or_else(|| {
+        Error::ModNotFound {
+          mid: mid.to_string(),
+          required_by: None,
+        }
+      })?;
+      self.releases.insert(key.clone(), m.knossos_mod()?);
+    }
+
+    Ok(&self.releases[&key])
+  }
+}
+
+fn default_packages(km: &KnossosMod, statuses: &[&str]) -> BTreeSet<String> {
+  km.packages
+    .iter()
+    .filter(|p| statuses.contains(&p.status.as_deref().unwrap_or("")))
+    .map(|p| p.name.clone())
+    .collect()
+}
+
+/// Orders mods so that each one comes before all of its dependencies.
+/// Among the mods free to go next, the selected mods win in the order they
+/// were given, then dependencies in the order they were discovered.
+fn dependency_order(roots: &[&str], mods: &BTreeMap<String, ResolvedMod>) -> Result<Vec<String>> {
+  check_cycles(roots, mods)?;
+
+  let mut rank: HashMap<&str, usize> = HashMap::new();
+  let mut queue: VecDeque<&str> = roots.iter().copied().collect();
+  while let Some(mid) = queue.pop_front() {
+    if rank.contains_key(mid) {
+      continue;
+    }
+
+    rank.insert(mid, rank.len());
+    if let Some(m) = mods.get(mid) {
+      queue.extend(m.dependencies.iter().map(|d| d.mid.as_str()));
+    }
+  }
+
+  let mut indegree: HashMap<&str, usize> = rank.keys().map(|&mid| (mid, 0)).collect();
+  for &mid in rank.keys() {
+    for dep in dependency_set(mods, mid) {
+      *indegree.entry(dep).or_default() += 1;
+    }
+  }
+
+  let mut ready: BTreeSet<(usize, &str)> = indegree
+    .iter()
+    .filter(|(_, &n)| n == 0)
+    .map(|(&mid, _)| (rank[mid], mid))
+    .collect();
+  let mut order = Vec::new();
+
+  while let Some(next) = ready.iter().next().copied() {
+    ready.remove(&next);
+    let mid = next.1;
+    order.push(mid.to_string());
+
+    for dep in dependency_set(mods, mid) {
+      let n = indegree.entry(dep).or_default();
+      *n -= 1;
+      if *n == 0 {
+        ready.insert((rank[dep], dep));
+      }
+    }
+  }
+
+  Ok(order)
+}
+
+fn dependency_set<'a>(mods: &'a BTreeMap<String, ResolvedMod>, mid: &str) -> BTreeSet<&'a str> {
+  mods
+    .get(mid)
+    .map(|m| m.dependencies.iter().map(|d| d.mid.as_str()).collect())
+    .unwrap_or_default()
+}
+
+fn check_cycles(roots: &[&str], mods: &BTreeMap<String, ResolvedMod>) -> Result<()> {
+  #[derive(Clone, Copy, PartialEq)]
+  enum Mark {
+    Visiting,
+    Done,
+  }
+
+  fn visit(
+    mid: &str,
+    mods: &BTreeMap<String, ResolvedMod>,
+    marks: &mut HashMap<String, Mark>,
+    path: &mut Vec<String>,
+  ) -> Result<()> {
+    match marks.get(mid) {
+      Some(Mark::Done) => return Ok(()),
+      Some(Mark::Visiting) => {
+        let start = path.iter().position(|m| m == mid).unwrap_or(0);
+        let mut cycle = path[start..].to_vec();
+        cycle.push(mid.to_string());
+        return Err(Error::DependencyCycle(cycle));
+      }
+      None => {}
+    }
+
+    marks.insert(mid.to_string(), Mark::Visiting);
+    path.push(mid.to_string());
+
+    for dep in dependency_set(mods, mid) {
+      visit(dep, mods, marks, path)?;
+    }
+
+    path.pop();
+    marks.insert(mid.to_string(), Mark::Done);
+    Ok(())
+  }
+
+  let mut marks = HashMap::new();
+
+  for root in roots {
+    visit(root, mods, &mut marks, &mut Vec::new())?;
+  }
+
+  Ok(())
+}
+
+/// The `-mod` list for the resolved mods: highest priority first, with the
+/// engine left out since it is not a mod folder.
+pub fn mod_flag(resolution: &Resolution) -> Vec<String> {
+  resolution
+    .mods
+    .iter()
+    .filter(|m| !m.knossos_mod.is_engine())
+    .map(|m| m.mid.clone())
+    .collect()
+}
+
+/// Prints the computed `-mod` list, warning on stderr when it differs from
+/// the `mod_flag` stored for the first selected mod.
+pub fn print_mod_flag(resolution: &Resolution) -> Result<()> {
+  let computed = mod_flag(resolution);
+
+  if let Some(primary) = resolution.mods.first() {
+    let stored = &primary.knossos_mod.mod_flag;
+    if stored != &computed {
+      eprintln!(
+        "Warning: stored mod_flag of {} ({}) is '{}', computed '{}'",
+        primary.mid,
+        primary.version,
+        stored.join(","),
+        computed.join(",")
+      );
+    }
+  }
+
+  match resolution.mods.first() {
+    Some(primary) => repo::print_mod_flag(&primary.mid, &primary.version, &computed),
+    None => Ok(()),
+  }
+}
+
+impl FromStr for Selection {
+  type Err = Error;
+
+  /// Parses `MID[@VERSION][:PACKAGE,PACKAGE...]`.
+  fn from_str(text: &str) -> Result<Selection> {
+    let (rest, packages) = match text.split_once(':') {
+      Some((rest, packages)) => (
+        rest,
+        Some(
+          packages
+            .split(',')
+            .map(|p| p.trim().to_string())
+            .filter(|p| !p.is_empty())
+            .collect(),
+        ),
+      ),
+      None => (text, None),
+    };
+
+    let (mid, version) = match rest.split_once('@') {
+      Some((mid, version)) => (mid, Some(version.to_string())),
+      None => (rest, None),
+    };
+
+    if mid.is_empty() {
+      return Err(Error::InvalidSelection(text.to_string()));
+    }
+
+    Ok(Selection {
+      mid: mid.to_string(),
+      version,
+      packages,
+    })
+  }
+}
+
+pub fn print_tree(resolution: &Resolution) {
+  let mods: HashMap<&str, &ResolvedMod> = resolution
+    .mods
+    .iter()
+    .map(|m| (m.mid.as_str(), m))
+    .collect();
+  let dependents: BTreeSet<&str> = resolution
+    .mods
+    .iter()
+    .flat_map(|m| m.dependencies.iter().map(|d| d.mid.as_str()))
+    .collect();
+  let mut printed = BTreeSet::new();
+
+  for m in &resolution.mods {
+    if !dependents.contains(m.mid.as_str()) {
+      print_node(m, None, 0, &mods, &mut printed);
+    }
+  }
+}
+
+fn print_node<'a>(
+  m: &'a ResolvedMod,
+  spec: Option<&str>,
+  depth: usize,
+  mods: &HashMap<&str, &'a ResolvedMod>,
+  printed: &mut BTreeSet<&'a str>,
+) {
+  let indent = "  ".repeat(depth);
+  let spec = spec.map(|s| format!(" ({})", s)).unwrap_or_default();
+
+  if !printed.insert(m.mid.as_str()) {
+    println!("{}{} {}{} (see above)", indent, m.mid, m.version, spec);
+    return;
+  }
+
+  println!(
+    "{}{} {}{} [{}]",
+    indent,
+    m.mid,
+    m.version,
+    spec,
+    m.packages.join(", ")
+  );
+
+  let mut seen = BTreeSet::new();
+  for d in &m.dependencies {
+    if !seen.insert(d.mid.as_str()) {
+      continue;
+    }
+
+    if let Some(dep) = mods.get(d.mid.as_str()) {
+      print_node(dep, d.version.as_deref(), depth + 1, mods, printed);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::repo::tests::db;
+  use serde_json::{json, Value};
+
+  /// A release of `mid` whose required package depends on `deps`, each a
+  /// mod id and a version spec, with `""` for any version.
+  fn release(mid: &str, version: &str, deps: &[(&str, &str)]) -> Value {
+    let dependencies: Vec<Value> = deps
+      .iter()
+      .map(|(id, spec)| match *spec {
+        "" => json!({"id": id, "packages": []}),
+        _ => json!({"id": id, "version": spec, "packages": []}),
+      })
+      .collect();
+
+    json!({
+      "id": mid, "title": mid, "version": version,
+      "packages": [{
+        "name": "Core", "status": "required", "files": [], "filelist": [],
+        "dependencies": dependencies
+      }]
+    })
+  }
+
+  fn versions(resolution: &Resolution) -> Vec<String> {
+    resolution
+      .mods
+      .iter()
+      .map(|m| format!("{} {}", m.mid, m.version))
+      .collect()
+  }
+
+  fn resolve_sel(conn: &Connection, sel: &str) -> Result<Resolution> {
+    resolve(conn, &[sel.parse().unwrap()])
+  }
+
+  #[test]
+  fn pinned_version_is_exact() {
+    let conn = db(&[
+      release("MVPS", "1.0", &[]),
+      release("MVPS", "1.0.5", &[]),
+      release("MVPS", "1.1", &[]),
+    ]);
+
+    assert_eq!(versions(&resolve_sel(&conn, "MVPS").unwrap()), ["MVPS 1.1"]);
+    assert_eq!(
+      versions(&resolve_sel(&conn, "MVPS@1.0").unwrap()),
+      ["MVPS 1.0"]
+    );
+    assert_eq!(
+      versions(&resolve_sel(&conn, "MVPS@1.0.5").unwrap()),
+      ["MVPS 1.0.5"]
+    );
+    assert!(matches!(
+      resolve_sel(&conn, "MVPS@1.2"),
+      Err(Error::DependencyConflict { .. })
+    ));
+  }
+
+  #[test]
+  fn later_constraint_restarts_resolution() {
+    // B 2.0 is picked for A first, then C rules it out.
+    let conn = db(&[
+      release("A", "1.0", &[("B", ""), ("C", ">=1")]),
+      release("B", "1.0", &[]),
+      release("B", "2.0", &[]),
+      release("C", "1.0", &[("B", "<2")]),
+    ]);
+
+    assert_eq!(
+      versions(&resolve_sel(&conn, "A").unwrap()),
+      ["A 1.0", "C 1.0", "B 1.0"]
+    );
+  }
+
+  #[test]
+  fn incompatible_constraints_conflict() {
+    let conn = db(&[
+      release("A", "1.0", &[("B", ">=2"), ("C", "")]),
+      release("B", "1.0", &[]),
+      release("B", "2.0", &[]),
+      release("C", "1.0", &[("B", "<2")]),
+    ]);
+
+    match resolve_sel(&conn, "A") {
+      Err(Error::DependencyConflict { mid, constraints }) => {
+        assert_eq!(mid, "B");
+        assert_eq!(constraints.len(), 2);
+      }
+      other => panic!("expected a conflict, got {:?}", other.map(|r| versions(&r))),
+    }
+  }
+
+  #[test]
+  fn restart_drops_constraints_of_replaced_picks() {
+    // B 2.0 wants C >= 2 until D rules it out; B 1.0 then wants C < 2.
+    let conn = db(&[
+      release("A", "1.0", &[("D", ""), ("B", "")]),
+      release("B", "1.0", &[("C", "<2")]),
+      release("B", "2.0", &[("C", ">=2")]),
+      release("C", "1.0", &[]),
+      release("C", "2.0", &[]),
+      release("D", "1.0", &[("B", "<2")]),
+    ]);
+
+    let mut resolved = versions(&resolve_sel(&conn, "A").unwrap());
+    resolved.sort();
+    assert_eq!(resolved, ["A 1.0", "B 1.0", "C 1.0", "D 1.0"]);
+  }
+
+  #[test]
+  fn named_packages_add_to_required() {
+    let mut mvps = release("MVPS", "1.0", &[]);
+    mvps["packages"] = json!([
+      {"name": "Core", "status": "required", "files": [], "filelist": []},
+      {"name": "HD", "status": "recommended", "files": [], "filelist": []},
+      {"name": "Extra", "status": "optional", "files": [], "filelist": []}
+    ]);
+    let conn = db(&[mvps]);
+
+    let packages = |sel: &str| resolve_sel(&conn, sel).unwrap().mods[0].packages.clone();
+    assert_eq!(packages("MVPS"), ["Core", "HD"]);
+    assert_eq!(packages("MVPS:Extra"), ["Core", "Extra"]);
+    assert!(matches!(
+      resolve_sel(&conn, "MVPS:Missing"),
+      Err(Error::PackageNotFound { .. })
+    ));
+  }
+
+  #[test]
+  fn dependency_cycle_is_reported() {
+    let conn = db(&[
+      release("A", "1.0", &[("B", "")]),
+      release("B", "1.0", &[("A", "")]),
+    ]);
+
+    match resolve_sel(&conn, "A") {
+      Err(Error::DependencyCycle(cycle)) => assert_eq!(cycle, ["A", "B", "A"]),
+      other => panic!("expected a cycle, got {:?}", other.map(|r| versions(&r))),
+    }
+  }
+}
@@ -0,0 +1,277 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::checksum;
+use crate::error::{Error, Result};
+use crate::installer;
+use crate::knossos::{FileListEntry, KnossosMod, Package};
+use crate::output;
+
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+  pub mid: String,
+  pub version: String,
+  pub packages: Vec<String>,
+  pub checked: usize,
+  pub missing: Vec<String>,
+  pub modified: Vec<Modified>,
+  pub extra: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Modified {
+  pub filename: String,
+  pub expected: String,
+  pub actual: String,
+}
+
+impl VerifyReport {
+  /// Extra files are reported but do not count as a failure.
+  pub fn is_ok(&self) -> bool {
+    self.missing.is_empty() && self.modified.is_empty()
+  }
+}
+
+/// Packages listed in the local mod.json of an installed mod folder, if
+/// there is one.
+pub fn installed_packages(dir: &Path) -> Result<Option<Vec<String>>> {
+  let path = dir.join("mod.json");
+  if !path.is_file() {
+    return Ok(None);
+  }
+
+  let bytes = fs::read(&path).map_err(Error::io(&path))?;
+  let local: Value = serde_json::from_slice(&bytes)?;
+  Ok(local["packages"].as_array().map(|packages| {
+    packages
+      .iter()
+      .filter_map(|p| p["name"].as_str().map(String::from))
+      .collect()
+  }))
+}
+
+/// Hashes the files of `dir` in parallel and compares them with the
+/// filelists of `packages`. Names are matched exactly first and then
+/// ignoring case, since mods are often built on Windows.
+pub fn verify(km: &KnossosMod, packages: &[&Package], dir: &Path) -> Result<VerifyReport> {
+  let expected: Vec<&FileListEntry> = packages.iter().flat_map(|p| p.filelist.iter()).collect();
+  for entry in &expected {
+    if entry.checksum.algorithm() != "sha256" {
+      return Err(Error::UnsupportedChecksum(
+        entry.checksum.algorithm().to_string(),
+      ));
+    }
+  }
+
+  let mut actual = BTreeMap::new();
+  list_files(dir, "", &mut actual)?;
+  let lowercase: HashMap<String, String> = actual
+    .keys()
+    .map(|name| (name.to_lowercase(), name.clone()))
+    .collect();
+
+  let mut report = VerifyReport {
+    mid: km.id.clone(),
+    version: km.version.clone(),
+    packages: packages.iter().map(|p| p.name.clone()).collect(),
+    checked: expected.len(),
+    missing: Vec::new(),
+    modified: Vec::new(),
+    extra: Vec::new(),
+  };
+
+  let mut to_hash = Vec::new();
+  for entry in &expected {
+    let found = if actual.contains_key(&entry.filename) {
+      Some(entry.filename.clone())
+    } else {
+      lowercase.get(&entry.filename.to_lowercase()).cloned()
+    };
+
+    match found {
+      Some(name) => {
+        actual.insert(name.clone(), true);
+        to_hash.push((*entry, name));
+      }
+      None => report.missing.push(entry.filename.clone()),
+    }
+  }
+
+  for (entry, digest) in hash_all(dir, &to_hash)? {
+    if !digest.eq_ignore_ascii_case(entry.checksum.digest()) {
+      report.modified.push(Modified {
+        filename: entry.filename.clone(),
+        expected: entry.checksum.digest().to_string(),
+        actual: digest,
+      });
+    }
+  }
+
+  report.extra = actual
+    .into_iter()
+    .filter(|(name, matched)| !matched && !is_bookkeeping_file(name))
+    .map(|(name, _)| name)
+    .collect();
+  report.missing.sort();
+  report.modified.sort_by(|a, b| a.filename.cmp(&b.filename));
+
+  Ok(report)
+}
+
+pub fn print_report(report: &VerifyReport, dir: &Path) {
+  for name in &report.missing {
+    println!("{:<10} {}", "MISSING", name);
+  }
+  for m in &report.modified {
+    println!("{:<10} {}", "MODIFIED", m.filename);
+  }
+  for name in &report.extra {
+    println!("{:<10} {}", "EXTRA", name);
+  }
+
+  println!(
+    "\n==> {} {} in {}: {} files checked, {} missing, {} modified, {} extra",
+    report.mid,
+    report.version,
+    dir.display(),
+    report.checked,
+    report.missing.len(),
+    report.modified.len(),
+    report.extra.len()
+  );
+}
+
+/// Collects the files below `dir` as `/`-separated relative paths, each
+/// marked as not yet matched by a filelist entry.
+fn list_files(dir: &Path, prefix: &str, files: &mut BTreeMap<String, bool>) -> Result<()> {
+  for entry in fs::read_dir(dir).map_err(Error::io(dir))? {
+    let entry = entry.map_err(Error::io(dir))?;
+    let path = entry.path();
+    let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+
+    if path.is_dir() {
+      list_files(&path, &format!("{}/", name), files)?;
+    } else {
+      files.insert(name, false);
+    }
+  }
+
+  Ok(())
+}
+
+fn is_bookkeeping_file(name: &str) -> bool {
+  name == "mod.json" || name == installer::RECORD_FILE
+}
+
+/// Hashes the files on all available cores and pairs each digest with its
+/// filelist entry.
+fn hash_all<'a>(
+  dir: &Path,
+  files: &[(&'a FileListEntry, String)],
+) -> Result<Vec<(&'a FileListEntry, String)>> {
+  let workers = thread::available_parallelism().map_or(1, |n| n.get());
+  let next = AtomicUsize::new(0);
+  let results = Mutex::new(Vec::with_capacity(files.len()));
+
+  let progress_bar = ProgressBar::with_draw_target(files.len() as u64, output::progress_target());
+  progress_bar.set_style(
+    ProgressStyle::default_bar()
+      .template("[{elapsed_precise}] [{wide_bar}] {pos}/{len} files ({eta})")
+      .progress_chars("=> "),
+  );
+
+  thread::scope(|scope| {
+    for _ in 0..workers.min(files.len()) {
+      scope.spawn(|| loop {
+        let i = next.fetch_add(1, Ordering::Relaxed);
+        let Some((entry, name)) = files.get(i) else {
+          break;
+        };
+
+        let digest = checksum::sha256_file(&dir.join(name));
+        progress_bar.inc(1);
+        results
+          .lock()
+          .unwrap()
+          .push(digest.map(|digest| (*entry, digest)));
+      });
+    }
+  });
+  progress_bar.finish_and_clear();
+
+  results.into_inner().unwrap().into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+  use std::path::PathBuf;
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("neb-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  /// Writes `contents` to `dir/name` and returns its filelist entry.
+  fn write(dir: &Path, name: &str, contents: &str) -> Value {
+    let path = dir.join(name);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, contents).unwrap();
+    let digest = checksum::sha256_file(&path).unwrap();
+    json!({"filename": name, "archive": "core.7z", "checksum": ["sha256", digest]})
+  }
+
+  #[test]
+  fn classifies_missing_modified_and_extra_files() {
+    let dir = temp_dir("verify");
+    let mut filelist: Vec<Value> = (0..20)
+      .map(|i| write(&dir, &format!("data/tables/t{:02}.tbm", i), &i.to_string()))
+      .collect();
+    filelist.push(write(&dir, "data/missing.vp", "gone"));
+    filelist.push(write(&dir, "data/modified.vp", "before"));
+    let mut upper = write(&dir, "Data/Upper.VP", "case");
+    upper["filename"] = json!("data/upper.vp");
+    filelist.push(upper);
+
+    fs::remove_file(dir.join("data/missing.vp")).unwrap();
+    fs::write(dir.join("data/modified.vp"), "after").unwrap();
+    fs::write(dir.join("data/extra.cfg"), "").unwrap();
+    fs::write(dir.join("mod.json"), "{}").unwrap();
+
+    let km = KnossosMod::from_value(&json!({
+      "id": "MVPS", "title": "MediaVPs", "version": "4.6.0",
+      "packages": [{"name": "Core", "status": "required", "files": [], "filelist": filelist}]
+    }))
+    .unwrap();
+    let packages: Vec<&Package> = km.packages.iter().collect();
+    let report = verify(&km, &packages, &dir).unwrap();
+
+    assert_eq!(report.checked, 23);
+    assert_eq!(report.missing, ["data/missing.vp"]);
+    let modified: Vec<_> = report.modified.iter().map(|m| &m.filename).collect();
+    assert_eq!(modified, ["data/modified.vp"]);
+    assert_eq!(report.extra, ["data/extra.cfg"]);
+    assert!(!report.is_ok());
+
+    // Extra files alone do not fail the check.
+    fs::write(dir.join("data/missing.vp"), "gone").unwrap();
+    fs::write(dir.join("data/modified.vp"), "before").unwrap();
+    let report = verify(&km, &packages, &dir).unwrap();
+    assert!(report.missing.is_empty() && report.modified.is_empty());
+    assert_eq!(report.extra, ["data/extra.cfg"]);
+    assert!(report.is_ok());
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,584 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::error::{Error, Result};
+
+/// Orders versions the way Knossos does, which is semver with some
+/// leniency for what mod authors actually publish:
+///
+/// - missing release parts count as zero, so `1.0` equals `1.0.0`,
+/// - a prerelease sorts before its release (`1.0.0-RC1 < 1.0.0`),
+/// - `+build` metadata is ignored,
+/// - digits inside identifiers compare as numbers (`RC2 < RC10`), which
+///   also orders nightly builds such as `23.1.0-20230101_abcdef` by date,
+/// - a leading `v` is ignored.
+pub fn compare(a: &str, b: &str) -> Ordering {
+  let a = Parsed::new(a);
+  let b = Parsed::new(b);
+
+  compare_release(&a.release, &b.release).then_with(|| match (&a.prerelease, &b.prerelease) {
+    (None, None) => Ordering::Equal,
+    (None, Some(_)) => Ordering::Greater,
+    (Some(_), None) => Ordering::Less,
+    (Some(a), Some(b)) => compare_prerelease(a, b),
+  })
+}
+
+/// Sorts newest first. Versions that compare equal, like `1.0` and
+/// `1.0.0`, are kept in a fixed order.
+pub fn sort_descending(versions: &mut [String]) {
+  versions.sort_by(|a, b| compare(b, a).then_with(|| b.cmp(a)));
+}
+
+/// Whether `version` is a release rather than an RC, beta or nightly
+/// build.
+pub fn is_stable(version: &str) -> bool {
+  Parsed::new(version).prerelease.is_none()
+}
+
+struct Parsed<'a> {
+  release: Vec<&'a str>,
+  prerelease: Option<Vec<&'a str>>,
+}
+
+impl<'a> Parsed<'a> {
+  fn new(version: &'a str) -> Parsed<'a> {
+    let version = version.trim();
+    let version = version
+      .strip_prefix(['v', 'V'])
+      .filter(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+      .unwrap_or(version);
+    let version = version.split('+').next().unwrap_or("");
+
+    let (release, prerelease) = match version.split_once('-') {
+      Some((release, prerelease)) => (release, Some(prerelease.split('.').collect())),
+      None => (version, None),
+    };
+
+    Parsed {
+      release: release.split('.').collect(),
+      prerelease,
+    }
+  }
+}
+
+fn compare_release(a: &[&str], b: &[&str]) -> Ordering {
+  (0..a.len().max(b.len()))
+    .map(|i| compare_identifier(release_part(a, i), release_part(b, i)))
+    .find(|&ordering| ordering != Ordering::Equal)
+    .unwrap_or(Ordering::Equal)
+}
+
+fn release_part<'a>(parts: &[&'a str], i: usize) -> &'a str {
+  match parts.get(i) {
+    Some(part) if !part.is_empty() => part,
+    _ => "0",
+  }
+}
+
+/// Compares identifier by identifier; when one list is a prefix of the
+/// other, the shorter one sorts first.
+fn compare_prerelease(a: &[&str], b: &[&str]) -> Ordering {
+  a.iter()
+    .zip(b)
+    .map(|(a, b)| compare_identifier(a, b))
+    .find(|&ordering| ordering != Ordering::Equal)
+    .unwrap_or_else(|| a.len().cmp(&b.len()))
+}
+
+/// Compares runs of digits numerically and other runs ignoring ASCII
+/// case. A number sorts before text, as in semver.
+fn compare_identifier(a: &str, b: &str) -> Ordering {
+  let mut a_runs = runs(a);
+  let mut b_runs = runs(b);
+
+  loop {
+    let ordering = match (a_runs.next(), b_runs.next()) {
+      (None, None) => return Ordering::Equal,
+      (None, Some(_)) => return Ordering::Less,
+      (Some(_), None) => return Ordering::Greater,
+      (Some(a), Some(b)) => match (is_number(a), is_number(b)) {
+        (true, true) => compare_number(a, b),
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        (false, false) => a
+          .bytes()
+          .map(|c| c.to_ascii_lowercase())
+          .cmp(b.bytes().map(|c| c.to_ascii_lowercase())),
+      },
+    };
+
+    if ordering != Ordering::Equal {
+      return ordering;
+    }
+  }
+}
+
+/// Splits `RC10_abc` into `RC`, `10` and `_abc`.
+fn runs(identifier: &str) -> impl Iterator<Item = &str> {
+  let mut rest = identifier;
+  std::iter::from_fn(move || {
+    let first = rest.chars().next()?;
+    let end = rest
+      .find(|c: char| c.is_ascii_digit() != first.is_ascii_digit())
+      .unwrap_or(rest.len());
+    let (run, tail) = rest.split_at(end);
+    rest = tail;
+    Some(run)
+  })
+}
+
+fn is_number(run: &str) -> bool {
+  run.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Compares digit strings of any length without parsing them, so that
+/// long nightly dates or hashes cannot overflow.
+fn compare_number(a: &str, b: &str) -> Ordering {
+  let a = a.trim_start_matches('0');
+  let b = b.trim_start_matches('0');
+  a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+/// A Knossos dependency version specifier such as `>=1.2`, `~1.0`,
+/// `^2.1.0`, `1.0` (any 1.0.x release, prereleases excluded) or a
+/// comma-separated combination of them, e.g. `>=4.5, <5`. Only
+/// [`VersionSpec::exact`] pins a single release.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionSpec {
+  text: String,
+  items: Vec<SpecItem>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct SpecItem {
+  op: Op,
+  version: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  /// Knossos `==`, where `1.0` stands for any `1.0.x` release.
+  Eq,
+  Ne,
+  /// The same version by `compare`, e.g. `1.0` and `1.0.0` but not `1.0.5`.
+  Exact,
+}
+
+impl VersionSpec {
+  pub fn parse(text: &str) -> Result<VersionSpec> {
+    let mut items = Vec::new();
+
+    for part in text.split(',').map(str::trim) {
+      if part.is_empty() || part == "*" {
+        continue;
+      }
+
+      let invalid = || Error::InvalidVersionSpec(text.to_string());
+      let (prefix, version) = split_op(part);
+      if version.is_empty() || !version.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err(invalid());
+      }
+      let parts = release_parts(version).ok_or_else(invalid)?;
+
+      let version = version.to_string();
+      match prefix {
+        "<" => items.push(SpecItem {
+          op: Op::Lt,
+          version,
+        }),
+        "<=" => items.push(SpecItem {
+          op: Op::Le,
+          version,
+        }),
+        ">" => items.push(SpecItem {
+          op: Op::Gt,
+          version,
+        }),
+        ">=" => items.push(SpecItem {
+          op: Op::Ge,
+          version,
+        }),
+        "" | "=" | "==" => items.push(SpecItem {
+          op: Op::Eq,
+          version,
+        }),
+        "!=" => items.push(SpecItem {
+          op: Op::Ne,
+          version,
+        }),
+        "~" => {
+          let upper = bump(&parts, tilde_position(&parts)).ok_or_else(invalid)?;
+          items.push(SpecItem {
+            op: Op::Ge,
+            version,
+          });
+          items.push(SpecItem {
+            op: Op::Lt,
+            version: upper,
+          });
+        }
+        "~=" => {
+          let upper = bump(&parts, parts.len().saturating_sub(2)).ok_or_else(invalid)?;
+          items.push(SpecItem {
+            op: Op::Ge,
+            version,
+          });
+          items.push(SpecItem {
+            op: Op::Lt,
+            version: upper,
+          });
+        }
+        "^" => {
+          let upper = bump(&parts, caret_position(&parts)).ok_or_else(invalid)?;
+          items.push(SpecItem {
+            op: Op::Ge,
+            version,
+          });
+          items.push(SpecItem {
+            op: Op::Lt,
+            version: upper,
+          });
+        }
+        _ => return Err(invalid()),
+      }
+    }
+
+    Ok(VersionSpec {
+      text: text.trim().to_string(),
+      items,
+    })
+  }
+
+  /// A spec that every version satisfies.
+  pub fn any() -> VersionSpec {
+    VersionSpec {
+      text: "*".to_string(),
+      items: Vec::new(),
+    }
+  }
+
+  /// A spec that only `version` satisfies, or versions that `compare`
+  /// as equal to it such as `1.0.0` for `1.0`. Unlike Knossos `==1.0`,
+  /// it does not match `1.0.5`.
+  pub fn exact(version: &str) -> VersionSpec {
+    VersionSpec {
+      text: version.to_string(),
+      items: vec![SpecItem {
+        op: Op::Exact,
+        version: version.to_string(),
+      }],
+    }
+  }
+
+  pub fn is_any(&self) -> bool {
+    self.items.is_empty()
+  }
+
+  pub fn matches(&self, version: &str) -> bool {
+    self.items.iter().all(|item| item.matches(version))
+  }
+}
+
+impl fmt::Display for VersionSpec {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.text)
+  }
+}
+
+impl SpecItem {
+  fn matches(&self, version: &str) -> bool {
+    match self.op {
+      Op::Lt => compare(version, &self.version) == Ordering::Less,
+      Op::Le => compare(version, &self.version) != Ordering::Greater,
+      Op::Gt => compare(version, &self.version) == Ordering::Greater,
+      Op::Ge => compare(version, &self.version) != Ordering::Less,
+      Op::Eq => is_same_version(version, &self.version),
+      Op::Ne => !is_same_version(version, &self.version),
+      Op::Exact => version == self.version || compare(version, &self.version) == Ordering::Equal,
+    }
+  }
+}
+
+fn split_op(part: &str) -> (&str, &str) {
+  let end = part
+    .find(|c: char| !matches!(c, '<' | '>' | '=' | '!' | '~' | '^'))
+    .unwrap_or(part.len());
+  (&part[..end], part[end..].trim())
+}
+
+/// `1.0` in a spec stands for any `1.0.x` release, but not an RC or
+/// nightly build of one, so that a dependency on a release never resolves
+/// to a prerelease. A spec with a prerelease or build suffix has to match
+/// exactly.
+fn is_same_version(version: &str, spec_version: &str) -> bool {
+  if version == spec_version {
+    return true;
+  }
+
+  if spec_version.contains(['-', '+']) || !is_stable(version) {
+    return false;
+  }
+
+  // Spec versions were checked by `parse`; a version with parts that
+  // are not numbers is only the same when equal as text.
+  match (release_parts(spec_version), release_parts(version)) {
+    (Some(spec_parts), Some(parts)) => {
+      parts.len() >= spec_parts.len() && parts[..spec_parts.len()] == spec_parts[..]
+    }
+    _ => false,
+  }
+}
+
+/// The numeric release parts of `version`, or `None` if a part is not a
+/// number or too large.
+fn release_parts(version: &str) -> Option<Vec<u32>> {
+  Parsed::new(version)
+    .release
+    .iter()
+    .map(|part| part.parse::<u32>().ok())
+    .collect()
+}
+
+/// `~1.2.3` and `~1.2` allow patch updates, `~1` allows minor updates.
+fn tilde_position(parts: &[u32]) -> usize {
+  if parts.len() >= 2 {
+    1
+  } else {
+    0
+  }
+}
+
+/// `^` allows updates that do not change the left-most non-zero part.
+fn caret_position(parts: &[u32]) -> usize {
+  parts
+    .iter()
+    .position(|&x| x != 0)
+    .unwrap_or(parts.len().saturating_sub(1))
+}
+
+/// Returns the smallest release above every version sharing the first
+/// `position` of the release `parts`, e.g. `1.3.0` for `1.2.3` and 1, or
+/// `None` if that part is already the largest number.
+fn bump(parts: &[u32], position: usize) -> Option<String> {
+  let mut parts = parts.to_vec();
+  while parts.len() <= position {
+    parts.push(0);
+  }
+
+  parts[position] = parts[position].checked_add(1)?;
+  for part in parts.iter_mut().skip(position + 1) {
+    *part = 0;
+  }
+
+  Some(
+    parts
+      .iter()
+      .map(|x| x.to_string())
+      .collect::<Vec<String>>()
+      .join("."),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::cmp::Ordering::{Equal, Greater, Less};
+
+  #[test]
+  fn compare_table() {
+    let table = [
+      // Release parts
+      ("1.0.0", "1.0.0", Equal),
+      ("1.0.0", "1.0.1", Less),
+      ("1.0.1", "1.1.0", Less),
+      ("1.1.0", "2.0.0", Less),
+      ("1.9.0", "1.10.0", Less),
+      ("1.0.10", "1.0.9", Greater),
+      ("10.0.0", "9.99.99", Greater),
+      ("1.0", "1.0.0", Equal),
+      ("1", "1.0.0", Equal),
+      ("1.0", "1.0.1", Less),
+      ("1.0.0.1", "1.0.0", Greater),
+      ("1.01", "1.1", Equal),
+      ("v1.2.0", "1.2.0", Equal),
+      ("V2", "1.9", Greater),
+      ("1.0a", "1.0", Greater),
+      ("1.0a", "1.0b", Less),
+      ("4294967296.0", "4294967295.0", Greater),
+      // Prereleases
+      ("1.0.0-RC1", "1.0.0", Less),
+      ("1.0.0-RC1", "1.0.0-RC2", Less),
+      ("1.0.0-RC2", "1.0.0-RC10", Less),
+      ("1.0.0-rc1", "1.0.0-RC1", Equal),
+      ("1.0.0-rc.1", "1.0.0-rc.2", Less),
+      ("1.0.0-alpha", "1.0.0-beta", Less),
+      ("1.0.0-beta", "1.0.0-RC1", Less),
+      ("1.0.0-alpha", "1.0.0-alpha.1", Less),
+      ("1.0.0-alpha.1", "1.0.0-alpha.beta", Less),
+      ("1.0.0-1", "1.0.0-alpha", Less),
+      ("1.0.0-2", "1.0.0-11", Less),
+      ("1.0.0-RC1", "0.9.9", Greater),
+      ("1.0.0-RC1", "1.0", Less),
+      ("1.0-RC1", "1.0.0-RC1", Equal),
+      // Build metadata
+      ("1.0.0+build.1", "1.0.0", Equal),
+      ("1.0.0+build.1", "1.0.0+build.2", Equal),
+      ("1.0.0-RC1+abc", "1.0.0-RC1", Equal),
+      ("1.0.0+abc", "1.0.1", Less),
+      // Nightly engine builds
+      ("23.1.0-20230101_abcdef", "23.1.0-20230102_012345", Less),
+      ("23.1.0-20230131_ffffff", "23.1.0-20230201_000000", Less),
+      ("23.1.0-20230101_abcdef", "23.1.0", Less),
+      ("23.1.0-20230101_abcdef", "23.0.0", Greater),
+      ("23.1.0-20230101_abcdef", "23.1.0-RC1", Less),
+      ("22.3.0-20230101_abcdef", "23.1.0-20221231_abcdef", Less),
+      ("23.1.0-20230101-abcdef", "23.1.0-20230102-012345", Less),
+      // Sloppy input
+      ("", "0", Equal),
+      ("", "0.0.1", Less),
+      ("1..2", "1.0.2", Equal),
+      (" 1.0.0 ", "1.0.0", Equal),
+    ];
+
+    for (a, b, expected) in table {
+      assert_eq!(compare(a, b), expected, "compare({:?}, {:?})", a, b);
+      assert_eq!(
+        compare(b, a),
+        expected.reverse(),
+        "compare({:?}, {:?})",
+        b,
+        a
+      );
+    }
+  }
+
+  #[test]
+  fn sort_descending_orders_newest_first() {
+    let mut versions: Vec<String> = [
+      "1.0.0-RC1",
+      "0.9",
+      "1.0.0",
+      "1.10.0",
+      "1.0.0-RC10",
+      "1.2.0+build",
+      "1.0.0-RC2",
+      "1.2.0",
+      "1.9.0",
+    ]
+    .iter()
+    .map(|v| v.to_string())
+    .collect();
+
+    sort_descending(&mut versions);
+    assert_eq!(
+      versions,
+      [
+        "1.10.0",
+        "1.9.0",
+        "1.2.0+build",
+        "1.2.0",
+        "1.0.0",
+        "1.0.0-RC10",
+        "1.0.0-RC2",
+        "1.0.0-RC1",
+        "0.9",
+      ]
+    );
+  }
+
+  #[test]
+  fn spec_match_table() {
+    let table = [
+      // A plain version stands for every release it is a prefix of.
+      ("4.5", "4.5", true),
+      ("4.5", "4.5.0", true),
+      ("4.5", "4.5.2", true),
+      ("4.5", "4.5.2+build", true),
+      ("4.5", "4.6.0", false),
+      ("4.5", "4.50", false),
+      ("==4.5", "4.5.2", true),
+      ("=4.5.0", "4.5.0", true),
+      // but not for its prereleases, unless it names one.
+      ("4.5", "4.5.0-RC1", false),
+      ("==4.5", "4.5.2-20230101_abcdef", false),
+      ("4.5.0-RC1", "4.5.0-RC1", true),
+      ("4.5.0-RC1", "4.5.0-RC2", false),
+      ("4.5.0-RC1", "4.5.0", false),
+      ("!=4.5", "4.5.1", false),
+      ("!=4.5", "4.6.0", true),
+      // Comparisons and ranges
+      (">=4.5", "4.5.0", true),
+      (">=4.5", "4.5.0-RC1", false),
+      (">4.5", "4.5.0", false),
+      ("<5", "5.0.0-RC1", true),
+      ("<=4.5", "4.5.0", true),
+      ("~4.5", "4.5.9", true),
+      ("~4.5", "4.6.0", false),
+      ("~4", "4.9.0", true),
+      ("~=4.5.1", "4.5.9", true),
+      ("~=4.5.1", "4.6.0", false),
+      ("^4.5", "4.9.0", true),
+      ("^4.5", "5.0.0", false),
+      ("^0.4", "0.5.0", false),
+      (">=4.5, <5", "4.9.9", true),
+      (">=4.5, <5", "5.0.0", false),
+      ("*", "4.5.0-RC1", true),
+      ("", "1.0", true),
+    ];
+
+    for (spec, version, expected) in table {
+      let parsed = VersionSpec::parse(spec).unwrap();
+      assert_eq!(
+        parsed.matches(version),
+        expected,
+        "{:?} matches {:?}",
+        spec,
+        version
+      );
+    }
+  }
+
+  #[test]
+  fn spec_matches_with_prereleases() {
+    let spec = VersionSpec::parse(">=1.0, <2").unwrap();
+    assert!(spec.matches("1.0.0"));
+    assert!(spec.matches("1.9.9"));
+    assert!(!spec.matches("1.0.0-RC1"));
+    assert!(!spec.matches("2.0.0"));
+
+    let spec = VersionSpec::parse("~1.2").unwrap();
+    assert!(spec.matches("1.2.5"));
+    assert!(!spec.matches("1.3.0"));
+  }
+
+  #[test]
+  fn exact_spec_does_not_match_prefix() {
+    let spec = VersionSpec::exact("1.0");
+    assert!(spec.matches("1.0"));
+    assert!(spec.matches("1.0.0"));
+    assert!(!spec.matches("1.0.5"));
+    assert!(!spec.matches("1.0.0-RC1"));
+
+    // Knossos == keeps matching every 1.0.x release.
+    let spec = VersionSpec::parse("==1.0").unwrap();
+    assert!(spec.matches("1.0.5"));
+    assert!(!spec.matches("1.1.0"));
+  }
+
+  #[test]
+  fn spec_rejects_bad_release_parts() {
+    for text in ["1.x", "1..2", ">=1.0a", "^4294967295", "~1.4294967295.0"] {
+      assert!(
+        matches!(VersionSpec::parse(text), Err(Error::InvalidVersionSpec(_))),
+        "{}",
+        text
+      );
+    }
+    assert!(VersionSpec::parse("~4294967295.1").is_ok());
+  }
+}
@@ -1,6 +1,8 @@
 #![deny(warnings)]
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::fmt::Write;
+use std::net::Ipv4Addr;
 use std::path::Path;
 use std::sync::Arc;
 
@@ -12,30 +14,48 @@ use tinytemplate::TinyTemplate;
 use tokio::sync::Mutex;
 use warp::{http::Response, Filter};
 
-use crate::repo::{self, Mod};
-
-static DEFAULT_BIND: [u8; 4] = [127, 0, 0, 1];
-static DEFAULT_PORT: u16 = 3200;
+use crate::error::{self, Error};
+use crate::knossos::{Dependency, KnossosMod};
+use crate::repo::{self, Mod, SearchFilter, SearchResult};
+use crate::status;
 
 type Db = Arc<Mutex<Connection>>;
 
 #[derive(Serialize)]
-struct ModListContext {
-  mods: Vec<Mod>,
+struct ModListContext<'a> {
+  mods: Vec<ModItem>,
+  query: &'a str,
+  filters: Vec<FilterParam<'a>>,
+}
+
+/// A search filter from the query string, kept in the search form.
+#[derive(Serialize)]
+struct FilterParam<'a> {
+  name: &'a str,
+  value: &'a str,
+}
+
+#[derive(Serialize)]
+struct ModItem {
+  mid: String,
+  title: String,
+  tile: String,
+  /// HTML with the search matches in `<mark>`.
+  snippet: String,
 }
 
 #[derive(Serialize)]
 struct ModInfoContext<'a> {
-  m: Value,
+  m: &'a KnossosMod,
   mid: String,
   versions: &'a Vec<Version>,
   url_path: String,
   is_total_conversion: bool,
   is_engine: bool,
-  packages: &'a Vec<Package>,
+  packages: &'a Vec<Package<'a>>,
   total_size: u64,
   sha256sum: String,
-  dependencies: Vec<(&'a String, &'a Vec<Value>)>,
+  dependencies: Vec<(&'a str, &'a [Dependency])>,
   modline: String,
 }
 
@@ -46,29 +66,27 @@ struct Version {
 }
 
 #[derive(Serialize)]
-struct Package {
-  name: String,
-  status: String,
-  notes: String,
-  filename: String,
+struct Package<'a> {
+  name: &'a str,
+  status: &'a str,
+  notes: &'a str,
+  filename: &'a str,
   filesize: u64,
-  checksum: String,
-  urls: Vec<Value>,
-  dependencies: Vec<Value>,
+  checksum: &'a str,
+  urls: &'a [String],
+  dependencies: &'a [Dependency],
 }
 
 #[tokio::main]
-pub async fn start(db_path: &Path) {
+pub async fn start(db_path: &Path, bind: Ipv4Addr, port: u16) -> error::Result<()> {
   if std::env::var_os("RUST_LOG").is_none() {
     std::env::set_var("RUST_LOG", "mods=info");
   }
 
   env_logger::init();
 
-  let conn = repo::open_read_only(db_path);
+  let conn = repo::open_read_only(db_path)?;
   let db = Arc::new(Mutex::new(conn));
-  let bind = get_bind_env_var();
-  let port = get_port_env_var();
 
   let favicon = warp::path!("favicon.ico").map(|| {
     Response::builder()
@@ -81,6 +99,7 @@ pub async fn start(db_path: &Path) {
   let about_page = warp::path!("about").map(|| warp::reply::html(ABOUT_PAGE));
 
   let mod_list_page = warp::path!("mods")
+    .and(warp::query::<HashMap<String, String>>())
     .and(with_db(db.clone()))
     .and_then(list_page);
 
@@ -138,20 +157,84 @@ pub async fn start(db_path: &Path) {
     )
     .with(warp::log("mods"));
 
-  println!("Running server at http://localhost:{}", port);
+  status!("Running server at http://localhost:{}", port);
   warp::serve(routes).run((bind, port)).await;
+
+  Ok(())
 }
 
-async fn list_page(db: Db) -> Result<impl warp::Reply, Infallible> {
+async fn list_page(
+  params: HashMap<String, String>,
+  db: Db,
+) -> Result<impl warp::Reply, Infallible> {
+  let query = params.get("q").map(|q| q.trim()).unwrap_or_default();
+  let mut filter = SearchFilter::default();
+  let mut filters = Vec::new();
+  for name in SearchFilter::NAMES {
+    if let Some(value) = params.get(name).filter(|v| !v.trim().is_empty()) {
+      if let Err(e) = filter.set(name, value) {
+        return Ok(bad_request(e));
+      }
+      filters.push(FilterParam { name, value });
+    }
+  }
+
   let conn = db.lock().await;
-  let mods = repo::list_mods(&conn);
+  let mods = if query.is_empty() && filter.is_empty() {
+    repo::list_mods(&conn).map(|mods| mods.into_iter().map(mod_item).collect())
+  } else {
+    repo::search_mods(&conn, query, &filter)
+      .map(|results| results.into_iter().map(search_result_item).collect())
+  };
+  let mods = match mods {
+    Ok(mods) => mods,
+    Err(e) => return Ok(internal_error(e)),
+  };
 
-  let ctx = ModListContext { mods };
+  let ctx = ModListContext {
+    mods,
+    query,
+    filters,
+  };
 
   let mut tt = TinyTemplate::new();
   tt.add_template("mod_list", MOD_LIST_PAGE).unwrap();
   let html = tt.render("mod_list", &ctx).unwrap();
-  Ok(warp::reply::html(html))
+  Ok(warp::reply::with_status(
+    warp::reply::html(html),
+    http::StatusCode::OK,
+  ))
+}
+
+fn mod_item(m: Mod) -> ModItem {
+  ModItem {
+    mid: m.mid,
+    title: m.title,
+    tile: m.tile,
+    snippet: String::new(),
+  }
+}
+
+fn search_result_item(r: SearchResult) -> ModItem {
+  let plain = r
+    .snippet
+    .replace([repo::SNIPPET_START, repo::SNIPPET_END], "");
+  let snippet = if plain == r.title || plain == r.mid {
+    String::new()
+  } else {
+    let mut html = String::new();
+    tinytemplate::escape(&r.snippet, &mut html);
+    html
+      .replace(repo::SNIPPET_START, "<mark>")
+      .replace(repo::SNIPPET_END, "</mark>")
+  };
+
+  ModItem {
+    mid: r.mid,
+    title: r.title,
+    tile: r.tile,
+    snippet,
+  }
 }
 
 async fn info_page_without_version(mid: String, db: Db) -> Result<impl warp::Reply, Infallible> {
@@ -188,18 +271,29 @@ async fn info_page_as_json(
 ) -> Result<impl warp::Reply, Infallible> {
   let conn = db.lock().await;
 
-  if let Some(m) = repo::get_mod(&conn, &mid, &version) {
-    let mod_json: Value = serde_json::from_str(m.mod_json.as_str()).unwrap();
+  let mod_json = repo::get_mod(&conn, &mid, &version).and_then(|maybe_mod| {
+    maybe_mod
+      .map(|m| serde_json::from_str::<Value>(m.mod_json.as_str()))
+      .transpose()
+      .map_err(Error::from)
+  });
 
-    Ok(warp::reply::with_status(
+  match mod_json {
+    Ok(Some(mod_json)) => Ok(warp::reply::with_status(
       warp::reply::json(&mod_json),
       http::StatusCode::OK,
-    ))
-  } else {
-    Ok(warp::reply::with_status(
+    )),
+    Ok(None) => Ok(warp::reply::with_status(
       warp::reply::json(&serde_json::json!({})),
       http::StatusCode::NOT_FOUND,
-    ))
+    )),
+    Err(e) => {
+      log::error!("{}", e);
+      Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": e.to_string() })),
+        http::StatusCode::INTERNAL_SERVER_ERROR,
+      ))
+    }
   }
 }
 
@@ -209,13 +303,15 @@ async fn info_page(
   db: Db,
 ) -> Result<impl warp::Reply, Infallible> {
   let conn = db.lock().await;
-  let m = if let Some(md) = repo::get_mod(&conn, &mid, &version) {
-    md
-  } else {
-    return Ok(warp::reply::with_status(
-      warp::reply::html(NOT_FOUND_PAGE.to_string()),
-      http::StatusCode::NOT_FOUND,
-    ));
+  let m = match repo::get_mod(&conn, &mid, &version) {
+    Ok(Some(md)) => md,
+    Ok(None) => {
+      return Ok(warp::reply::with_status(
+        warp::reply::html(NOT_FOUND_PAGE.to_string()),
+        http::StatusCode::NOT_FOUND,
+      ))
+    }
+    Err(e) => return Ok(internal_error(e)),
   };
 
   let url_path = if let Some(v) = version {
@@ -224,25 +320,28 @@ async fn info_page(
     format!("/mods/{}", mid)
   };
 
-  let mod_json: Value = serde_json::from_str(m.mod_json.as_str()).unwrap();
+  let km = match m.knossos_mod() {
+    Ok(km) => km,
+    Err(e) => return Ok(internal_error(e)),
+  };
 
-  let is_total_conversion = mod_json["type"] == "tc";
-  let is_engine = mod_json["type"] == "engine";
+  let is_total_conversion = km.is_total_conversion();
+  let is_engine = km.is_engine();
 
   let mut packages: Vec<Package> = Vec::new();
 
-  for p in mod_json["packages"].as_array().unwrap() {
-    let file = &p["files"][0];
+  for p in &km.packages {
+    let file = p.files.first();
 
     packages.push(Package {
-      name: p["name"].as_str().unwrap().to_string(),
-      status: p["status"].as_str().unwrap().to_string(),
-      notes: p["notes"].as_str().unwrap().to_string(),
-      filename: file["filename"].as_str().unwrap().to_string(),
-      filesize: file["filesize"].as_u64().unwrap(),
-      checksum: file["checksum"][1].as_str().unwrap().to_string(),
-      urls: file["urls"].as_array().unwrap().to_vec(),
-      dependencies: p["dependencies"].as_array().unwrap().to_vec(),
+      name: &p.name,
+      status: p.status.as_deref().unwrap_or(""),
+      notes: p.notes.as_deref().unwrap_or(""),
+      filename: file.map(|f| f.filename.as_str()).unwrap_or(""),
+      filesize: file.map(|f| f.filesize).unwrap_or(0),
+      checksum: file.map(|f| f.checksum.digest()).unwrap_or(""),
+      urls: file.map(|f| f.urls.as_slice()).unwrap_or(&[]),
+      dependencies: &p.dependencies,
     });
   }
 
@@ -253,27 +352,18 @@ async fn info_page(
     .collect::<Vec<String>>()
     .join("\n");
 
-  let dependencies: Vec<(&String, &Vec<Value>)> = packages
+  let dependencies: Vec<(&str, &[Dependency])> = packages
     .iter()
     .filter_map(|p| {
       if p.dependencies.is_empty() {
         None
       } else {
-        Some((&p.name, &p.dependencies))
+        Some((p.name, p.dependencies))
       }
     })
     .collect();
 
-  let modline = format!(
-    "-mod {}",
-    mod_json["mod_flag"]
-      .as_array()
-      .unwrap()
-      .iter()
-      .map(|x| x.as_str().unwrap().to_string())
-      .collect::<Vec<String>>()
-      .join(",")
-  );
+  let modline = format!("-mod {}", km.mod_flag.join(","));
 
   let versions = m
     .versions
@@ -285,7 +375,7 @@ async fn info_page(
     .collect();
 
   let ctx = ModInfoContext {
-    m: mod_json,
+    m: &km,
     mid,
     versions: &versions,
     url_path,
@@ -309,6 +399,24 @@ async fn info_page(
   ))
 }
 
+fn internal_error(e: Error) -> warp::reply::WithStatus<warp::reply::Html<String>> {
+  log::error!("{}", e);
+  warp::reply::with_status(
+    warp::reply::html(format!("<h1>Internal Server Error</h1><p>{}</p>", e)),
+    http::StatusCode::INTERNAL_SERVER_ERROR,
+  )
+}
+
+fn bad_request(e: Error) -> warp::reply::WithStatus<warp::reply::Html<String>> {
+  // The message quotes the request, so it must not be taken as markup.
+  let mut message = String::new();
+  tinytemplate::escape(&e.to_string(), &mut message);
+  warp::reply::with_status(
+    warp::reply::html(format!("<h1>Bad Request</h1><p>{}</p>", message)),
+    http::StatusCode::BAD_REQUEST,
+  )
+}
+
 fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = std::convert::Infallible> + Clone {
   warp::any().map(move || db.clone())
 }
@@ -325,40 +433,6 @@ fn reply_js(js: &str) -> Result<Response<&str>, http::Error> {
     .body(js)
 }
 
-fn get_bind_env_var() -> [u8; 4] {
-  if let Ok(val) = std::env::var("BIND") {
-    let list: Vec<u8> = val
-      .split('.')
-      .map(|x| {
-        x.parse().unwrap_or_else(|_| {
-          println!("Invalid BIND env variable.");
-          std::process::exit(1)
-        })
-      })
-      .collect();
-
-    if list.len() != 4 {
-      println!("Invalid BIND env variable.");
-      std::process::exit(1)
-    }
-
-    [list[0], list[1], list[2], list[3]]
-  } else {
-    DEFAULT_BIND
-  }
-}
-
-fn get_port_env_var() -> u16 {
-  if let Ok(val) = std::env::var("PORT") {
-    val.parse::<u16>().unwrap_or_else(|_| {
-      println!("Invalid PORT env variable.");
-      std::process::exit(1)
-    })
-  } else {
-    DEFAULT_PORT
-  }
-}
-
 fn format_bytes(value: &Value, output: &mut String) -> tinytemplate::error::Result<()> {
   let kb = 1024;
   let mb = kb * 1024;
@@ -1,31 +1,52 @@
-let $mods = document.querySelectorAll(".mod-item")
+let $searchForm = document.getElementById("search-form")
 let $searchBox = document.getElementById("search-box")
 
 let searchInputTimeout
 let searchInterval = 150
-$searchBox.addEventListener("keydown", () => {
+let searchCount = 0
+$searchBox.addEventListener("input", () => {
   clearTimeout(searchInputTimeout)
   searchInputTimeout = setTimeout(search, searchInterval)
 })
 
+$searchForm.addEventListener("submit", (e) => {
+  e.preventDefault()
+  clearTimeout(searchInputTimeout)
+  search()
+})
+
 document.addEventListener("keyup", (e) => {
   if (e.code == "KeyS") {
     $searchBox.focus()
   }
 })
 
+// Fetches the results page for the current form values and swaps in its
+// mod list, so the search runs on the server's full-text index.
 function search() {
-  requestAnimationFrame(() => {
-    let query = $searchBox.value.toLowerCase()
+  let params = new URLSearchParams()
+  for (let [name, value] of new FormData($searchForm)) {
+    if (value.trim() != "") {
+      params.append(name, value.trim())
+    }
+  }
 
-    for (let $mod of $mods) {
-      let text = $mod.textContent.toLowerCase()
+  let query = params.toString()
+  let url = query ? "/mods?" + query : "/mods"
+  let count = ++searchCount
 
-      if (text.includes(query)) {
-        $mod.classList.remove("is-hidden")
-      } else {
-        $mod.classList.add("is-hidden")
+  fetch(url)
+    .then((response) => response.text())
+    .then((html) => {
+      // Ignores responses that arrive after a newer search started.
+      if (count != searchCount) {
+        return
       }
-    }
-  })
+
+      let page = new DOMParser().parseFromString(html, "text/html")
+      document
+        .getElementById("search-results")
+        .replaceWith(page.getElementById("search-results"))
+      history.replaceState(null, "", url)
+    })
 }